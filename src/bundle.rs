@@ -0,0 +1,741 @@
+use anyhow::Context;
+use futures::StreamExt;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::download_dist::sha256_digest;
+use crate::gc::now_seconds;
+use crate::http::http_date;
+use crate::link_mode::link_file;
+use crate::link_mode::link_file_reusing;
+use crate::mirrors::mirror_names;
+use crate::mirrors::synced_mirror_names;
+use crate::mirrors::MirrorCtx;
+use crate::paths::paths;
+use crate::release::verify_signature;
+use crate::replicate::blocking;
+use crate::replicate::copy_verified;
+use crate::replicate::list_files;
+use crate::snapshot::current_snapshot;
+use crate::snapshot::prune_snapshots;
+use crate::snapshot::snapshot_packages;
+use crate::snapshot::switch_current;
+use crate::snapshot::DEFAULT_KEEP;
+use crate::store::blob_path;
+use crate::store::is_sha256_name;
+use crate::store::list_blobs;
+use crate::store::lock_store;
+use crate::sync::lock_root;
+use crate::tar::extract;
+use crate::tar::TarWriter;
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+/// Version of the manifest format, bumped on incompatible changes.
+const FORMAT: &str = "1";
+
+const MANIFEST: &str = "manifest";
+const SIGNATURE: &str = "manifest.asc";
+
+/// Name of the tree in a root without a mirrors file.
+const ROOT_TREE: &str = ".";
+
+/// Blobs copied into the store at the same time.
+const NUM_THREADS: usize = 8;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ExportOptions {
+    /// Blob list printed by the manifest command on the target, whose blobs
+    /// are left out of the bundle
+    #[arg(long, value_name = "PATH")]
+    pub have: Option<PathBuf>,
+
+    /// Sign the bundle manifest with this gpg key
+    #[arg(long, value_name = "KEYID")]
+    pub sign_key: Option<String>,
+
+    /// Bundle directory to write, or a tar archive if the name ends in .tar
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ImportOptions {
+    /// Keyring holding the key the bundle manifest was signed with
+    #[arg(long, value_name = "PATH")]
+    pub keyring: Option<String>,
+
+    /// Import a bundle without checking its signature
+    #[arg(long, conflicts_with = "keyring")]
+    pub allow_unsigned: bool,
+
+    /// Number of generations to keep in each mirror, including the imported one
+    #[arg(long, default_value_t = DEFAULT_KEEP)]
+    pub keep: usize,
+
+    /// Bundle directory or tar archive written by export
+    pub bundle: PathBuf,
+}
+
+/// A file of a bundle, below the tree it belongs to.
+#[derive(Debug, Clone)]
+struct BundleFile {
+    sha256: String,
+    size: u64,
+    path: String,
+}
+
+/// The published generation of one mirror as carried by a bundle.
+#[derive(Debug, Clone, Default)]
+struct BundleTree {
+    name: String,
+    snapshot: String,
+    /// The dists files, all of them in the bundle.
+    files: Vec<BundleFile>,
+    /// Pool files as blob and path, the blobs in the bundle or on the target.
+    pool: Vec<(String, String)>,
+}
+
+/// What a bundle holds, the part that is signed.
+#[derive(Debug, Clone, Default)]
+struct Manifest {
+    date: String,
+    blobs: Vec<(String, u64)>,
+    trees: Vec<BundleTree>,
+}
+
+impl Manifest {
+    fn to_text(&self) -> String {
+        let mut ret = format!("Format: {}\nDate: {}\n", FORMAT, self.date);
+        for (sha256, size) in self.blobs.iter() {
+            ret.push_str(&format!("Blob: {} {}\n", sha256, size));
+        }
+        for tree in self.trees.iter() {
+            ret.push_str(&format!("Tree: {} {}\n", tree.name, tree.snapshot));
+            for x in tree.files.iter() {
+                ret.push_str(&format!("File: {} {} {}\n", x.sha256, x.size, x.path));
+            }
+            for (sha256, path) in tree.pool.iter() {
+                ret.push_str(&format!("Pool: {} {}\n", sha256, path));
+            }
+        }
+        ret
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Manifest> {
+        let mut ret = Manifest::default();
+        let mut format = None;
+        for line in text.lines().filter(|x| !x.is_empty()) {
+            let invalid = || anyhow::format_err!("invalid manifest line {:?}", line);
+            let (key, value) = line.split_once(": ").ok_or_else(invalid)?;
+            let fields: Vec<&str> = value.splitn(3, ' ').collect();
+            let sha256 = |x: &str| match is_sha256_name(x) {
+                true => Ok(x.to_string()),
+                false => Err(invalid()),
+            };
+            match (key, fields.as_slice()) {
+                ("Format", [x]) => format = Some(x.to_string()),
+                ("Date", _) => ret.date = value.to_string(),
+                ("Blob", [a, b]) => ret
+                    .blobs
+                    .push((sha256(a)?, b.parse().map_err(|_| invalid())?)),
+                ("Tree", [a, b]) => ret.trees.push(BundleTree {
+                    name: a.to_string(),
+                    snapshot: b.to_string(),
+                    ..BundleTree::default()
+                }),
+                ("File", [a, b, c]) => {
+                    ret.trees
+                        .last_mut()
+                        .ok_or_else(invalid)?
+                        .files
+                        .push(BundleFile {
+                            sha256: sha256(a)?,
+                            size: b.parse().map_err(|_| invalid())?,
+                            path: safe_path(c).ok_or_else(invalid)?,
+                        })
+                }
+                ("Pool", [a, b]) => ret
+                    .trees
+                    .last_mut()
+                    .ok_or_else(invalid)?
+                    .pool
+                    .push((sha256(a)?, safe_path(b).ok_or_else(invalid)?)),
+                _ => return Err(invalid()),
+            }
+        }
+        match format.as_deref() {
+            Some(FORMAT) => Ok(ret),
+            Some(o) => Err(anyhow::format_err!("unsupported bundle format {}", o)),
+            None => Err(anyhow::format_err!("the manifest names no bundle format")),
+        }
+    }
+}
+
+/// `path` if it stays below the directory it is relative to.
+fn safe_path(path: &str) -> Option<String> {
+    let bad = path.is_empty()
+        || path.starts_with('/')
+        || path
+            .split('/')
+            .any(|x| x.is_empty() || x == "." || x == "..");
+    (!bad).then(|| path.to_string())
+}
+
+/// Where the files of a tree are kept inside a bundle.
+fn tree_dir(tree: &str) -> String {
+    if tree == ROOT_TREE {
+        String::from("trees")
+    } else {
+        format!("trees/{}", tree)
+    }
+}
+
+/// The trees acted on: the `selected` mirror, every synced mirror of the
+/// mirrors file, or the root itself without one.
+fn tree_names(selected: Option<&MirrorCtx>) -> Vec<String> {
+    match selected.and_then(|x| x.name()) {
+        Some(o) => vec![o.to_string()],
+        None if !mirror_names().is_empty() => synced_mirror_names(),
+        None => vec![String::from(ROOT_TREE)],
+    }
+}
+
+fn tree_ctx(tree: &str) -> anyhow::Result<MirrorCtx> {
+    match tree == ROOT_TREE {
+        true => Ok(MirrorCtx::root()),
+        false => MirrorCtx::open(tree),
+    }
+}
+
+/// Prints the name of every blob in the store, the list export takes with
+/// --have on the mirror feeding this one.
+pub async fn print_blob_list() -> anyhow::Result<()> {
+    // A mirror that has not been filled yet has no store.
+    let blobs = match tokio::fs::try_exists(&paths().store).await? {
+        true => list_blobs(&paths().store).await?,
+        false => Vec::new(),
+    };
+    let mut names: Vec<String> = blobs
+        .into_iter()
+        .map(|x| x.0)
+        .filter(|x| is_sha256_name(x))
+        .collect();
+    names.sort();
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    for x in names.iter() {
+        writeln!(out, "{}", x)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+async fn read_have(path: &Path) -> anyhow::Result<HashSet<String>> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| is_sha256_name(x))
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// A bundle being written, straight into a directory or a tar archive.
+/// Either is built under a temporary name and renamed once complete.
+enum BundleWriter {
+    Dir {
+        tmp: PathBuf,
+        path: PathBuf,
+    },
+    Tar {
+        tar: TarWriter,
+        tmp: PathBuf,
+        path: PathBuf,
+    },
+}
+
+impl BundleWriter {
+    async fn create(path: &Path) -> anyhow::Result<BundleWriter> {
+        if tokio::fs::symlink_metadata(path).await.is_ok() {
+            return Err(anyhow::format_err!("{} already exists", path.display()));
+        }
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let _ = tokio::fs::remove_dir_all(&tmp).await;
+        let _ = tokio::fs::remove_file(&tmp).await;
+        if path.extension().is_some_and(|x| x == "tar") {
+            Ok(BundleWriter::Tar {
+                tar: TarWriter::create(&tmp, now_seconds()).await?,
+                tmp,
+                path: path.to_path_buf(),
+            })
+        } else {
+            tokio::fs::create_dir_all(&tmp).await?;
+            Ok(BundleWriter::Dir {
+                tmp,
+                path: path.to_path_buf(),
+            })
+        }
+    }
+
+    async fn add_file(&mut self, name: &str, source: &Path, sha256: &str) -> anyhow::Result<()> {
+        match self {
+            BundleWriter::Dir { tmp, .. } => {
+                let (from, to, sha256) = (source.to_path_buf(), tmp.join(name), sha256.to_string());
+                blocking(move || copy_verified(&from, &to, &sha256)).await?;
+                Ok(())
+            }
+            BundleWriter::Tar { tar, .. } => tar.add_file(name, source).await,
+        }
+    }
+
+    async fn add_bytes(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            BundleWriter::Dir { tmp, .. } => Ok(tokio::fs::write(tmp.join(name), data).await?),
+            BundleWriter::Tar { tar, .. } => tar.add_bytes(name, data).await,
+        }
+    }
+
+    async fn finish(self) -> anyhow::Result<()> {
+        let (tmp, path) = match self {
+            BundleWriter::Dir { tmp, path } => (tmp, path),
+            BundleWriter::Tar { tar, tmp, path } => {
+                tar.finish().await?;
+                (tmp, path)
+            }
+        };
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("failed to move {} to {}", tmp.display(), path.display()))
+    }
+}
+
+/// Signs `text` with gpg and returns the armored detached signature.
+async fn sign(text: &str, key: &str, scratch: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::create_dir_all(scratch).await?;
+    let file = scratch.join(MANIFEST);
+    tokio::fs::write(&file, text).await?;
+    let output = tokio::process::Command::new("gpg")
+        .arg("--batch")
+        .arg("--armor")
+        .arg("--local-user")
+        .arg(key)
+        .arg("--output")
+        .arg("-")
+        .arg("--detach-sign")
+        .arg(&file)
+        .output()
+        .await
+        .context("failed to run gpg")?;
+    let _ = tokio::fs::remove_file(&file).await;
+    if !output.status.success() {
+        return Err(anyhow::format_err!(
+            "failed to sign the manifest with {}: {}",
+            key,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Adds the published generation of tree `ctx` to `manifest`, and gives the
+/// files to put in the bundle as bundle name, source and SHA256.
+async fn export_tree(
+    ctx: &MirrorCtx,
+    tree: &str,
+    have: &HashSet<String>,
+    manifest: &mut Manifest,
+) -> anyhow::Result<Vec<(String, PathBuf, String)>> {
+    let mut ret = Vec::new();
+    let snapshot = match current_snapshot(ctx).await {
+        Some(o) => o,
+        None => {
+            warn!(tree, "nothing published, skipping");
+            return Ok(ret);
+        }
+    };
+    let mut entry = BundleTree {
+        name: tree.to_string(),
+        snapshot: snapshot.clone(),
+        ..BundleTree::default()
+    };
+
+    let dists = Path::new(&ctx.paths().snapshots)
+        .join(&snapshot)
+        .join("dists");
+    let dir = dists.clone();
+    let mut files = blocking(move || list_files(&dir)).await?;
+    files.sort();
+    for x in files.iter() {
+        let source = dists.join(x);
+        let sha256 = sha256_digest(&source.to_string_lossy()).await?;
+        let size = tokio::fs::metadata(&source).await?.len();
+        let path = format!("dists/{}", x.to_string_lossy());
+        ret.push((
+            format!("{}/{}", tree_dir(tree), path),
+            source,
+            sha256.clone(),
+        ));
+        entry.files.push(BundleFile { sha256, size, path });
+    }
+
+    let mut seen = HashSet::new();
+    let known: HashSet<&str> = manifest.blobs.iter().map(|x| x.0.as_str()).collect();
+    let mut added = Vec::new();
+    for x in snapshot_packages(ctx, &snapshot).await? {
+        if !seen.insert(x.filename.clone()) {
+            continue;
+        }
+        entry.pool.push((x.sha256.clone(), x.filename.clone()));
+        if have.contains(&x.sha256) || known.contains(x.sha256.as_str()) {
+            continue;
+        }
+        let source = PathBuf::from(blob_path(&x.sha256));
+        let size = tokio::fs::metadata(&source)
+            .await
+            .with_context(|| format!("blob {} of {} is missing", x.sha256, x.filename))?
+            .len();
+        ret.push((format!("blobs/{}", x.sha256), source, x.sha256.clone()));
+        added.push((x.sha256, size));
+    }
+    added.sort();
+    added.dedup();
+    manifest.blobs.extend(added);
+    manifest.trees.push(entry);
+    Ok(ret)
+}
+
+/// Writes a bundle of the published generation of every mirror: its dists
+/// files, the blobs its pool needs that are not in the --have list, and a
+/// manifest with the SHA256 of all of them, signed with --sign-key.
+pub async fn export_bundle(
+    selected: Option<&MirrorCtx>,
+    options: &ExportOptions,
+) -> anyhow::Result<()> {
+    let _store = lock_store(false).await?;
+    let trees = tree_names(selected);
+    let root = MirrorCtx::root().dir().to_path_buf();
+    let have = match &options.have {
+        Some(o) => read_have(&root.join(o)).await?,
+        None => HashSet::new(),
+    };
+    let mut manifest = Manifest {
+        date: http_date(now_seconds()),
+        ..Manifest::default()
+    };
+    let mut files = Vec::new();
+    for tree in trees.iter() {
+        let ctx = tree_ctx(tree)?;
+        files.extend(export_tree(&ctx, tree, &have, &mut manifest).await?);
+    }
+    if manifest.trees.is_empty() {
+        return Err(anyhow::format_err!(
+            "nothing is published, there is nothing to export"
+        ));
+    }
+
+    let output = root.join(&options.output);
+    let mut writer = BundleWriter::create(&output).await?;
+    let mut bytes = 0;
+    for (name, source, sha256) in files.iter() {
+        debug!(file = %name, "adding");
+        writer.add_file(name, source, sha256).await?;
+        bytes += tokio::fs::metadata(source).await?.len();
+    }
+    let text = manifest.to_text();
+    writer.add_bytes(MANIFEST, text.as_bytes()).await?;
+    if let Some(key) = &options.sign_key {
+        let signature = sign(&text, key, &root.join(&paths().tmp)).await?;
+        writer.add_bytes(SIGNATURE, &signature).await?;
+    }
+    writer.finish().await?;
+    info!(
+        trees = manifest.trees.len(),
+        blobs = manifest.blobs.len(),
+        files = files.len(),
+        bytes,
+        signed = options.sign_key.is_some(),
+        "exported {}",
+        output.display()
+    );
+    Ok(())
+}
+
+/// Checks the manifest signature as the import options ask for.
+async fn check_signature(dir: &Path, options: &ImportOptions) -> anyhow::Result<()> {
+    let manifest = dir.join(MANIFEST).to_string_lossy().to_string();
+    let signature = dir.join(SIGNATURE);
+    match (&options.keyring, signature.exists()) {
+        (Some(keyring), true) => {
+            verify_signature(&manifest, &signature.to_string_lossy(), keyring).await
+        }
+        (Some(_), false) => Err(anyhow::format_err!("the bundle is not signed")),
+        (None, _) if options.allow_unsigned => {
+            warn!("importing without checking the bundle signature");
+            Ok(())
+        }
+        (None, _) => Err(anyhow::format_err!(
+            "pass --keyring to check the bundle signature, or --allow-unsigned"
+        )),
+    }
+}
+
+/// Builds the generation a bundle carries for tree `ctx` beside the
+/// published ones. False if it is published already.
+async fn build_tree(ctx: &MirrorCtx, dir: &Path, tree: &BundleTree) -> anyhow::Result<bool> {
+    let target = format!("{}/{}", &ctx.paths().snapshots, tree.snapshot);
+    if tokio::fs::try_exists(&target).await? {
+        if current_snapshot(ctx).await.as_deref() == Some(tree.snapshot.as_str()) {
+            return Ok(false);
+        }
+        return Err(anyhow::format_err!("snapshot {} already exists", target));
+    }
+    let mut missing = 0;
+    for (sha256, path) in tree.pool.iter() {
+        if !tokio::fs::try_exists(blob_path(sha256)).await? {
+            warn!(file = %path, blob = %sha256, "blob is neither in the bundle nor in the store");
+            missing += 1;
+        }
+    }
+    if missing > 0 {
+        return Err(anyhow::format_err!(
+            "{} pool files of {} have no blob, export the bundle with the current blob list",
+            missing,
+            tree.name
+        ));
+    }
+
+    let building = format!("{}/.{}", &ctx.paths().snapshots, tree.snapshot);
+    let _ = tokio::fs::remove_dir_all(&building).await;
+    tokio::fs::create_dir_all(&building).await?;
+    let source = dir.join(tree_dir(&tree.name));
+    for x in tree.files.iter() {
+        let (from, to, sha256) = (
+            source.join(&x.path),
+            Path::new(&building).join(&x.path),
+            x.sha256.clone(),
+        );
+        blocking(move || copy_verified(&from, &to, &sha256))
+            .await
+            .with_context(|| format!("failed to import {}", x.path))?;
+    }
+    let previous = current_snapshot(ctx).await;
+    for (sha256, path) in tree.pool.iter() {
+        let loc = format!("{}/{}", building, path);
+        match &previous {
+            Some(o) => {
+                let previous = format!("{}/{}/{}", &ctx.paths().snapshots, o, path);
+                link_file_reusing(ctx, sha256, &loc, &previous).await?
+            }
+            None => link_file(ctx, sha256, &loc).await?,
+        }
+    }
+    Ok(true)
+}
+
+/// Moves the generation build_tree made into place and switches `current`
+/// to it.
+async fn publish_tree(ctx: &MirrorCtx, tree: &BundleTree) -> anyhow::Result<()> {
+    let building = format!("{}/.{}", &ctx.paths().snapshots, tree.snapshot);
+    let target = format!("{}/{}", &ctx.paths().snapshots, tree.snapshot);
+    tokio::fs::rename(&building, &target)
+        .await
+        .with_context(|| format!("failed to move {} to {}", building, target))?;
+    switch_current(ctx, &tree.snapshot).await
+}
+
+/// Undoes publish_tree: points `current` back at `previous`, or removes it
+/// when there was none, and drops the imported generation.
+async fn unpublish_tree(
+    ctx: &MirrorCtx,
+    tree: &BundleTree,
+    previous: &Option<String>,
+) -> anyhow::Result<()> {
+    match previous {
+        Some(o) => switch_current(ctx, o).await?,
+        None => tokio::fs::remove_file(&ctx.paths().current)
+            .await
+            .with_context(|| format!("failed to remove {}", ctx.paths().current))?,
+    }
+    let target = format!("{}/{}", &ctx.paths().snapshots, tree.snapshot);
+    tokio::fs::remove_dir_all(&target)
+        .await
+        .with_context(|| format!("failed to remove {}", target))
+}
+
+async fn import_dir(dir: &Path, options: &ImportOptions) -> anyhow::Result<()> {
+    check_signature(dir, options).await?;
+    let path = dir.join(MANIFEST);
+    let text = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let manifest = Manifest::parse(&text)?;
+    for tree in manifest.trees.iter() {
+        if (tree.name == ROOT_TREE) != mirror_names().is_empty() {
+            return Err(anyhow::format_err!(
+                "the bundle tree {} does not match the mirrors here",
+                tree.name
+            ));
+        }
+        if safe_path(&tree.snapshot).is_none_or(|x| x.contains('/')) {
+            return Err(anyhow::format_err!(
+                "invalid snapshot name {}",
+                tree.snapshot
+            ));
+        }
+    }
+
+    // Blobs nothing refers to yet are harmless, they go in first.
+    let results = futures::stream::iter(manifest.blobs.iter().map(|(sha256, _)| {
+        let from = dir.join("blobs").join(sha256);
+        let to = PathBuf::from(blob_path(sha256));
+        let sha256 = sha256.clone();
+        async move {
+            if to.exists() {
+                return Ok(false);
+            }
+            blocking(move || copy_verified(&from, &to, &sha256)).await?;
+            Ok::<bool, anyhow::Error>(true)
+        }
+    }))
+    .buffer_unordered(NUM_THREADS)
+    .collect::<Vec<_>>()
+    .await;
+    let mut added = 0;
+    for (result, (sha256, _)) in results.into_iter().zip(manifest.blobs.iter()) {
+        if result.with_context(|| format!("failed to import blob {}", sha256))? {
+            added += 1;
+        }
+    }
+
+    // Every tree is built and locked before the first one is switched.
+    let mut locks = Vec::new();
+    let mut built = Vec::new();
+    for tree in manifest.trees.iter() {
+        let ctx = tree_ctx(&tree.name)?;
+        locks.push(lock_root(&ctx)?);
+        if build_tree(&ctx, dir, tree).await? {
+            built.push((ctx, tree));
+        } else {
+            info!(tree = %tree.name, snapshot = %tree.snapshot, "already published");
+        }
+    }
+    // A tree failing to switch puts back those switched before it, so the
+    // mirrors do not serve generations of different bundles.
+    let mut switched: Vec<(&MirrorCtx, &BundleTree, Option<String>)> = Vec::new();
+    for (ctx, tree) in built.iter() {
+        let previous = current_snapshot(ctx).await;
+        if let Err(e) = publish_tree(ctx, tree).await {
+            let target = format!("{}/{}", &ctx.paths().snapshots, tree.snapshot);
+            let _ = tokio::fs::remove_dir_all(&target).await;
+            let mut undone = true;
+            for (ctx, tree, previous) in switched.iter().rev() {
+                if let Err(e) = unpublish_tree(ctx, tree, previous).await {
+                    error!(tree = %tree.name, "failed to switch back: {:#}", e);
+                    undone = false;
+                }
+            }
+            return Err(e.context(match undone {
+                true => format!("failed to publish {}, the import is rolled back", tree.name),
+                false => format!(
+                    "failed to publish {}, earlier trees stay switched to the bundle",
+                    tree.name
+                ),
+            }));
+        }
+        info!(tree = %tree.name, snapshot = %tree.snapshot, "published imported snapshot");
+        switched.push((ctx, *tree, previous));
+    }
+    for (ctx, _, _) in switched.iter() {
+        prune_snapshots(ctx, options.keep).await?;
+    }
+    info!(
+        blobs = added,
+        trees = built.len(),
+        date = %manifest.date,
+        "imported bundle"
+    );
+    Ok(())
+}
+
+/// Imports a bundle written by export: checks the manifest signature, copies
+/// the blobs into the store checking each against its SHA256, builds every
+/// generation beside the published ones with checked dists files, and only
+/// then switches the current link of each mirror to its new generation,
+/// switching back all of them if one fails. Like publish, it then drops all
+/// but the newest `keep` generations.
+pub async fn import_bundle(options: &ImportOptions) -> anyhow::Result<()> {
+    if options.keep == 0 {
+        return Err(anyhow::format_err!("--keep must be at least 1"));
+    }
+    let _store = lock_store(false).await?;
+    let root = MirrorCtx::root().dir().to_path_buf();
+    let path = root.join(&options.bundle);
+    if tokio::fs::metadata(&path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .is_dir()
+    {
+        return import_dir(&path, options).await;
+    }
+    let dir = root.join(&paths().tmp).join("import");
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    tokio::fs::create_dir_all(&dir).await?;
+    let result = match extract(&path, &dir).await {
+        Ok(()) => import_dir(&dir, options).await,
+        Err(e) => Err(e).with_context(|| format!("failed to unpack {}", path.display())),
+    };
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const B: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    #[test]
+    fn manifests_round_trip() {
+        let text = format!(
+            "Format: {}\nDate: Sat, 17 Oct 2026 10:00:00 UTC\nBlob: {} 1234\n\
+             Tree: . 20261017T100000Z\nFile: {} 56 dists/stable/Release\n\
+             Pool: {} pool/main/h/hello/hello_2.10-3_amd64.deb\n\
+             Tree: small 20261017T100000Z\n",
+            FORMAT, A, B, A
+        );
+        let manifest = Manifest::parse(&text).unwrap();
+        assert_eq!(manifest.date, "Sat, 17 Oct 2026 10:00:00 UTC");
+        assert_eq!(manifest.blobs, vec![(A.to_string(), 1234)]);
+        assert_eq!(manifest.trees.len(), 2);
+        assert_eq!(manifest.trees[0].files[0].path, "dists/stable/Release");
+        assert_eq!(manifest.trees[0].pool.len(), 1);
+        assert!(manifest.trees[1].files.is_empty());
+        assert_eq!(manifest.to_text(), text);
+    }
+
+    #[test]
+    fn manifests_refuse_bad_lines() {
+        let head = format!("Format: {}\nTree: . 1\n", FORMAT);
+        for x in [
+            format!("{}File: {} 1 ../etc/passwd\n", head, A),
+            format!("{}File: {} 1 /etc/passwd\n", head, A),
+            format!("{}Pool: {} pool//x.deb\n", head, A),
+            format!("{}Pool: xyz pool/x.deb\n", head),
+            format!("{}Blob: {} many\n", head, A),
+            format!("{}Other: 1\n", head),
+            format!("Format: {}\nFile: {} 1 dists/x\n", FORMAT, A),
+            String::from("Date: now\n"),
+            String::from("Format: 0\n"),
+        ] {
+            assert!(Manifest::parse(&x).is_err(), "{:?}", x);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use anyhow::Context;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
+use tokio::sync::Notify;
+
+use crate::gc::now_seconds;
+use crate::http;
+use crate::http::Request;
+use crate::http::Response;
+use crate::metrics;
+use crate::mirrors::find_mirror;
+use crate::mirrors::mirror_names;
+use crate::mirrors::synced_mirror_names;
+use crate::mirrors::MirrorCtx;
+use crate::schedule::Schedule;
+use crate::serve::start_server;
+use crate::serve::ServeOptions;
+use crate::sync::sync;
+use crate::sync::SyncOptions;
+use tracing::error;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Instrument;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8089";
+const DEFAULT_SCHEDULE: &str = "6h";
+
+/// Name the status API gives the mirror when there is no mirrors file.
+const DEFAULT_MIRROR: &str = "default";
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct DaemonOptions {
+    /// Address of the status, trigger and metrics API
+    #[arg(long, default_value = DEFAULT_LISTEN)]
+    pub listen: String,
+
+    /// Schedule of mirrors without one in the mirrors file, an interval such
+    /// as 6h or a cron expression such as "0 */6 * * *"
+    #[arg(long, default_value = DEFAULT_SCHEDULE)]
+    pub schedule: String,
+
+    /// Also serve the mirror on this address, as the serve command does
+    #[arg(long, value_name = "ADDR")]
+    pub serve: Option<String>,
+
+    /// Access log of the mirror served with --serve
+    #[arg(long, value_name = "PATH", requires = "serve")]
+    pub access_log: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub sync: SyncOptions,
+}
+
+/// What the status API reports about one mirror.
+#[derive(Debug, Clone, serde::Serialize)]
+struct MirrorStatus {
+    name: String,
+    schedule: String,
+    running: bool,
+    /// Waiting for its turn, after a trigger or while already running.
+    queued: bool,
+    next_run: Option<u64>,
+    last_start: Option<u64>,
+    last_end: Option<u64>,
+    last_result: Option<&'static str>,
+    last_error: Option<String>,
+    runs: u64,
+    failures: u64,
+}
+
+struct Daemon {
+    status: Mutex<Vec<MirrorStatus>>,
+    wake: Notify,
+    /// Whether the mirrors come from a mirrors file and have subtrees.
+    subtrees: bool,
+    textfile: Option<PathBuf>,
+    /// Held while the textfile is written, so syncs finishing together do
+    /// not count the same counters twice.
+    textfile_lock: tokio::sync::Mutex<()>,
+}
+
+impl Daemon {
+    /// Queues a sync of mirror `name`, or of every mirror. A mirror that is
+    /// syncing already runs once more afterwards. False for unknown names.
+    fn trigger(&self, name: Option<&str>) -> bool {
+        let mut found = false;
+        for x in self.status.lock().unwrap().iter_mut() {
+            if name.is_none_or(|n| n == x.name) {
+                x.queued = true;
+                found = true;
+            }
+        }
+        if found {
+            self.wake.notify_one();
+        }
+        found
+    }
+
+    /// Takes the first queued mirror that is not running and marks it
+    /// running.
+    fn next_queued(&self) -> Option<String> {
+        let mut status = self.status.lock().unwrap();
+        let x = status.iter_mut().find(|x| x.queued && !x.running)?;
+        x.queued = false;
+        x.running = true;
+        x.last_start = Some(now_seconds());
+        Some(x.name.clone())
+    }
+
+    fn finish(&self, name: &str, result: &anyhow::Result<()>) {
+        let mut status = self.status.lock().unwrap();
+        if let Some(x) = status.iter_mut().find(|x| x.name == name) {
+            x.running = false;
+            x.last_end = Some(now_seconds());
+            x.runs += 1;
+            match result {
+                Ok(()) => {
+                    x.last_result = Some("success");
+                    x.last_error = None;
+                }
+                Err(e) => {
+                    x.last_result = Some("failure");
+                    x.last_error = Some(format!("{:#}", e));
+                    x.failures += 1;
+                }
+            }
+        }
+    }
+
+    fn set_next_run(&self, name: &str, next: Option<u64>) {
+        let mut status = self.status.lock().unwrap();
+        if let Some(x) = status.iter_mut().find(|x| x.name == name) {
+            x.next_run = next;
+        }
+    }
+
+    async fn run_one(&self, name: &str, options: &SyncOptions) -> anyhow::Result<()> {
+        let ctx = match self.subtrees {
+            true => MirrorCtx::open(name)?,
+            false => MirrorCtx::root(),
+        };
+        sync(&ctx, options).await
+    }
+
+    /// Syncs mirror `name` and records the outcome.
+    async fn run(&self, name: String, options: &SyncOptions) {
+        let span = info_span!("mirror", mirror = %name);
+        let result = self.run_one(&name, options).instrument(span.clone()).await;
+        if let Err(e) = &result {
+            span.in_scope(|| error!("{:#}", e));
+        }
+        self.finish(&name, &result);
+        if let Some(path) = &self.textfile {
+            let _guard = self.textfile_lock.lock().await;
+            if let Err(e) = metrics::write_textfile(&path.to_string_lossy()).await {
+                error!("{:#}", e);
+            }
+        }
+    }
+
+    /// Starts every queued sync as soon as it is queued. Every mirror works
+    /// in its own tree, so syncs of different mirrors run side by side,
+    /// while a mirror is never synced twice at once.
+    async fn worker(&self, options: &SyncOptions) {
+        let mut running = FuturesUnordered::new();
+        loop {
+            while let Some(name) = self.next_queued() {
+                running.push(self.run(name, options));
+            }
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                Some(()) = running.next(), if !running.is_empty() => {}
+            }
+        }
+    }
+
+    /// Queues mirror `name` whenever its schedule is due. Intervals start
+    /// with a sync right away, cron expressions wait for their next match.
+    async fn scheduler(&self, name: String, schedule: Schedule) {
+        let now = now_seconds();
+        let mut next = match schedule {
+            Schedule::Interval(_) => Some(now),
+            Schedule::Cron(_) => schedule.next_after(now),
+        };
+        loop {
+            self.set_next_run(&name, next);
+            let at = match next {
+                Some(o) => o,
+                None => {
+                    warn!(mirror = %name, "schedule never matches, only triggers sync the mirror");
+                    return;
+                }
+            };
+            let now = now_seconds();
+            if at > now {
+                tokio::time::sleep(std::time::Duration::from_secs(at - now)).await;
+            }
+            self.trigger(Some(&name));
+            // Runs missed while suspended or busy are not made up for.
+            let now = now_seconds();
+            next = schedule.next_after(at);
+            while let Some(x) = next.filter(|x| *x < now) {
+                next = schedule.next_after(x);
+            }
+        }
+    }
+
+    async fn handle(&self, request: Request) -> Response {
+        let path = request.path.trim_end_matches('/');
+        match (request.method.as_str(), path) {
+            ("GET" | "HEAD", "/status") => {
+                let status = self.status.lock().unwrap().clone();
+                match serde_json::to_vec_pretty(&status) {
+                    Ok(o) => Response::new(200, "application/json", o),
+                    Err(e) => Response::text(500, e.to_string()),
+                }
+            }
+            ("GET" | "HEAD", "/metrics") => Response::new(
+                200,
+                "text/plain; version=0.0.4; charset=utf-8",
+                metrics::render(),
+            ),
+            ("POST", "/trigger") => {
+                info!("sync of every mirror triggered over HTTP");
+                self.trigger(None);
+                Response::text(202, "queued")
+            }
+            ("POST", x) if x.starts_with("/trigger/") => {
+                let name = &x["/trigger/".len()..];
+                if self.trigger(Some(name)) {
+                    info!(mirror = %name, "sync triggered over HTTP");
+                    Response::text(202, "queued")
+                } else {
+                    Response::text(404, format!("no mirror {}", name))
+                }
+            }
+            (_, "/status" | "/metrics" | "/trigger") => Response::text(405, "method not allowed"),
+            _ => Response::text(404, "not found"),
+        }
+    }
+}
+
+/// Keeps syncing the mirrors on their schedules until SIGINT or SIGTERM.
+/// SIGUSR1 or a POST to /trigger syncs every mirror early, a POST to
+/// /trigger/NAME a single one, and GET /status and /metrics report on the
+/// runs. The metrics are also written to `textfile` after each sync.
+///
+/// Acts on the `selected` mirror only, if there is one.
+pub async fn daemon(
+    selected: Option<&MirrorCtx>,
+    options: &DaemonOptions,
+    textfile: Option<&Path>,
+) -> anyhow::Result<()> {
+    let (names, subtrees) = match selected.and_then(|x| x.name()) {
+        Some(o) => (vec![o.to_string()], true),
+        None if !mirror_names().is_empty() => (synced_mirror_names(), true),
+        None => (vec![String::from(DEFAULT_MIRROR)], false),
+    };
+    let mut schedules = Vec::new();
+    let mut status = Vec::new();
+    for name in names.iter() {
+        let text = find_mirror(name)
+            .and_then(|x| x.schedule)
+            .unwrap_or_else(|| options.schedule.clone());
+        let schedule =
+            Schedule::parse(&text).with_context(|| format!("invalid schedule of {}", name))?;
+        schedules.push((name.clone(), schedule));
+        status.push(MirrorStatus {
+            name: name.clone(),
+            schedule: text,
+            running: false,
+            queued: false,
+            next_run: None,
+            last_start: None,
+            last_end: None,
+            last_result: None,
+            last_error: None,
+            runs: 0,
+            failures: 0,
+        });
+    }
+    let daemon = Arc::new(Daemon {
+        status: Mutex::new(status),
+        wake: Notify::new(),
+        subtrees,
+        textfile: textfile.map(|x| x.to_path_buf()),
+        textfile_lock: tokio::sync::Mutex::new(()),
+    });
+    if let Some(listen) = &options.serve {
+        let options = ServeOptions {
+            listen: listen.clone(),
+            access_log: options.access_log.clone(),
+        };
+        let files = start_server(selected, &options).await?;
+        tokio::spawn(async move {
+            if let Err(e) = files.await {
+                error!("serving the mirror failed: {:#}", e);
+            }
+        });
+    }
+    let listener = tokio::net::TcpListener::bind(&options.listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", options.listen))?;
+    info!(listen = %options.listen, mirrors = names.len(), "daemon started");
+    let shared = Arc::clone(&daemon);
+    let api = http::serve(listener, move |request| {
+        let daemon = Arc::clone(&shared);
+        async move { daemon.handle(request).await }
+    });
+
+    for (name, schedule) in schedules {
+        let daemon = Arc::clone(&daemon);
+        tokio::spawn(async move { daemon.scheduler(name, schedule).await });
+    }
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let shared = Arc::clone(&daemon);
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            info!("sync of every mirror triggered by SIGUSR1");
+            shared.trigger(None);
+        }
+    });
+
+    let mut term = signal(SignalKind::terminate())?;
+    // An interrupted sync resumes from its last finished stage next time.
+    tokio::select! {
+        _ = daemon.worker(&options.sync) => Ok(()),
+        result = api => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("interrupted, shutting down");
+            Ok(())
+        }
+        _ = term.recv() => {
+            info!("terminated, shutting down");
+            Ok(())
+        }
+    }
+}
@@ -1,34 +1,290 @@
-mod download_dist;
-use download_dist::clean_sha;
-use download_dist::download_dist;
-use download_dist::download_pool;
-use download_dist::link_pool;
-use download_dist::make_config;
+use clap::CommandFactory;
+use clap::Parser;
+use deb_mirror::bundle::export_bundle;
+use deb_mirror::bundle::import_bundle;
+use deb_mirror::bundle::print_blob_list;
+use deb_mirror::bundle::ExportOptions;
+use deb_mirror::bundle::ImportOptions;
+use deb_mirror::daemon::daemon;
+use deb_mirror::daemon::DaemonOptions;
+use deb_mirror::download_dist::clean_sha;
+use deb_mirror::download_dist::download_dist;
+use deb_mirror::download_dist::download_pool;
+use deb_mirror::download_dist::link_pool;
+use deb_mirror::download_dist::make_config;
+use deb_mirror::fsck::fsck_sha;
+use deb_mirror::fsck::FsckOptions;
+use deb_mirror::gc::gc_sha;
+use deb_mirror::gc::GcOptions;
+use deb_mirror::logging::init_logging;
+use deb_mirror::logging::LogOptions;
+use deb_mirror::media::write_media;
+use deb_mirror::media::MediaOptions;
+use deb_mirror::metrics;
+use deb_mirror::mirrors::load_mirrors;
+use deb_mirror::mirrors::mirror_names;
+use deb_mirror::mirrors::shared_path;
+use deb_mirror::mirrors::synced_mirror_names;
+use deb_mirror::mirrors::MirrorCtx;
+use deb_mirror::paths::set_paths;
+use deb_mirror::paths::Paths;
+use deb_mirror::proxy::proxy;
+use deb_mirror::proxy::ProxyOptions;
+use deb_mirror::serve::serve;
+use deb_mirror::serve::ServeOptions;
+use deb_mirror::snapshot::diff_snapshots;
+use deb_mirror::snapshot::publish_snapshot;
+use deb_mirror::snapshot::rollback_snapshot;
+use deb_mirror::snapshot::PublishOptions;
+use deb_mirror::store::migrate_store;
+use deb_mirror::sync;
+use deb_mirror::sync::sync;
+use deb_mirror::sync::SyncOptions;
+use tracing::error;
+use tracing::info_span;
+use tracing::Instrument;
+
+/// Debian mirror keeping every file once in a SHA256 content addressed store
+#[derive(Parser)]
+#[command(name = "deb_mirror", version)]
+struct Cli {
+    /// Mirror root directory, every relative path is taken from here
+    #[arg(long, global = true)]
+    root: Option<std::path::PathBuf>,
+
+    /// Mirror of the mirrors file to act on, all of them for sync when unset
+    #[arg(long, global = true)]
+    mirror: Option<String>,
+
+    /// Write metrics to this node_exporter textfile after the run
+    #[arg(long, global = true, value_name = "PATH")]
+    metrics_textfile: Option<std::path::PathBuf>,
+
+    #[command(flatten)]
+    log: LogOptions,
+
+    #[command(flatten)]
+    paths: Paths,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Generate the mirror and dists lists from the configuration
+    #[command(alias = "s")]
+    Config,
+    /// Download the dists files, staged until the next link
+    #[command(alias = "d")]
+    Dist,
+    /// Download the pool files listed in the Packages indices
+    #[command(alias = "p")]
+    Pool,
+    /// Move blobs still referenced back from the waste directory
+    #[command(alias = "c")]
+    Clean,
+    /// Link pool files, as set in the link mode file (symlink, hardlink, reflink or copy), then put the staged dists files in place
+    #[command(alias = "l")]
+    Link,
+    /// Delete unreferenced blobs from the store
+    #[command(alias = "g")]
+    Gc(GcOptions),
+    /// Verify every blob against its name
+    #[command(alias = "v")]
+    Verify(FsckOptions),
+    /// Migrate the store to a LEVELS deep fan-out
+    #[command(alias = "m")]
+    Migrate { levels: usize },
+    /// Publish a new snapshot, holding pinned packages, and switch current to it
+    #[command(alias = "n")]
+    Publish(PublishOptions),
+    /// Switch current back to an older snapshot
+    Rollback { snapshot: String },
+    /// List packages that changed between two snapshots
+    Diff { a: String, b: String },
+    /// Run config, dist, pool, link, publish and gc under a lock, resuming after a crash
+    ///
+    /// Exits 2 if another sync holds the lock, 10 to 15 if config, dist,
+    /// pool, link, publish or gc failed.
+    Sync(SyncOptions),
+    /// Sync the mirrors on their schedules, on SIGUSR1 and on HTTP triggers
+    ///
+    /// Serves GET /status, GET /metrics, POST /trigger and
+    /// POST /trigger/NAME on the listen address.
+    Daemon(DaemonOptions),
+    /// Serve the published dists and pool trees over HTTP
+    Serve(ServeOptions),
+    /// Cache what apt clients ask for instead of mirroring in full
+    ///
+    /// Serves the mirrors with proxy = true, or the selected or legacy one.
+    Proxy(ProxyOptions),
+    /// Print the blobs in the store, the list export --have takes
+    Manifest,
+    /// Write a bundle of the published mirrors for an offline mirror
+    ///
+    /// The bundle holds the dists files, the pool blobs missing from the
+    /// --have list and a manifest of their SHA256, signed with --sign-key.
+    Export(ExportOptions),
+    /// Check a bundle written by export and publish what it carries
+    Import(ImportOptions),
+    /// Write the pool, or the packages matching --include and --exclude, as
+    /// ISO 9660 images of the given size
+    ///
+    /// Every image carries Packages indices for its own files, a Release
+    /// file and .disk/info, so each can be added with apt-cdrom.
+    Media(MediaOptions),
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+}
+
+impl Command {
+    /// Whether the command works on a single mirror subtree rather than on
+    /// the shared store or on every mirror.
+    fn needs_mirror(&self) -> bool {
+        !matches!(
+            self,
+            Command::Gc(_)
+                | Command::Migrate { .. }
+                | Command::Sync(_)
+                | Command::Daemon(_)
+                | Command::Serve(_)
+                | Command::Proxy(_)
+                | Command::Manifest
+                | Command::Export(_)
+                | Command::Import(_)
+                | Command::Completions { .. }
+        )
+    }
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args: Vec<_> = std::env::args().collect();
-    if args.len() > 1 {
-        if args[1].eq("d") {
-            return download_dist().await;
-        } else if args[1].eq("p") {
-            return download_pool().await;
-        } else if args[1].eq("c") {
-            return clean_sha().await;
-        } else if args[1].eq("l") {
-            return link_pool().await;
-        } else if args[1].eq("s") {
-            return make_config().await;
-        } else {
-            return Err(anyhow::format_err!("Unknown command"));
+async fn main() {
+    let cli = Cli::parse();
+    let log = match init_logging(&cli.log) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = run(cli).await {
+        error!("{:#}", e);
+        let status = sync::exit_code(&e);
+        // Exiting skips destructors, flush the log first.
+        drop(log);
+        std::process::exit(status);
+    }
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    if let Some(root) = &cli.root {
+        std::env::set_current_dir(root)
+            .map_err(|e| anyhow::format_err!("failed to enter {}: {}", root.display(), e))?;
+    }
+    let mut paths = cli.paths;
+    load_mirrors(&paths)?;
+    paths.store = shared_path(&paths.store);
+    paths.waste = shared_path(&paths.waste);
+    paths.quarantine = shared_path(&paths.quarantine);
+    paths.gc_state = shared_path(&paths.gc_state);
+    set_paths(paths);
+    let textfile = match &cli.metrics_textfile {
+        Some(o) => Some(std::env::current_dir()?.join(o)),
+        None => None,
+    };
+
+    let result = match &cli.mirror {
+        Some(name) => {
+            let ctx = MirrorCtx::open(name)?;
+            let span = info_span!("mirror", mirror = %name);
+            dispatch(cli.command, Some(&ctx), textfile.as_deref())
+                .instrument(span)
+                .await
+        }
+        None if cli.command.needs_mirror() && !mirror_names().is_empty() => {
+            Err(anyhow::format_err!(
+                "{} configures mirrors, pick one with --mirror",
+                deb_mirror::paths::paths().mirrors
+            ))
+        }
+        None => dispatch(cli.command, None, textfile.as_deref()).await,
+    };
+    if let Some(path) = textfile {
+        // A failed run is still worth recording.
+        if let Err(e) = metrics::write_textfile(&path.to_string_lossy()).await {
+            error!("{:#}", e);
+        }
+    }
+    result
+}
+
+/// Runs `command` on the `selected` mirror, or on the root without one.
+async fn dispatch(
+    command: Command,
+    selected: Option<&MirrorCtx>,
+    textfile: Option<&std::path::Path>,
+) -> anyhow::Result<()> {
+    let ctx = match selected {
+        Some(o) => o.clone(),
+        None => MirrorCtx::root(),
+    };
+    match command {
+        Command::Config => make_config(&ctx).await,
+        Command::Dist => download_dist(&ctx).await,
+        Command::Pool => download_pool(&ctx).await,
+        Command::Clean => clean_sha(&ctx).await,
+        Command::Link => link_pool(&ctx).await,
+        Command::Gc(options) => gc_sha(&options).await,
+        Command::Verify(options) => fsck_sha(&ctx, options).await,
+        Command::Migrate { levels } => migrate_store(levels).await,
+        Command::Publish(options) => publish_snapshot(&ctx, &options).await,
+        Command::Rollback { snapshot } => rollback_snapshot(&ctx, &snapshot).await,
+        Command::Diff { a, b } => diff_snapshots(&ctx, &a, &b).await,
+        Command::Sync(options) => {
+            let names = match selected {
+                Some(_) => Vec::new(),
+                None => synced_mirror_names(),
+            };
+            if names.is_empty() {
+                return sync(&ctx, &options).await;
+            }
+            // One mirror failing does not hold back the others, the exit
+            // status is that of the first failure.
+            let mut first_error = None;
+            for name in names.iter() {
+                let span = info_span!("mirror", mirror = %name);
+                let result = match MirrorCtx::open(name) {
+                    Ok(o) => sync(&o, &options).instrument(span.clone()).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    span.in_scope(|| error!("{:#}", e));
+                    if first_error.is_none() {
+                        first_error = Some(e.context(format!("sync of mirror {} failed", name)));
+                    }
+                }
+            }
+            match first_error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+        Command::Daemon(options) => daemon(selected, &options, textfile).await,
+        Command::Serve(options) => serve(selected, &options).await,
+        Command::Proxy(options) => proxy(selected, &options).await,
+        Command::Manifest => print_blob_list().await,
+        Command::Export(options) => export_bundle(selected, &options).await,
+        Command::Import(options) => import_bundle(&options).await,
+        Command::Media(options) => write_media(&ctx, &options).await,
+        Command::Completions { shell } => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "deb_mirror",
+                &mut std::io::stdout(),
+            );
+            Ok(())
         }
-    } else {
-        println!("Run with 1 command line argument...");
-        println!("s => generate config files from sources.list");
-        println!("d => download dist");
-        println!("p => download pool");
-        println!("c => clean sha256 directory");
-        println!("l => link pool files");
-        return Err(anyhow::format_err!("No command given"));
     }
 }
@@ -4,8 +4,8 @@ use anyhow::Context;
 use futures::StreamExt;
 use sha2::Digest;
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::fs;
 use std::io::prelude::*;
 use std::path::Path;
@@ -13,25 +13,53 @@ use std::process::ExitStatus;
 use std::sync::Mutex;
 use tokio::io::AsyncReadExt;
 
+use crate::link_mode::link_file;
+use crate::link_mode::LinkMode;
+use crate::metrics;
+use crate::mirrors::write_mirror_lists;
+use crate::mirrors::MirrorCtx;
+use crate::paths::paths;
+use crate::progress::Progress;
+use crate::progress::Reporter;
+use crate::release::merge_releases;
+use crate::release::parse_release_date;
+use crate::release::verify_signature;
+use crate::release::ReleaseFile;
+use crate::sources::parse_sources_list;
+use crate::store::blob_path;
+use crate::store::blob_path_in;
+use crate::store::lock_store;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
 const TEXT_PACKAGE: &str = "Package: ";
 const TEXT_VERSION: &str = "Version: ";
 const TEXT_FILENAME: &str = "Filename: ";
 const TEXT_SHA256: &str = "SHA256: ";
+const TEXT_SIZE: &str = "Size: ";
 
-const TEXT_DEB: &str = "deb";
-const TEXT_HTTP: &str = "http://";
-const TEXT_HTTPS: &str = "https://";
-
-const STORE: &str = "SHA256";
-const TMP: &str = "TMP";
-const WASTE: &str = "WASTE";
-
-async fn sha256_digest(dest: &str) -> anyhow::Result<String> {
-    let data = tokio::fs::read(&dest).await?;
+/// Hashes the file in fixed size chunks so large blobs are never held in
+/// memory at once.
+pub(crate) fn sha256_file(dest: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(dest)?;
     let mut hasher = sha2::Sha256::new();
-    hasher.update(data);
-    let result = hasher.finalize();
-    let ret = hex::encode(result);
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub(crate) async fn sha256_digest(dest: &str) -> anyhow::Result<String> {
+    let path = std::path::PathBuf::from(dest);
+    let ret = tokio::task::spawn_blocking(move || sha256_file(&path))
+        .await?
+        .with_context(|| format!("failed to hash {}", dest))?;
     Ok(ret)
 }
 
@@ -49,16 +77,123 @@ fn map_num_url_to_num_threads(num_url: u16) -> u16 {
     }
 }
 
-async fn read_list_url_mirrors() -> anyhow::Result<String> {
-    tokio::fs::read_to_string("list.url_mirrors.txt")
+pub(crate) async fn read_list_url_mirrors(ctx: &MirrorCtx) -> anyhow::Result<String> {
+    tokio::fs::read_to_string(&ctx.paths().url_mirrors)
         .await
-        .with_context(|| format!("failed to read list.url_mirrors.txt"))
+        .with_context(|| format!("failed to read {}", ctx.paths().url_mirrors))
+}
+
+/// The upstream hosts of a mirror: those of list.url_mirrors.txt and those
+/// list.arch_mirrors.txt names for single architectures, such as
+/// ports.ubuntu.com for arm64 beside archive.ubuntu.com.
+pub(crate) struct Upstreams {
+    pub(crate) default: Vec<String>,
+    pub(crate) by_arch: BTreeMap<String, Vec<String>>,
+    /// Those that served the Release files of the last dist run, from
+    /// list.fresh_mirrors.txt.
+    pub(crate) fresh: Vec<String>,
 }
 
-async fn read_list_dist_packages() -> anyhow::Result<String> {
-    tokio::fs::read_to_string("list.dist_packages.txt")
+impl Upstreams {
+    /// Reads both lists; a tree configured before the architecture list
+    /// existed has none.
+    pub(crate) async fn read(ctx: &MirrorCtx) -> anyhow::Result<Upstreams> {
+        let default = read_list_url_mirrors(ctx)
+            .await?
+            .lines()
+            .map(|x| x.trim().trim_end_matches('/'))
+            .filter(|x| x.len() > 7)
+            .map(|x| x.to_string())
+            .collect();
+        let text = read_optional(&ctx.paths().arch_mirrors).await?;
+        let mut by_arch: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for line in text.lines() {
+            if let Some((arch, url)) = line.trim().split_once(' ') {
+                by_arch
+                    .entry(arch.to_string())
+                    .or_default()
+                    .push(url.trim().trim_end_matches('/').to_string());
+            }
+        }
+        let fresh = read_optional(&ctx.paths().fresh_mirrors)
+            .await?
+            .lines()
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect();
+        Ok(Upstreams {
+            default,
+            by_arch,
+            fresh,
+        })
+    }
+
+    /// URLs files of `arch` are fetched from, in order of preference.
+    pub(crate) fn for_arch(&self, arch: Option<&str>) -> &[String] {
+        match arch.and_then(|x| self.by_arch.get(x)) {
+            Some(o) => o,
+            None => &self.default,
+        }
+    }
+
+    /// Every URL, each once.
+    pub(crate) fn all(&self) -> Vec<&str> {
+        let mut ret: Vec<&str> = Vec::new();
+        for x in self.default.iter().chain(self.by_arch.values().flatten()) {
+            if !ret.contains(&x.as_str()) {
+                ret.push(x);
+            }
+        }
+        ret
+    }
+
+    /// The distinct URL lists, the default first. Each serves its own
+    /// Release files, which are merged when there is more than one.
+    pub(crate) fn groups(&self) -> Vec<&[String]> {
+        let mut ret: Vec<&[String]> = vec![&self.default];
+        for x in self.by_arch.values() {
+            if !ret.contains(&x.as_slice()) {
+                ret.push(x);
+            }
+        }
+        ret
+    }
+}
+
+async fn read_optional(path: &str) -> anyhow::Result<String> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(o) => Ok(o),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path)),
+    }
+}
+
+/// Where the dists file `file` of upstream `host` is downloaded to before
+/// it is checked and moved into dists.
+pub(crate) fn host_file(ctx: &MirrorCtx, host: &str, file: &str) -> String {
+    let name: String = host
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}/releases/{}/{}", ctx.paths().tmp, name, file)
+}
+
+/// Architecture of a dists index such as `dists/noble/main/binary-arm64/Packages`.
+pub(crate) fn index_arch(file: &str) -> Option<&str> {
+    file.split('/').find_map(|x| x.strip_prefix("binary-"))
+}
+
+/// Architecture of a pool file such as `pool/main/h/hello/hello_1.0_arm64.deb`.
+fn pool_arch(filename: &str) -> Option<&str> {
+    let base = filename.rsplit('/').next()?;
+    let stem = base.rsplit_once('.')?.0;
+    Some(stem.rsplit_once('_')?.1)
+}
+
+pub(crate) async fn read_list_dist_packages(ctx: &MirrorCtx) -> anyhow::Result<String> {
+    tokio::fs::read_to_string(&ctx.paths().dist_packages)
         .await
-        .with_context(|| format!("failed to read list.dist_packages.txt"))
+        .with_context(|| format!("failed to read {}", ctx.paths().dist_packages))
 }
 
 async fn download_wget(url: &str, file_name: &str) -> anyhow::Result<()> {
@@ -166,7 +301,36 @@ async fn mkdir(loc: &str) -> anyhow::Result<()> {
     }
 }
 
-async fn do_link(sha: &str, loc: &str) -> anyhow::Result<()> {
+/// Path of `target` relative to the directory holding `loc`, both absolute,
+/// so links into a store shared by several mirrors stay relative.
+pub(crate) fn relative_target(loc: &str, target: &str) -> anyhow::Result<String> {
+    let from: Vec<_> = Path::new(loc)
+        .parent()
+        .map(|x| x.components().collect())
+        .unwrap_or_default();
+    let to: Vec<_> = Path::new(target).components().collect();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut ret = std::path::PathBuf::new();
+    for _ in common..from.len() {
+        ret.push("..");
+    }
+    for x in to[common..].iter() {
+        ret.push(x);
+    }
+    Ok(ret.to_string_lossy().to_string())
+}
+
+pub(crate) async fn do_link(sha: &str, loc: &str) -> anyhow::Result<()> {
+    do_link_to(&blob_path(sha), loc).await
+}
+
+/// Symlinks `loc` to the blob at `blob`, for callers that know the blob's
+/// path in a store other than the one this process opened.
+pub(crate) async fn do_link_to(blob: &str, loc: &str) -> anyhow::Result<()> {
     match std::path::Path::new(loc).parent() {
         Some(parent_dir) => {
             match tokio::fs::create_dir_all(parent_dir).await {
@@ -185,12 +349,16 @@ async fn do_link(sha: &str, loc: &str) -> anyhow::Result<()> {
             counts += 1;
         }
     });
-    let mut dest = String::new();
-    for _ in 0..(counts - 1) {
-        dest.push_str("../");
-    }
-    dest.push_str("SHA256/");
-    dest.push_str(sha);
+    let dest = if blob.starts_with('/') {
+        relative_target(loc, blob)?
+    } else {
+        let mut dest = String::new();
+        for _ in 0..(counts - 1) {
+            dest.push_str("../");
+        }
+        dest.push_str(blob);
+        dest
+    };
 
     tokio::fs::symlink(/*original = */ &dest, /*link = */ &loc)
         .await
@@ -198,17 +366,68 @@ async fn do_link(sha: &str, loc: &str) -> anyhow::Result<()> {
 }
 
 #[derive(Debug)]
-struct package_pair {
-    sha256: String,
-    filename: String,
+pub(crate) struct package_pair {
+    pub(crate) sha256: String,
+    pub(crate) filename: String,
+    /// Size from the index, 0 when it does not list one.
+    pub(crate) size: u64,
+}
+
+pub(crate) type package_pair_list = Vec<package_pair>;
+
+pub(crate) fn has_packages(instr: &str) -> bool {
+    const TEXT_PACKAGES: &str = "Packages";
+    if instr.len() >= TEXT_PACKAGES.len() {
+        let ret = instr[instr.len() - TEXT_PACKAGES.len()..].eq(TEXT_PACKAGES);
+        return ret;
+    } else {
+        return false;
+    }
+}
+
+pub(crate) async fn read_packages(ctx: &MirrorCtx) -> anyhow::Result<package_pair_list> {
+    read_packages_under(ctx, &ctx.path("")).await
+}
+
+/// Parses the Packages indices staged by the dist stage, none when no sync
+/// is between its dist and link stages.
+pub(crate) async fn read_staged_packages(ctx: &MirrorCtx) -> anyhow::Result<package_pair_list> {
+    let staged = staged_dir(ctx);
+    match tokio::fs::try_exists(&staged).await? {
+        true => read_packages_under(ctx, &format!("{}/", staged)).await,
+        false => Ok(package_pair_list::new()),
+    }
+}
+
+/// The indices the pool and link stages work on: the staged ones if there
+/// are, else the published ones.
+async fn read_pending_packages(ctx: &MirrorCtx) -> anyhow::Result<package_pair_list> {
+    match tokio::fs::try_exists(staged_dir(ctx)).await? {
+        true => read_staged_packages(ctx).await,
+        false => read_packages(ctx).await,
+    }
 }
 
-type package_pair_list = Vec<package_pair>;
+/// Like read_packages, for dists files stored below the directory `root`,
+/// given with a trailing slash, rather than at the top of the mirror.
+async fn read_packages_under(ctx: &MirrorCtx, root: &str) -> anyhow::Result<package_pair_list> {
+    let files_1 = read_list_dist_packages(ctx).await?;
 
-async fn read_packages() -> anyhow::Result<package_pair_list> {
-    let files_1 = read_list_dist_packages().await?;
-    println!("{:?}", files_1);
+    let files: Vec<String> = files_1
+        .split('\n')
+        .filter(|x| has_packages(x))
+        .map(|x| format!("{}{}", root, x))
+        .collect();
+    let files: Vec<&str> = files.iter().map(|x| x.as_str()).collect();
+    read_packages_files(ctx, &files).await
+}
 
+/// Parses the given uncompressed Packages files, e.g. those of an older
+/// snapshot, the same way read_packages does.
+pub(crate) async fn read_packages_files(
+    ctx: &MirrorCtx,
+    files: &[&str],
+) -> anyhow::Result<package_pair_list> {
     fn match_begin(in_str: &str, pattern: &str) -> bool {
         if in_str.len() > pattern.len() {
             return in_str[0..pattern.len()].eq(pattern);
@@ -239,20 +458,10 @@ async fn read_packages() -> anyhow::Result<package_pair_list> {
         }
     }
 
-    fn has_packages(instr: &str) -> bool {
-        const TEXT_PACKAGES: &str = "Packages";
-        if instr.len() >= TEXT_PACKAGES.len() {
-            let ret = instr[instr.len() - TEXT_PACKAGES.len()..].eq(TEXT_PACKAGES);
-            return ret;
-        } else {
-            return false;
-        }
-    }
-
     let mut files_2: String = String::new();
 
-    for x in files_1.split('\n').filter(|x| has_packages(x)) {
-        println!("Got package {}", x);
+    for x in files {
+        debug!(file = x, "reading Packages");
         let package_file_contents = tokio::fs::read_to_string(x)
             .await
             .with_context(|| format!("failed to read the Packages file {}", x))?;
@@ -266,9 +475,12 @@ async fn read_packages() -> anyhow::Result<package_pair_list> {
 
     let mut meta_data = package_pair_list::new();
 
+    let mirror = ctx.mirror().filter(|x| x.has_filters());
+    let mut package: String = String::new();
     let mut filename: String = String::new();
     let mut sha256: String = String::new();
     let mut version: String = String::new();
+    let mut size: u64 = 0;
 
     enum LoopState {
         NeedPackage = 0,
@@ -279,10 +491,18 @@ async fn read_packages() -> anyhow::Result<package_pair_list> {
 
     let mut current_state: LoopState = LoopState::NeedPackage;
     files_2.split('\n').for_each(|x| {
+        if match_begin(x, TEXT_SIZE) {
+            size = x[TEXT_SIZE.len()..].trim().parse().unwrap_or(0);
+        }
         match current_state {
             LoopState::NeedPackage => {
                 if match_begin(x, TEXT_PACKAGE) {
-                    current_state = LoopState::NeedVersion;
+                    package = x[TEXT_PACKAGE.len()..].to_string();
+                    size = 0;
+                    current_state = match &mirror {
+                        Some(o) if !o.wants(&package) => LoopState::NeedPackage,
+                        _ => LoopState::NeedVersion,
+                    };
                 }
             }
             LoopState::NeedVersion => {
@@ -308,6 +528,7 @@ async fn read_packages() -> anyhow::Result<package_pair_list> {
                     meta_data.push(package_pair {
                         sha256: sha256.clone(),
                         filename: filename.clone(),
+                        size,
                     });
                 }
             }
@@ -317,9 +538,74 @@ async fn read_packages() -> anyhow::Result<package_pair_list> {
     return Ok(meta_data);
 }
 
-async fn link_pool_in_dist(file_name: &str) {
+/// Reads every Sources index listed in list.dist_packages.txt and returns
+/// one entry per file referenced from a Checksums-Sha256 field, with the
+/// filename joined onto the stanza's Directory.
+pub(crate) async fn read_sources(ctx: &MirrorCtx) -> anyhow::Result<package_pair_list> {
+    const TEXT_SOURCES: &str = "/Sources";
+    const TEXT_DIRECTORY: &str = "Directory: ";
+    const TEXT_CHECKSUMS_SHA256: &str = "Checksums-Sha256:";
+
+    let files = read_list_dist_packages(ctx).await?;
+    let mut meta_data = package_pair_list::new();
+
+    for x in files.split('\n').filter(|x| x.ends_with(TEXT_SOURCES)) {
+        let contents = match tokio::fs::read_to_string(ctx.path(x)).await {
+            Ok(o) => o,
+            Err(e) => {
+                debug!(file = x, error = %e, "skipping Sources");
+                continue;
+            }
+        };
+
+        for stanza in contents.split("\n\n") {
+            let mut directory: &str = "";
+            let mut in_checksums = false;
+            let mut entries: Vec<(&str, &str, &str)> = Vec::new();
+
+            for line in stanza.lines() {
+                if let Some(rest) = line.strip_prefix(TEXT_DIRECTORY) {
+                    directory = rest.trim();
+                    in_checksums = false;
+                } else if line.starts_with(TEXT_CHECKSUMS_SHA256) {
+                    in_checksums = true;
+                } else if in_checksums && line.starts_with(' ') {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() == 3 {
+                        entries.push((fields[0], fields[1], fields[2]));
+                    }
+                } else {
+                    in_checksums = false;
+                }
+            }
+
+            for (sha256, size, name) in entries {
+                meta_data.push(package_pair {
+                    sha256: sha256.to_string(),
+                    filename: format!("{}/{}", directory, name),
+                    size: size.parse().unwrap_or(0),
+                });
+            }
+        }
+    }
+
+    Ok(meta_data)
+}
+
+async fn link_pool_in_dist(ctx: &MirrorCtx, file_name: &str) {
+    link_pool_in_dist_under(ctx, &ctx.path(""), file_name).await
+}
+
+/// Like link_pool_in_dist, for a dist file stored below the directory `root`,
+/// given with a trailing slash, rather than at the top of the mirror.
+pub(crate) async fn link_pool_in_dist_under(ctx: &MirrorCtx, root: &str, file_name: &str) {
+    // Directory symlinks cannot be published as files, so the convenience
+    // pool links inside dists are only made in symlink mode.
+    if ctx.link_mode() != LinkMode::Symlink {
+        return;
+    }
     let loc: Vec<&str> = file_name.split('/').collect();
-    let mut out = String::new();
+    let mut out = String::from(root);
     for i in 0..loc.len() - 1 {
         if !loc[i].eq(".") {
             out.push_str(loc[i]);
@@ -332,6 +618,7 @@ async fn link_pool_in_dist(file_name: &str) {
 }
 
 async fn link_pool_package(
+    ctx: &MirrorCtx,
     x: std::sync::Arc<package_pair_list>,
     j: std::sync::Arc<std::sync::atomic::AtomicU64>,
 ) {
@@ -342,7 +629,7 @@ async fn link_pool_package(
             let end = std::cmp::min(begin + batch_size as usize, x.len());
             for idx in begin..end {
                 let item = &x[idx];
-                let _res = do_link(item.sha256.as_str(), item.filename.as_str()).await;
+                let _res = link_file(ctx, item.sha256.as_str(), &ctx.path(&item.filename)).await;
             }
         } else {
             break;
@@ -350,45 +637,93 @@ async fn link_pool_package(
     }
 }
 
-pub async fn link_pool() -> anyhow::Result<()> {
-    async fn slave(x: &str) -> anyhow::Result<()> {
-        mkdir(x).await?;
-        link_pool_in_dist(x).await;
+pub async fn link_pool(ctx: &MirrorCtx) -> anyhow::Result<()> {
+    async fn slave(ctx: &MirrorCtx, x: &str) -> anyhow::Result<()> {
+        mkdir(&ctx.path(x)).await?;
+        link_pool_in_dist(ctx, x).await;
         Ok(())
     }
 
     const num_threads: usize = 16;
 
-    let files = read_list_dist_packages().await?;
+    let _store = lock_store(false).await?;
+    let files = read_list_dist_packages(ctx).await?;
 
-    futures::stream::iter(files.split('\n').filter(|x| x.len() > 0).map(|x| slave(x)))
-        .buffer_unordered(num_threads)
-        .collect::<Vec<_>>()
-        .await;
+    futures::stream::iter(
+        files
+            .split('\n')
+            .filter(|x| x.len() > 0)
+            .map(|x| slave(ctx, x)),
+    )
+    .buffer_unordered(num_threads)
+    .collect::<Vec<_>>()
+    .await;
 
-    let meta_data = std::sync::Arc::new(read_packages().await?);
+    let meta_data = std::sync::Arc::new(read_pending_packages(ctx).await?);
     let counter = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
     let mut handles = Vec::new();
 
     for _ in 0..num_threads {
         let data_ref = std::sync::Arc::clone(&meta_data);
         let index_ref = std::sync::Arc::clone(&counter);
-        handles.push(link_pool_package(data_ref, index_ref));
+        handles.push(link_pool_package(ctx, data_ref, index_ref));
     }
 
     futures::future::join_all(handles).await;
 
+    publish_staged(ctx, &files).await
+}
+
+/// Directory the dist stage leaves the dists files of the next publish in,
+/// laid out like the top of the mirror.
+fn staged_dir(ctx: &MirrorCtx) -> String {
+    format!("{}/staged", ctx.paths().tmp)
+}
+
+/// Moves the dists files staged by the dist stage into place, once the
+/// pool files they refer to are fetched and linked. Listed files the new
+/// Release came without are removed rather than left stale next to it,
+/// and the Release files go last.
+async fn publish_staged(ctx: &MirrorCtx, list: &str) -> anyhow::Result<()> {
+    let staged = staged_dir(ctx);
+    if !tokio::fs::try_exists(&staged).await? {
+        return Ok(());
+    }
+    let (releases, indices): (Vec<&str>, Vec<&str>) = list
+        .split('\n')
+        .filter(|x| !x.is_empty())
+        .partition(|x| is_release(x));
+    let signatures: Vec<String> = releases
+        .iter()
+        .filter(|x| x.ends_with("/Release"))
+        .map(|x| format!("{}.gpg", x))
+        .filter(|x| !releases.contains(&x.as_str()))
+        .collect();
+    let files = indices
+        .into_iter()
+        .chain(signatures.iter().map(|x| x.as_str()))
+        .chain(releases);
+    for file in files {
+        let (from, to) = (format!("{}/{}", staged, file), ctx.path(file));
+        match tokio::fs::rename(&from, &to).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let _ = tokio::fs::remove_file(&to).await;
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to move {} into place", file)),
+        }
+    }
+    tokio::fs::remove_dir_all(&staged)
+        .await
+        .with_context(|| format!("failed to remove {}", staged))?;
+    debug!("published the staged dists files");
     Ok(())
 }
 
 async fn move_file_from_waste_to_sha256(sha256: &str) -> anyhow::Result<()> {
-    let mut src = String::from(WASTE);
-    src.push('/');
-    src.push_str(sha256);
-
-    let mut dst = String::from(STORE);
-    dst.push('/');
-    dst.push_str(sha256);
+    let src = blob_path_in(&paths().waste, sha256);
+    let dst = blob_path(sha256);
+    mkdir(dst.as_str()).await?;
 
     tokio::fs::rename(src.as_str(), dst.as_str())
         .await
@@ -415,7 +750,7 @@ async fn move_file_from_waste_to_sha256_list(
                 match move_file_from_waste_to_sha256(item.as_str()).await {
                     Ok(_) => {}
                     Err(e) => {
-                        println!("Failed to find the file {} while cleaning", item);
+                        warn!(sha256 = %item, error = %e, "failed to find the blob while cleaning");
                     }
                 }
             }
@@ -425,8 +760,9 @@ async fn move_file_from_waste_to_sha256_list(
     }
 }
 
-pub async fn clean_sha() -> anyhow::Result<()> {
-    let packages = read_packages().await?;
+pub async fn clean_sha(ctx: &MirrorCtx) -> anyhow::Result<()> {
+    let _store = lock_store(false).await?;
+    let packages = read_packages(ctx).await?;
     let shas: Vec<String> = packages.into_iter().map(|x| x.sha256).collect();
     let shas_ref = std::sync::Arc::new(shas);
 
@@ -447,24 +783,20 @@ pub async fn clean_sha() -> anyhow::Result<()> {
 }
 
 async fn download_sha256_in_pool(
+    ctx: &MirrorCtx,
     sha256: &str,
     filename: &str,
     base_url: &str,
-) -> anyhow::Result<()> {
-    let final_dest: String = {
-        let mut tmp: String = String::from(STORE);
-        tmp.push('/');
-        tmp.push_str(sha256);
-        tmp
-    };
+) -> anyhow::Result<bool> {
+    let final_dest: String = blob_path(sha256);
 
     if tokio::fs::try_exists(final_dest.as_str()).await? {
-        println!("{} already exists", final_dest);
-        return Ok(());
+        debug!(path = %final_dest, "already in the store");
+        return Ok(false);
     }
 
     let dest: String = {
-        let mut tmpstr: String = String::from(TMP);
+        let mut tmpstr: String = String::from(&ctx.paths().tmp);
         tmpstr.push('/');
         tmpstr.push_str(sha256);
         tmpstr
@@ -482,21 +814,23 @@ async fn download_sha256_in_pool(
     for _ in 0..num_tries {
         match download(url.as_str(), dest.as_str()).await {
             Err(_) => {
-                println!("Failed downloading, trying again {}", filename);
+                debug!(file = filename, "download failed, trying again");
             }
             Ok(_) => {
                 let hash = sha256_digest(&dest).await?;
                 if sha256.eq(hash.as_str()) {
+                    mkdir(final_dest.as_str()).await?;
                     match tokio::fs::rename(dest.as_str(), final_dest.as_str()).await {
                         Err(_) => {
-                            println!("Failed to move {} to {}", dest, final_dest);
+                            warn!(from = %dest, to = %final_dest, "failed to move into the store");
                         }
                         Ok(_) => {
-                            return Ok(());
+                            return Ok(true);
                         }
                     };
                 } else {
-                    println!("Hash did not match, try downloading again later...");
+                    warn!(file = filename, "hash did not match, downloading again");
+                    metrics::add(&metrics::HASH_MISMATCHES, &[("mirror", ctx.label())], 1.0);
                     tokio::fs::remove_file(&dest).await?;
                 }
             }
@@ -510,9 +844,11 @@ async fn download_sha256_in_pool(
 }
 
 async fn download_package_list_in_pool(
+    ctx: &MirrorCtx,
     inputs: std::sync::Arc<Vec<package_pair>>,
-    base_2: std::sync::Arc<Vec<&str>>,
+    upstreams: std::sync::Arc<Upstreams>,
     counter: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    progress: std::sync::Arc<Progress>,
 ) -> anyhow::Result<()> {
     const batch_size: u64 = 2 as u64;
     loop {
@@ -522,18 +858,47 @@ async fn download_package_list_in_pool(
             for i in begin..end {
                 let item = &inputs[i];
                 let index = i;
-                for num_tries in 0..base_2.len() {
-                    let url = base_2[(index + num_tries) % base_2.len()];
-                    match download_sha256_in_pool(&item.sha256, &item.filename, url).await {
+                let mut done = false;
+                // The hosts of the file's architecture in turn, then the
+                // others in case it is listed by another architecture too.
+                let preferred = upstreams.for_arch(pool_arch(&item.filename));
+                let mut urls: Vec<&str> = Vec::new();
+                for num_tries in 0..preferred.len() {
+                    urls.push(&preferred[(index + num_tries) % preferred.len()]);
+                }
+                for x in upstreams.all() {
+                    if !urls.contains(&x) {
+                        urls.push(x);
+                    }
+                }
+                // Mirrors serving the indices in use hold their files.
+                urls.sort_by_key(|x| !upstreams.fresh.iter().any(|y| y == x));
+                for url in urls {
+                    progress.begin(url);
+                    let result =
+                        download_sha256_in_pool(ctx, &item.sha256, &item.filename, url).await;
+                    progress.end(url);
+                    match result {
                         Err(e) => {
-                            println!("Failed to download the file {} from mirror {} due to {}, trying with a different mirror", item.filename, url, e);
+                            warn!(file = %item.filename, mirror = url, error = %e, "download failed, trying a different mirror");
                         }
-                        Ok(_) => {
-                            println!("Downloaded the file {}", item.filename);
+                        Ok(downloaded) => {
+                            debug!(file = %item.filename, "downloaded");
+                            progress.done(item.size, downloaded);
+                            if downloaded {
+                                let labels = [("mirror", ctx.label())];
+                                metrics::add(&metrics::DOWNLOADED_FILES, &labels, 1.0);
+                                metrics::add(&metrics::DOWNLOADED_BYTES, &labels, item.size as f64);
+                            }
+                            done = true;
                             break;
                         }
                     }
                 }
+                if !done {
+                    progress.failed();
+                    metrics::add(&metrics::FAILED_FILES, &[("mirror", ctx.label())], 1.0);
+                }
             }
         } else {
             break;
@@ -542,263 +907,468 @@ async fn download_package_list_in_pool(
     Ok(())
 }
 
-pub async fn download_pool() -> anyhow::Result<()> {
-    tokio::fs::create_dir_all(STORE).await?;
-    tokio::fs::create_dir_all(TMP).await?;
+pub async fn download_pool(ctx: &MirrorCtx) -> anyhow::Result<()> {
+    download_package_list(ctx, read_pending_packages(ctx).await?).await
+}
 
-    let base_1 = read_list_url_mirrors().await?;
-    let base_2: Vec<&str> = base_1.split('\n').filter(|x| x.len() > 7).collect();
-    let num_threads = map_num_url_to_num_threads((&base_2).len() as u16);
-    let urls = std::sync::Arc::new(base_2);
-    let meta_data = std::sync::Arc::new(read_packages().await?);
+/// Fetches every listed blob that is not yet in the store, spreading the
+/// work over the mirrors of each file's architecture. Fails if any blob could
+/// not be fetched, so dists referring to it are never published.
+pub(crate) async fn download_package_list(
+    ctx: &MirrorCtx,
+    packages: package_pair_list,
+) -> anyhow::Result<()> {
+    let _store = lock_store(false).await?;
+    tokio::fs::create_dir_all(&paths().store).await?;
+    tokio::fs::create_dir_all(&ctx.paths().tmp).await?;
+
+    let upstreams = Upstreams::read(ctx).await?;
+    let num_threads = map_num_url_to_num_threads(upstreams.all().len() as u16);
+    let bytes_total = packages.iter().map(|x| x.size).sum();
+    let progress = std::sync::Arc::new(Progress::new(
+        packages.len() as u64,
+        bytes_total,
+        &upstreams.all(),
+    ));
+    let reporter = Reporter::start(std::sync::Arc::clone(&progress));
+    let urls = std::sync::Arc::new(upstreams);
+    let meta_data = std::sync::Arc::new(packages);
     let counter = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
 
     let mut handles = Vec::new();
 
     for _ in 0..num_threads {
         handles.push(download_package_list_in_pool(
+            ctx,
             std::sync::Arc::clone(&meta_data),
             std::sync::Arc::clone(&urls),
             std::sync::Arc::clone(&counter),
+            std::sync::Arc::clone(&progress),
         ));
     }
 
     futures::future::join_all(handles).await;
-
+    reporter.finish().await;
+
+    let failed = progress.failures();
+    if failed > 0 {
+        return Err(anyhow::format_err!(
+            "{} of {} pool files could not be downloaded",
+            failed,
+            meta_data.len()
+        ));
+    }
     return Ok(());
 }
 
-pub async fn download_dist() -> anyhow::Result<()> {
-    let base = read_list_url_mirrors().await?;
-    println!("base: {:?}", base);
-    let base_s: Vec<&str> = base.split('\n').collect();
-    println!("base_s: {:?}", base_s);
-    let mut base = String::from(base_s[0]);
-    base.push('/');
-
-    let list_dist_packages = read_list_dist_packages().await?;
-    println!("1");
+/// A suite's Release file as one upstream mirror serves it.
+struct Candidate {
+    url: String,
+    text: String,
+    /// Its Date field, 0 when missing.
+    date: u64,
+}
 
-    async fn link_slave(filename: &str, url: &str) -> anyhow::Result<()> {
-        mkdir(filename).await?;
-        link_pool_in_dist(filename).await;
-        download(url, filename).await
+/// Fetches the Release file of `suite` from `url`, checked against the
+/// keyring when there is one.
+async fn fetch_release(
+    ctx: &MirrorCtx,
+    url: &str,
+    suite: &str,
+    keyring: Option<&str>,
+) -> anyhow::Result<Candidate> {
+    let release = format!("dists/{}/Release", suite);
+    let mut files = vec![release.clone()];
+    if keyring.is_some() {
+        files.push(format!("{}.gpg", release));
     }
-
-    let files: Vec<&str> = list_dist_packages
-        .split('\n')
-        .filter(|x| x.len() > 0)
-        .collect();
-
-    println!("files: {:?}", files);
-
-    let urls: Vec<String> = files
-        .iter()
-        .map(|x| {
-            let mut url: String = base.clone();
-            url.push_str(x);
-            return url;
-        })
-        .collect();
-
-    println!("urls: {:?}", urls);
-
-    let mut handles = Vec::new();
-    for (file, url) in files.iter().zip(urls.iter()) {
-        let tmp = link_slave(file, url);
-        handles.push(tmp);
+    for file in files.iter() {
+        let local = host_file(ctx, url, file);
+        mkdir(&local).await?;
+        // A copy from the last run is not resumed, it may be stale.
+        let _ = tokio::fs::remove_file(&local).await;
+        download(&format!("{}/{}", url, file), &local).await?;
     }
+    let local = host_file(ctx, url, &release);
+    if let Some(keyring) = keyring {
+        verify_signature(&local, &format!("{}.gpg", local), keyring).await?;
+    }
+    let text = tokio::fs::read_to_string(&local)
+        .await
+        .with_context(|| format!("failed to read {}", local))?;
+    let date = ReleaseFile::parse(&text)
+        .get("Date")
+        .and_then(parse_release_date)
+        .unwrap_or(0);
+    Ok(Candidate {
+        url: url.to_string(),
+        text,
+        date,
+    })
+}
 
-    const batch_size: usize = 16;
-
-    let results = futures::stream::iter(handles)
-        .buffer_unordered(batch_size)
-        .collect::<Vec<_>>()
-        .await;
-    // let results = futures::future::join_all(handles).await;
-
-    for (result, filename) in results.iter().zip(files.iter()) {
+/// The Release files of `suite` every mirror in `urls` serves, freshest
+/// first and in the order of `urls` among equals.
+async fn fetch_releases(
+    ctx: &MirrorCtx,
+    urls: &[String],
+    suite: &str,
+    keyring: Option<&str>,
+) -> Vec<Candidate> {
+    let results =
+        futures::future::join_all(urls.iter().map(|x| fetch_release(ctx, x, suite, keyring))).await;
+    let mut ret = Vec::new();
+    for (url, result) in urls.iter().zip(results) {
         match result {
-            Err(e) => {
-                println!("Failed to download dist files {} due to {}", filename, e);
-            }
-            Ok(()) => {}
-        };
+            Ok(o) => ret.push(o),
+            Err(e) => warn!(mirror = %url, suite, error = %e, "no usable Release file"),
+        }
     }
-
-    return Ok(());
+    ret.sort_by_key(|x| std::cmp::Reverse(x.date));
+    ret
 }
 
-pub async fn make_config() -> anyhow::Result<()> {
-    let content: String = tokio::fs::read_to_string("sources.list").await?;
-
-    let lines: Vec<&str> = content
-        .split('\n')
-        .filter(|x| x.len() > TEXT_DEB.len())
-        .collect();
-
-    let mut base_urls = HashSet::new();
-    let mut deb_version = HashSet::new();
-    let mut deb_components = HashSet::new();
-
-    {
-        fn is_deb(inurl: &str) -> bool {
-            return inurl.eq(TEXT_DEB);
+/// Downloads the dists files of a suite matching the Release file of
+/// `chosen` into the directory `stage`, from its mirror or from any other
+/// in `urls` serving the same content. Files no mirror has are skipped as
+/// before, but a file only served with other content means the Release is
+/// not consistent with what is out there, and fails.
+async fn fetch_indices(
+    ctx: &MirrorCtx,
+    files: &[&str],
+    suite: &str,
+    chosen: &Candidate,
+    urls: &[String],
+    stage: &str,
+) -> anyhow::Result<()> {
+    let release = ReleaseFile::parse(&chosen.text);
+    let dir = format!("dists/{}/", suite);
+    let mut order: Vec<&str> = vec![&chosen.url];
+    order.extend(urls.iter().map(|x| x.as_str()).filter(|x| *x != chosen.url));
+
+    async fn fetch_one(
+        ctx: &MirrorCtx,
+        file: &str,
+        path: &str,
+        release: &ReleaseFile,
+        order: &[&str],
+        stage: &str,
+    ) -> anyhow::Result<()> {
+        let mut mismatch = None;
+        for url in order.iter() {
+            let local = host_file(ctx, url, file);
+            mkdir(&local).await?;
+            let _ = tokio::fs::remove_file(&local).await;
+            if let Err(e) = download(&format!("{}/{}", url, file), &local).await {
+                debug!(file, mirror = url, error = %e, "not served");
+                continue;
+            }
+            if release.entry(path).is_some() {
+                let data = tokio::fs::read(&local).await?;
+                if let Err(e) = release.verify(path, &data) {
+                    debug!(file, mirror = url, error = %e, "does not match the Release");
+                    mismatch = Some(e);
+                    continue;
+                }
+            }
+            let staged = format!("{}/{}", stage, file);
+            mkdir(&staged).await?;
+            tokio::fs::rename(&local, &staged)
+                .await
+                .with_context(|| format!("failed to stage {}", file))?;
+            return Ok(());
         }
-
-        fn is_http(inurl: &str) -> bool {
-            if inurl.len() > TEXT_HTTPS.len() {
-                return inurl[0..TEXT_HTTP.len()].eq(TEXT_HTTP)
-                    || inurl[0..TEXT_HTTPS.len()].eq(TEXT_HTTPS);
-            } else {
-                return false;
+        match mismatch {
+            Some(e) => Err(e),
+            None => {
+                warn!(file, "failed to download a dist file");
+                Ok(())
             }
         }
+    }
 
-        enum LineState {
-            LineDeb = 0,
-            LineHttp = 1,
-            LineVersion = 2,
-            LineComponent = 3,
-        }
+    const BATCH_SIZE: usize = 16;
+    let results = futures::stream::iter(files.iter().map(|file| {
+        let path = &file[dir.len()..];
+        fetch_one(ctx, file, path, &release, &order, stage)
+    }))
+    .buffer_unordered(BATCH_SIZE)
+    .collect::<Vec<_>>()
+    .await;
+    results.into_iter().collect()
+}
+
+/// Downloads the dists files. For each suite, every mirror's Release file
+/// is fetched and the freshest one whose indices can be had is used, so a
+/// mirror that is down, stale or halfway through a sync is passed over.
+/// When some architectures come from other hosts, each host's Release is
+/// chosen that way and the suite's Release merged from them.
+///
+/// The files are staged rather than put in place: each candidate's indices
+/// go to a directory of their own, dropped when it is passed over, and the
+/// staged tree is only kept once every suite is consistent. The link stage
+/// publishes it after the pool stage fetched what it refers to.
+pub async fn download_dist(ctx: &MirrorCtx) -> anyhow::Result<()> {
+    let staging = format!("{}/staging", ctx.paths().tmp);
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+    let result = stage_dists(ctx, &staging).await;
+    if result.is_ok() {
+        let staged = staged_dir(ctx);
+        let _ = tokio::fs::remove_dir_all(&staged).await;
+        tokio::fs::create_dir_all(&staging).await?;
+        tokio::fs::rename(&staging, &staged)
+            .await
+            .with_context(|| format!("failed to move {} to {}", staging, staged))?;
+    }
+    let _ = tokio::fs::remove_dir_all(&staging).await;
+    result
+}
 
-        lines.iter().for_each(|x| {
-            let mut state: LineState = LineState::LineDeb;
+/// Fetches the dists files of every suite into the directory `staging`.
+async fn stage_dists(ctx: &MirrorCtx, staging: &str) -> anyhow::Result<()> {
+    let upstreams = Upstreams::read(ctx).await?;
+    if upstreams.default.is_empty() {
+        return Err(anyhow::format_err!(
+            "no upstream mirror in {}",
+            ctx.paths().url_mirrors
+        ));
+    }
+    let keyring = ctx.mirror().and_then(|x| x.keyring.as_deref());
+    let groups = upstreams.groups();
 
-            x.split(' ').for_each(|x| {
-                if x.len() > 0 {
-                    match state {
-                        LineState::LineDeb => {
-                            if is_deb(x) {
-                                state = LineState::LineHttp;
-                            }
-                        }
-                        LineState::LineHttp => {
-                            if is_http(x) {
-                                state = LineState::LineVersion;
-                                base_urls.insert(x);
+    let list_dist_packages = read_list_dist_packages(ctx).await?;
+    let files: Vec<&str> = list_dist_packages
+        .split('\n')
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    // Mirrors are fresh when they serve the chosen Release of every suite.
+    let mut fresh: Option<Vec<String>> = None;
+    for release in files.iter().filter(|x| x.ends_with("/Release")) {
+        let suite = release
+            .strip_prefix("dists/")
+            .and_then(|x| x.strip_suffix("/Release"))
+            .ok_or_else(|| anyhow::format_err!("{} is not a suite's Release", release))?;
+        let dir = format!("dists/{}/", suite);
+        let mut chosen_all: Vec<Candidate> = Vec::new();
+        let mut fresh_suite: Vec<String> = Vec::new();
+        for urls in groups.iter() {
+            let indices: Vec<&str> = files
+                .iter()
+                .filter(|x| x.starts_with(&dir) && !is_release(x))
+                .filter(|x| upstreams.for_arch(index_arch(x)) == *urls)
+                .copied()
+                .collect();
+            let mut chosen = None;
+            let stage = format!("{}/candidate", ctx.paths().tmp);
+            for candidate in fetch_releases(ctx, urls, suite, keyring).await {
+                let _ = tokio::fs::remove_dir_all(&stage).await;
+                match fetch_indices(ctx, &indices, suite, &candidate, urls, &stage).await {
+                    Ok(()) => {
+                        for file in indices.iter() {
+                            let (from, to) = (
+                                format!("{}/{}", stage, file),
+                                format!("{}/{}", staging, file),
+                            );
+                            if tokio::fs::try_exists(&from).await? {
+                                mkdir(&to).await?;
+                                tokio::fs::rename(&from, &to)
+                                    .await
+                                    .with_context(|| format!("failed to stage {}", file))?;
                             }
                         }
-                        LineState::LineVersion => {
-                            state = LineState::LineComponent;
-                            deb_version.insert(x);
-                        }
-                        LineState::LineComponent => {
-                            deb_components.insert(x);
-                        }
+                        chosen = Some(candidate);
+                        break;
+                    }
+                    Err(e) => {
+                        warn!(mirror = %candidate.url, suite, error = %e, "the Release file is not consistent, trying an older one");
                     }
                 }
-            });
+            }
+            let _ = tokio::fs::remove_dir_all(&stage).await;
+            let chosen = chosen.ok_or_else(|| {
+                anyhow::format_err!(
+                    "no mirror of {} serves a consistent Release file of {}",
+                    urls.join(" "),
+                    suite
+                )
+            })?;
+            info!(mirror = %chosen.url, suite, date = chosen.date, "using Release file");
+            for url in urls.iter() {
+                let local = host_file(ctx, url, release);
+                if tokio::fs::read_to_string(&local).await.ok().as_deref() == Some(&chosen.text) {
+                    fresh_suite.push(url.clone());
+                }
+            }
+            chosen_all.push(chosen);
+        }
+        write_release(ctx, staging, release, &chosen_all, keyring.is_some()).await?;
+        fresh = Some(match fresh {
+            Some(o) => o.into_iter().filter(|x| fresh_suite.contains(x)).collect(),
+            None => fresh_suite,
         });
     }
-    {
-        let mut out_string = String::new();
 
-        base_urls.iter().for_each(|x| {
-            out_string.push_str(x);
-            out_string.push('\n');
-        });
-
-        println!("{}", out_string);
-        match fs::write("./list.url_mirrors.txt", out_string) {
-            Ok(_) => {}
-            Err(_) => {
-                println!("Unable to write to list.url_mirrors.txt");
-            }
-        };
+    let mut out_string = String::new();
+    for x in fresh.unwrap_or_default() {
+        out_string.push_str(&format!("{}\n", x));
     }
-    {
-        let mut out_data = String::new();
-
-        for v in &deb_version {
-            for c in &deb_components {
-                println!("v = {}", v);
-                println!("c = {}", c);
-
-                let mut tmp_str: String = String::from("dists/");
-
-                tmp_str.push_str(v);
-                tmp_str.push('/');
-
-                {
-                    let mut release_str = tmp_str.clone();
-                    release_str.push_str("Release\n");
-                    out_data.push_str(release_str.as_str());
-                }
-
-                tmp_str.push_str(c);
-
-                {
-                    let mut tmp_str_source: String = tmp_str.clone();
-
-                    tmp_str_source.push_str("/source/Sources");
-
-                    out_data.push_str(tmp_str_source.as_str());
-                    out_data.push('\n');
-                    {
-                        let mut tmp_str_source_gz: String = tmp_str_source.clone();
-                        tmp_str_source_gz.push_str(".gz");
-
-                        out_data.push_str(tmp_str_source_gz.as_str());
-                        out_data.push('\n');
+    tokio::fs::write(&ctx.paths().fresh_mirrors, out_string)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().fresh_mirrors))
+}
 
-                        let mut tmp_str_source_xz: String = tmp_str_source.clone();
-                        tmp_str_source_xz.push_str(".xz");
+fn is_release(file: &str) -> bool {
+    file.ends_with("/Release") || file.ends_with("/Release.gpg")
+}
 
-                        out_data.push_str(tmp_str_source_xz.as_str());
-                        out_data.push('\n');
-                    }
-                }
-                {
-                    let mut tmp_str_amd64: String = tmp_str.clone();
-                    tmp_str_amd64.push_str("/binary-amd64/Packages");
+/// Writes the Release file of a suite chosen from each host below the
+/// directory `root`. A single one is kept with its signature, several are
+/// merged, and the upstream signatures do not cover the merged file.
+async fn write_release(
+    ctx: &MirrorCtx,
+    root: &str,
+    release: &str,
+    chosen: &[Candidate],
+    signed: bool,
+) -> anyhow::Result<()> {
+    let signature = format!("{}.gpg", release);
+    let (path, local) = (
+        format!("{}/{}", root, release),
+        format!("{}/{}", root, signature),
+    );
+    mkdir(&path).await?;
+    match chosen {
+        [one] => {
+            if signed {
+                tokio::fs::copy(host_file(ctx, &one.url, &signature), &local)
+                    .await
+                    .with_context(|| format!("failed to write {}", local))?;
+            }
+            tokio::fs::write(&path, &one.text).await
+        }
+        _ => {
+            let texts: Vec<String> = chosen.iter().map(|x| x.text.clone()).collect();
+            debug!(
+                file = release,
+                hosts = chosen.len(),
+                "merged the Release files"
+            );
+            tokio::fs::write(&path, merge_releases(&texts)).await
+        }
+    }
+    .with_context(|| format!("failed to write {}", path))
+}
 
-                    out_data.push_str(tmp_str_amd64.as_str());
-                    out_data.push('\n');
-                    {
-                        let mut tmp_str_amd64_gz: String = tmp_str_amd64.clone();
-                        tmp_str_amd64_gz.push_str(".gz");
+/// Writes the url, architecture and dists lists from sources.list, or from
+/// the mirrors file when a mirror is selected. Entries keep their order, so
+/// the first one is what dists are downloaded from, and an `arch=` option
+/// sends the architectures it names to that entry's host.
+pub async fn make_config(ctx: &MirrorCtx) -> anyhow::Result<()> {
+    if let Some(mirror) = ctx.mirror() {
+        return write_mirror_lists(ctx, mirror).await;
+    }
 
-                        out_data.push_str(tmp_str_amd64_gz.as_str());
-                        out_data.push('\n');
+    let content: String = tokio::fs::read_to_string(&ctx.paths().config)
+        .await
+        .with_context(|| format!("failed to read {}", ctx.paths().config))?;
 
-                        let mut tmp_str_amd64_xz: String = tmp_str_amd64.clone();
-                        tmp_str_amd64_xz.push_str(".xz");
+    let entries = parse_sources_list(&content);
 
-                        out_data.push_str(tmp_str_amd64_xz.as_str());
-                        out_data.push('\n');
-                    }
-                }
-                {
-                    let mut tmp_str_i386: String = tmp_str.clone();
-                    tmp_str_i386.push_str("/binary-i386/Packages");
+    let mut base_urls: Vec<&str> = Vec::new();
+    let mut arch_urls: Vec<(&str, &str)> = Vec::new();
+    let mut architectures: Vec<&str> = Vec::new();
+    // Each suite with the components of its own entries, so a pocket such
+    // as noble-backports lists only the indices it has.
+    let mut suites: Vec<(&str, Vec<&str>)> = Vec::new();
 
-                    out_data.push_str(tmp_str_i386.as_str());
-                    out_data.push('\n');
-                    {
-                        let mut tmp_str_i386_gz: String = tmp_str_i386.clone();
-                        tmp_str_i386_gz.push_str(".gz");
+    fn add<'a>(list: &mut Vec<&'a str>, x: &'a str) {
+        if !list.contains(&x) {
+            list.push(x);
+        }
+    }
 
-                        out_data.push_str(tmp_str_i386_gz.as_str());
-                        out_data.push('\n');
+    for x in entries.iter().filter(|x| !x.source && x.is_http()) {
+        let url = x.url.trim_end_matches('/');
+        let arches = x.architectures();
+        if arches.is_empty() {
+            add(&mut base_urls, url);
+            add(&mut architectures, "amd64");
+            add(&mut architectures, "i386");
+        }
+        for arch in arches {
+            add(&mut architectures, arch);
+            if !arch_urls.contains(&(arch, url)) {
+                arch_urls.push((arch, url));
+            }
+        }
+        let components = match suites.iter_mut().find(|(s, _)| *s == x.suite) {
+            Some((_, o)) => o,
+            None => {
+                suites.push((&x.suite, Vec::new()));
+                &mut suites.last_mut().unwrap().1
+            }
+        };
+        for c in x.components.iter() {
+            add(components, c);
+        }
+    }
+    if base_urls.is_empty() {
+        if let Some((_, url)) = arch_urls.first() {
+            base_urls.push(url);
+        }
+    }
 
-                        let mut tmp_str_i386_xz: String = tmp_str_i386.clone();
-                        tmp_str_i386_xz.push_str(".xz");
+    let mut out_string = String::new();
+    for x in base_urls.iter() {
+        out_string.push_str(&format!("{}\n", x));
+    }
+    debug!(mirrors = %out_string.trim_end(), "writing the mirror list");
+    tokio::fs::write(&ctx.paths().url_mirrors, out_string)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().url_mirrors))?;
 
-                        out_data.push_str(tmp_str_i386_xz.as_str());
-                        out_data.push('\n');
-                    }
-                }
+    let mut out_string = String::new();
+    for (arch, url) in arch_urls.iter() {
+        out_string.push_str(&format!("{} {}\n", arch, url));
+    }
+    tokio::fs::write(&ctx.paths().arch_mirrors, out_string)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().arch_mirrors))?;
+
+    let mut out_data = String::new();
+    for (suite, components) in suites.iter() {
+        out_data.push_str(&format!("dists/{}/Release\n", suite));
+        for c in components.iter() {
+            debug!(suite, component = c, "adding dists files");
+            let mut indices = vec![format!("dists/{}/{}/source/Sources", suite, c)];
+            for arch in architectures.iter() {
+                indices.push(format!("dists/{}/{}/binary-{}/Packages", suite, c, arch));
+            }
+            for x in indices {
+                out_data.push_str(&format!("{}\n{}.gz\n{}.xz\n", x, x, x));
             }
         }
-        println!("{}", out_data);
+    }
+    tokio::fs::write(&ctx.paths().dist_packages, out_data)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().dist_packages))
+}
 
-        match fs::write("list.dist_packages.txt", out_data) {
-            Ok(_) => {}
-            Err(_) => {
-                println!("Unable to write file list.dist_packages.txt");
-            }
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_climb_to_the_common_directory() {
+        assert_eq!(
+            relative_target("/srv/m/full/pool/main/a/a.deb", "/srv/m/store/ab/cd/abcd").unwrap(),
+            "../../../../store/ab/cd/abcd"
+        );
+        assert_eq!(
+            relative_target("/srv/m/store/current", "/srv/m/store/snapshots/1").unwrap(),
+            "snapshots/1"
+        );
+        assert_eq!(relative_target("/a/b", "/c/d").unwrap(), "../c/d");
     }
-    Ok(())
 }
@@ -0,0 +1,216 @@
+use anyhow::Context;
+use futures::StreamExt;
+use std::collections::HashSet;
+
+use crate::download_dist::download_package_list;
+use crate::download_dist::read_packages;
+use crate::download_dist::sha256_digest;
+use crate::link_mode::check_pool_file;
+use crate::metrics;
+use crate::mirrors::MirrorCtx;
+use crate::paths::paths;
+use crate::snapshot::list_snapshots;
+use crate::store::is_sha256_name;
+use crate::store::list_blobs;
+use crate::store::lock_store;
+use tracing::info;
+use tracing::warn;
+
+const POOL: &str = "pool";
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct FsckOptions {
+    /// Fetch missing and quarantined blobs again from the mirrors
+    #[arg(long)]
+    pub redownload: bool,
+}
+
+/// Hashes one blob and moves it to the `quarantine` directory when the
+/// content does not match its name. Returns true if the blob is good.
+async fn verify_blob(
+    ctx: &MirrorCtx,
+    quarantine: &str,
+    name: String,
+    path: String,
+) -> anyhow::Result<bool> {
+    let hash = sha256_digest(&path).await?;
+    if hash.eq(&name) {
+        return Ok(true);
+    }
+    let dest = format!("{}/{}", quarantine, name);
+    warn!(blob = %path, hash = %hash, quarantine = %dest, "corrupt blob");
+    metrics::add(&metrics::HASH_MISMATCHES, &[("mirror", ctx.label())], 1.0);
+    tokio::fs::rename(&path, &dest)
+        .await
+        .with_context(|| format!("Failed to move {} to {}", path, dest))?;
+    Ok(false)
+}
+
+/// Walks a pool tree and returns every symlink whose target is missing.
+async fn dangling_links(root: &str) -> anyhow::Result<Vec<String>> {
+    let mut ret = Vec::new();
+    let mut stack = vec![std::path::PathBuf::from(root)];
+    while let Some(dir) = stack.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to list {:?}", dir)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                stack.push(entry.path());
+            } else if file_type.is_symlink() && tokio::fs::metadata(entry.path()).await.is_err() {
+                ret.push(entry.path().to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Re-hashes every blob in the store, quarantines those that do not match
+/// their name and reports referenced blobs that are missing from the store
+/// as well as dangling links in the pool and in every retained generation.
+/// With `--redownload` the missing and quarantined blobs are fetched again
+/// from the mirrors.
+pub async fn fsck_sha(ctx: &MirrorCtx, options: FsckOptions) -> anyhow::Result<()> {
+    let _store = lock_store(false).await?;
+    tokio::fs::create_dir_all(&paths().quarantine).await?;
+
+    let num_threads = std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(4);
+
+    // Files not named by a hash, such as a leftover temporary file, cannot
+    // be checked against their name and are only reported.
+    let (blobs, strays): (Vec<_>, Vec<_>) = list_blobs(&paths().store)
+        .await?
+        .into_iter()
+        .partition(|(name, _)| is_sha256_name(name));
+    for (_, path) in strays.iter() {
+        warn!(file = %path, "unexpected file in the store");
+    }
+    let mut good: HashSet<String> = HashSet::new();
+    let mut num_corrupt: usize = 0;
+    let mut num_failed: usize = 0;
+
+    let results = futures::stream::iter(blobs.into_iter().map(|(name, path)| async move {
+        let ret = verify_blob(ctx, &paths().quarantine, name.clone(), path).await;
+        (name, ret)
+    }))
+    .buffer_unordered(num_threads)
+    .collect::<Vec<_>>()
+    .await;
+
+    for (name, result) in results {
+        match result {
+            Ok(true) => {
+                good.insert(name);
+            }
+            Ok(false) => {
+                num_corrupt += 1;
+            }
+            Err(e) => {
+                warn!(blob = %name, error = %e, "failed to verify");
+                num_failed += 1;
+            }
+        }
+    }
+
+    // Retained generations have pool trees of their own, which clients can
+    // still be served from after a rollback.
+    let mut dangling = dangling_links(&ctx.path(POOL)).await?;
+    for name in list_snapshots(ctx).await? {
+        let pool = format!("{}/{}/{}", &ctx.paths().snapshots, name, POOL);
+        dangling.extend(dangling_links(&pool).await?);
+    }
+    for x in dangling.iter() {
+        warn!(link = %x, "dangling link");
+    }
+
+    // Only Packages blobs are fetched by download_pool, so Sources entries
+    // are not expected to be present.
+    let packages = read_packages(ctx).await?;
+    let missing: HashSet<&String> = packages
+        .iter()
+        .map(|x| &x.sha256)
+        .filter(|x| !good.contains(*x))
+        .collect();
+
+    let mut num_inconsistent: usize = 0;
+    for x in packages.iter().filter(|x| good.contains(&x.sha256)) {
+        if !check_pool_file(ctx, &x.sha256, &ctx.path(&x.filename)).await? {
+            warn!(file = %x.filename, "pool file does not match the store");
+            num_inconsistent += 1;
+        }
+    }
+
+    info!(
+        good = good.len(),
+        corrupt = num_corrupt,
+        unreadable = num_failed,
+        missing = missing.len(),
+        dangling = dangling.len(),
+        inconsistent = num_inconsistent,
+        "verified the store"
+    );
+
+    if options.redownload {
+        let missing: HashSet<String> = missing.into_iter().cloned().collect();
+        let queue: Vec<_> = packages
+            .into_iter()
+            .filter(|x| missing.contains(&x.sha256))
+            .collect();
+        info!(files = queue.len(), "queueing for download");
+        return download_package_list(ctx, queue).await;
+    }
+
+    if num_corrupt + num_failed + missing.len() + dangling.len() + num_inconsistent > 0 {
+        return Err(anyhow::format_err!("The store failed verification"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+
+    #[tokio::test]
+    async fn only_blobs_not_matching_their_name_are_quarantined() {
+        let dir = std::env::temp_dir().join(format!("mysync-fsck-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let quarantine = dir.join("QUARANTINE");
+        std::fs::create_dir_all(&quarantine).unwrap();
+        let quarantine = quarantine.to_string_lossy().to_string();
+        let ctx = MirrorCtx::root();
+
+        let data = b"Package: hello\n";
+        let good = hex::encode(sha2::Sha256::digest(data));
+        let path = dir.join(&good).to_string_lossy().to_string();
+        std::fs::write(&path, data).unwrap();
+        assert!(verify_blob(&ctx, &quarantine, good.clone(), path.clone())
+            .await
+            .unwrap());
+        assert!(std::path::Path::new(&path).exists());
+
+        let bad = "0".repeat(64);
+        let path = dir.join(&bad).to_string_lossy().to_string();
+        std::fs::write(&path, data).unwrap();
+        assert!(!verify_blob(&ctx, &quarantine, bad.clone(), path.clone())
+            .await
+            .unwrap());
+        assert!(!std::path::Path::new(&path).exists());
+        assert_eq!(
+            std::fs::read(format!("{}/{}", quarantine, bad)).unwrap(),
+            data
+        );
+
+        let missing = dir.join("1".repeat(64)).to_string_lossy().to_string();
+        assert!(verify_blob(&ctx, &quarantine, "1".repeat(64), missing)
+            .await
+            .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use anyhow::Context;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::download_dist::read_packages;
+use crate::download_dist::read_sources;
+use crate::download_dist::read_staged_packages;
+use crate::mirrors::all_trees;
+use crate::mirrors::MirrorCtx;
+use crate::paths::paths;
+use crate::snapshot::list_snapshots;
+use crate::snapshot::snapshot_packages;
+use crate::store::is_sha256_name;
+use crate::store::list_blobs;
+use crate::store::lock_store;
+use crate::store::record_store_usage;
+use tracing::info;
+use tracing::warn;
+
+const DEFAULT_GRACE_HOURS: u64 = 24 * 7;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct GcOptions {
+    /// Only list unreferenced blobs and their sizes, delete nothing
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Hours a blob must stay unreferenced before it is deleted
+    #[arg(long, default_value_t = DEFAULT_GRACE_HOURS, value_parser = parse_grace_hours)]
+    pub grace_hours: u64,
+}
+
+/// Parses the grace period, refusing one that does not fit in seconds.
+fn parse_grace_hours(text: &str) -> Result<u64, String> {
+    let hours: u64 = text
+        .parse()
+        .map_err(|_| format!("{} is not a number of hours", text))?;
+    if hours.checked_mul(3600).is_none() {
+        return Err(format!("{} hours is too long a grace period", text));
+    }
+    Ok(hours)
+}
+
+pub(crate) fn now_seconds() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// Every blob referenced from the Packages and Sources indices listed in
+/// list.dist_packages.txt and from every retained snapshot, of each mirror
+/// subtree when there is a mirrors file, since they all share the store.
+pub(crate) async fn referenced_blobs() -> anyhow::Result<HashSet<String>> {
+    let mut ret: HashSet<String> = HashSet::new();
+    for ctx in all_trees()? {
+        // A mirror that was never configured has nothing in the store yet.
+        if let Some(name) = ctx.name() {
+            if !tokio::fs::try_exists(&ctx.paths().dist_packages).await? {
+                continue;
+            }
+            let referenced = referenced_blobs_in(&ctx)
+                .await
+                .with_context(|| format!("failed to read the indices of mirror {}", name))?;
+            ret.extend(referenced);
+        } else {
+            ret.extend(referenced_blobs_in(&ctx).await?);
+        }
+    }
+    Ok(ret)
+}
+
+async fn referenced_blobs_in(ctx: &MirrorCtx) -> anyhow::Result<HashSet<String>> {
+    let mut ret: HashSet<String> = HashSet::new();
+    for x in read_packages(ctx).await? {
+        ret.insert(x.sha256);
+    }
+    // Fetched by a sync that has not published its indices yet.
+    for x in read_staged_packages(ctx).await? {
+        ret.insert(x.sha256);
+    }
+    for name in list_snapshots(ctx).await? {
+        for x in snapshot_packages(ctx, &name).await? {
+            ret.insert(x.sha256);
+        }
+    }
+    for x in read_sources(ctx).await? {
+        ret.insert(x.sha256);
+    }
+    Ok(ret)
+}
+
+/// Parses the gc state, `<sha256> <first seen unreferenced>` per line.
+/// Lines that do not parse are dropped, their blobs start a new grace
+/// period.
+fn parse_gc_state(content: &str) -> HashMap<String, u64> {
+    let mut ret = HashMap::new();
+    for line in content.lines() {
+        let mut fields = line.split(' ');
+        if let (Some(sha256), Some(since)) = (fields.next(), fields.next()) {
+            if let Ok(since) = since.parse::<u64>() {
+                ret.insert(sha256.to_string(), since);
+            }
+        }
+    }
+    ret
+}
+
+fn gc_state_text(state: &HashMap<String, u64>) -> String {
+    let mut out = String::new();
+    for (sha256, since) in state {
+        out.push_str(sha256);
+        out.push(' ');
+        out.push_str(&since.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// Whether a blob first seen unreferenced at `since` has outstayed the
+/// grace period at `now`.
+fn grace_expired(since: u64, now: u64, grace_hours: u64) -> bool {
+    now.saturating_sub(since) >= grace_hours.saturating_mul(3600)
+}
+
+async fn read_gc_state() -> anyhow::Result<HashMap<String, u64>> {
+    match tokio::fs::read_to_string(&paths().gc_state).await {
+        Ok(o) => Ok(parse_gc_state(&o)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", &paths().gc_state)),
+    }
+}
+
+async fn write_gc_state(state: &HashMap<String, u64>) -> anyhow::Result<()> {
+    let out = gc_state_text(state);
+    let tmp = format!("{}.tmp", &paths().gc_state);
+    tokio::fs::write(&tmp, out)
+        .await
+        .with_context(|| format!("failed to write {}", tmp))?;
+    tokio::fs::rename(&tmp, &paths().gc_state)
+        .await
+        .with_context(|| format!("failed to move {} to {}", tmp, &paths().gc_state))
+}
+
+/// Deletes blobs from the store that no configured suite references any
+/// more, but only after they have stayed unreferenced for the grace period.
+/// Unlike clean_sha this never moves live blobs out of the store, so a crash
+/// or a concurrent client download cannot lose a file that is still in use.
+/// The store lock is held exclusively throughout, so no sync adds or links
+/// blobs and no other gc updates the shared state file meanwhile.
+pub async fn gc_sha(options: &GcOptions) -> anyhow::Result<()> {
+    let _store = lock_store(true).await?;
+    let referenced = referenced_blobs().await?;
+    let mut state = read_gc_state().await?;
+    let now = now_seconds();
+
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut num_unreferenced: u64 = 0;
+    let mut bytes_unreferenced: u64 = 0;
+    let mut num_deleted: u64 = 0;
+    let mut bytes_deleted: u64 = 0;
+
+    for (name, path) in list_blobs(&paths().store).await? {
+        if referenced.contains(&name) || !is_sha256_name(&name) {
+            continue;
+        }
+        let metadata = tokio::fs::metadata(&path).await?;
+
+        let since = *state.entry(name.clone()).or_insert(now);
+        let expired = grace_expired(since, now, options.grace_hours);
+        seen.insert(name.clone());
+        num_unreferenced += 1;
+        bytes_unreferenced += metadata.len();
+
+        if options.dry_run {
+            println!(
+                "unreferenced {} {} bytes, {}",
+                name,
+                metadata.len(),
+                if expired {
+                    "would be deleted"
+                } else {
+                    "within grace period"
+                }
+            );
+        } else if expired {
+            match tokio::fs::remove_file(&path).await {
+                Ok(_) => {
+                    num_deleted += 1;
+                    bytes_deleted += metadata.len();
+                    state.remove(&name);
+                    seen.remove(&name);
+                }
+                Err(e) => {
+                    warn!(blob = %path, error = %e, "failed to delete");
+                }
+            }
+        }
+    }
+
+    info!(
+        unreferenced = num_unreferenced,
+        unreferenced_bytes = bytes_unreferenced,
+        deleted = num_deleted,
+        deleted_bytes = bytes_deleted,
+        "collected unreferenced blobs"
+    );
+
+    if !options.dry_run {
+        // Drop blobs that became referenced again or vanished from the store.
+        state.retain(|k, _| seen.contains(k));
+        write_gc_state(&state).await?;
+    }
+    record_store_usage().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const B: &str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    #[test]
+    fn gc_state_round_trips() {
+        let state = HashMap::from([(A.to_string(), 1_792_231_200), (B.to_string(), 0)]);
+        assert_eq!(parse_gc_state(&gc_state_text(&state)), state);
+        assert!(gc_state_text(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn broken_gc_state_lines_are_dropped() {
+        let text = format!("{} 100\n{}\n{} soon\n\n{} 200 extra\n", A, B, B, B);
+        let state = parse_gc_state(&text);
+        assert_eq!(
+            state,
+            HashMap::from([(A.to_string(), 100), (B.to_string(), 200)])
+        );
+    }
+
+    #[test]
+    fn blobs_expire_after_the_grace_period() {
+        let now = 1_792_231_200;
+        assert!(!grace_expired(now, now, 1));
+        assert!(!grace_expired(now - 3599, now, 1));
+        assert!(grace_expired(now - 3600, now, 1));
+        assert!(grace_expired(now, now, 0));
+        // A clock set back does not expire anything.
+        assert!(!grace_expired(now + 10, now, 1));
+        assert!(!grace_expired(0, now, u64::MAX));
+    }
+
+    #[test]
+    fn grace_periods_fit_in_seconds() {
+        assert_eq!(parse_grace_hours("168"), Ok(168));
+        assert_eq!(parse_grace_hours("0"), Ok(0));
+        assert!(parse_grace_hours("-1").is_err());
+        assert!(parse_grace_hours("week").is_err());
+        assert!(parse_grace_hours(&u64::MAX.to_string()).is_err());
+    }
+}
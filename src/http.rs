@@ -0,0 +1,387 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tracing::debug;
+use tracing::warn;
+
+use crate::snapshot::civil_from_days;
+
+/// Longest request line or header accepted.
+const MAX_LINE: usize = 8192;
+
+/// Most headers accepted in one request.
+const MAX_HEADERS: usize = 100;
+
+/// Most request body bytes read, the endpoints take no real payload.
+const MAX_BODY: u64 = 64 * 1024;
+
+/// How long an idle keep-alive connection is kept open.
+const IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long the server pauses after failing to accept a connection.
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// An HTTP/1.x request, the path percent-decoded and without the query.
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) version: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) peer: SocketAddr,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Body {
+    Bytes(Vec<u8>),
+    /// `length` bytes of an open file starting at `offset`.
+    File {
+        file: tokio::fs::File,
+        offset: u64,
+        length: u64,
+    },
+}
+
+impl Body {
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(o) => o.len() as u64,
+            Body::File { length, .. } => *length,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Body,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status,
+            headers: vec![(String::from("Content-Type"), content_type.to_string())],
+            body: Body::Bytes(body.into()),
+        }
+    }
+
+    pub(crate) fn header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub(crate) fn text(status: u16, body: impl Into<String>) -> Response {
+        let mut body = body.into();
+        if !body.ends_with('\n') {
+            body.push('\n');
+        }
+        Response::new(status, "text/plain; charset=utf-8", body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        _ => "",
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats unix seconds as an HTTP date, `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn http_date(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = seconds % 86400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60
+    )
+}
+
+/// Formats unix seconds the way access logs do, `06/Nov/1994:08:49:37 +0000`.
+pub(crate) fn log_date(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let rem = seconds % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60
+    )
+}
+
+/// Formats unix seconds the way `date -u` does, `Sun Nov  6 08:49:37 UTC 1994`.
+pub(crate) fn ctime_date(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = seconds % 86400;
+    format!(
+        "{} {} {:>2} {:02}:{:02}:{:02} UTC {}",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        MONTHS[month as usize - 1],
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60,
+        year
+    )
+}
+
+/// Decodes `%XX` escapes, None when they do not make valid UTF-8.
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = &bytes[i + 1..i + 3];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            ret.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            ret.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(ret).ok()
+}
+
+async fn read_line(reader: &mut BufReader<TcpStream>) -> anyhow::Result<Option<String>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(anyhow::format_err!("request line too long"));
+    }
+    let line = String::from_utf8(line)?;
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Reads the next request of a connection, None once the client closed it.
+async fn read_request(
+    reader: &mut BufReader<TcpStream>,
+    peer: SocketAddr,
+) -> anyhow::Result<Option<Request>> {
+    let line = match read_line(reader).await? {
+        Some(o) => o,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t, v),
+        _ => return Err(anyhow::format_err!("invalid request line {:?}", line)),
+    };
+    let path = target.split_once('?').map(|x| x.0).unwrap_or(target);
+    let path = percent_decode(path)
+        .ok_or_else(|| anyhow::format_err!("invalid request path {:?}", target))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("connection closed in the headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(anyhow::format_err!("too many headers"));
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_string(), v.trim().to_string()));
+        }
+    }
+    let mut request = Request {
+        method: method.to_string(),
+        path,
+        version: version.to_string(),
+        headers,
+        peer,
+    };
+    if version == "HTTP/1.0" && request.header("Connection").is_none() {
+        request
+            .headers
+            .push((String::from("Connection"), String::from("close")));
+    }
+
+    // Bodies are not used, but have to be consumed to keep the connection.
+    let length: u64 = match request.header("Content-Length") {
+        Some(o) => o
+            .parse()
+            .map_err(|_| anyhow::format_err!("invalid Content-Length {:?}", o))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(anyhow::format_err!("request body too large"));
+    }
+    tokio::io::copy(&mut (&mut *reader).take(length), &mut tokio::io::sink()).await?;
+    Ok(Some(request))
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    response: Response,
+    head_only: bool,
+    close: bool,
+) -> anyhow::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (k, v) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", k, v));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    if close {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    if !head_only {
+        match response.body {
+            Body::Bytes(o) => stream.write_all(&o).await?,
+            Body::File {
+                mut file,
+                offset,
+                length,
+            } => {
+                file.seek(std::io::SeekFrom::Start(offset)).await?;
+                let sent = tokio::io::copy(&mut file.take(length), stream).await?;
+                if sent != length {
+                    return Err(anyhow::format_err!("file shrank while being sent"));
+                }
+            }
+        }
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn handle_connection<H, F>(stream: TcpStream, peer: SocketAddr, handler: Arc<H>)
+where
+    H: Fn(Request) -> F,
+    F: std::future::Future<Output = Response>,
+{
+    let mut reader = BufReader::new(stream);
+    loop {
+        let request =
+            match tokio::time::timeout(IDLE_TIMEOUT, read_request(&mut reader, peer)).await {
+                Ok(Ok(Some(o))) => o,
+                Ok(Ok(None)) | Err(_) => return,
+                Ok(Err(e)) => {
+                    debug!(peer = %peer, error = %e, "bad request");
+                    let response = Response::text(400, "bad request");
+                    let _ = write_response(reader.get_mut(), response, false, true).await;
+                    return;
+                }
+            };
+        let close = request
+            .header("Connection")
+            .is_some_and(|x| x.eq_ignore_ascii_case("close"));
+        let head_only = request.method == "HEAD";
+        let response = handler(request).await;
+        if let Err(e) = write_response(reader.get_mut(), response, head_only, close).await {
+            debug!(peer = %peer, error = %e, "failed to send response");
+            return;
+        }
+        if close {
+            return;
+        }
+    }
+}
+
+/// Answers requests on `listener` with `handler` until the task is dropped,
+/// one task per connection.
+pub(crate) async fn serve<H, F>(listener: TcpListener, handler: H) -> anyhow::Result<()>
+where
+    H: Fn(Request) -> F + Send + Sync + 'static,
+    F: std::future::Future<Output = Response> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        // Running out of descriptors or a connection reset before it was
+        // accepted passes, the server goes on after a pause.
+        let (stream, peer) = match listener.accept().await {
+            Ok(o) => o,
+            Err(e) => {
+                warn!("failed to accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let handler = Arc::clone(&handler);
+        tokio::spawn(handle_connection(stream, peer, handler));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(
+            percent_decode("/pool/a%2Bb_1.0.deb").unwrap(),
+            "/pool/a+b_1.0.deb"
+        );
+        assert_eq!(percent_decode("/a%20b%2fc").unwrap(), "/a b/c");
+        assert_eq!(percent_decode("/caf%C3%A9").unwrap(), "/café");
+        assert_eq!(percent_decode("/plain").unwrap(), "/plain");
+        assert_eq!(percent_decode("").unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_bad_escapes() {
+        assert_eq!(percent_decode("/a%zzb"), None);
+        assert_eq!(percent_decode("/a%+1b"), None);
+        assert_eq!(percent_decode("/a%FFb"), None);
+    }
+}
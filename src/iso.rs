@@ -0,0 +1,544 @@
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::snapshot::civil_from_days;
+
+const SECTOR: u64 = 2048;
+
+/// Sectors before the volume descriptors, left empty.
+const SYSTEM_AREA: u64 = 16;
+
+/// Longest name a file record has room for beside its identifier and its
+/// Rock Ridge entries, within the 254 bytes of an even record length.
+const MAX_NAME: usize = 147;
+
+/// Where the data of a file on the image comes from.
+#[derive(Debug, Clone)]
+pub(crate) enum Content {
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+/// A file of an image, at a slash separated path below its root.
+#[derive(Debug, Clone)]
+pub(crate) struct IsoFile {
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    pub(crate) content: Content,
+}
+
+#[derive(Debug, Default)]
+struct Dir {
+    /// Name as shown through Rock Ridge, empty for the root.
+    name: String,
+    /// ISO 9660 identifier of the directory.
+    id: String,
+    parent: usize,
+    dirs: Vec<usize>,
+    files: Vec<(String, usize)>,
+    /// Path table number, counted from 1 in breadth first order.
+    number: u16,
+    extent: u32,
+    size: u32,
+}
+
+fn both16(value: u16) -> [u8; 4] {
+    let mut ret = [0u8; 4];
+    ret[..2].copy_from_slice(&value.to_le_bytes());
+    ret[2..].copy_from_slice(&value.to_be_bytes());
+    ret
+}
+
+fn both32(value: u32) -> [u8; 8] {
+    let mut ret = [0u8; 8];
+    ret[..4].copy_from_slice(&value.to_le_bytes());
+    ret[4..].copy_from_slice(&value.to_be_bytes());
+    ret
+}
+
+fn sectors(size: u64) -> u64 {
+    size.div_ceil(SECTOR)
+}
+
+/// Recording date of a directory record.
+fn record_date(seconds: u64) -> [u8; 7] {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let rem = seconds % 86400;
+    [
+        (year - 1900) as u8,
+        month as u8,
+        day as u8,
+        (rem / 3600) as u8,
+        ((rem / 60) % 60) as u8,
+        (rem % 60) as u8,
+        0,
+    ]
+}
+
+/// Date of the volume descriptor, `YYYYMMDDHHMMSScc` and a zone offset.
+fn volume_date(seconds: u64) -> [u8; 17] {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let rem = seconds % 86400;
+    let text = format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}00",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60
+    );
+    let mut ret = [0u8; 17];
+    ret[..16].copy_from_slice(text.as_bytes());
+    ret
+}
+
+/// A name reduced to ISO 9660 d-characters, unique among `taken`.
+fn iso_id(name: &str, dir: bool, taken: &mut HashSet<String>) -> String {
+    let clean = |x: &str| -> String {
+        x.chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9' | '_') => c,
+                _ => '_',
+            })
+            .collect()
+    };
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((b, e)) if !dir && !b.is_empty() => (clean(b), clean(e)),
+        _ => (clean(name), String::new()),
+    };
+    let ext: String = ext.chars().take(8).collect();
+    let room = if dir { 31 } else { 30 - ext.len() - 1 };
+    for n in 0.. {
+        let suffix = if n == 0 {
+            String::new()
+        } else {
+            format!("_{}", n)
+        };
+        let base: String = base.chars().take(room - suffix.len()).collect();
+        let id = if dir {
+            format!("{}{}", base, suffix)
+        } else {
+            format!("{}{}.{};1", base, suffix, ext)
+        };
+        if taken.insert(id.clone()) {
+            return id;
+        }
+    }
+    unreachable!()
+}
+
+/// Rock Ridge POSIX attributes, read-only for everyone.
+fn rock_px(dir: bool) -> Vec<u8> {
+    let mut ret = vec![b'P', b'X', 36, 1];
+    let mode: u32 = if dir { 0o40555 } else { 0o100444 };
+    ret.extend(both32(mode));
+    ret.extend(both32(if dir { 2 } else { 1 }));
+    ret.extend(both32(0));
+    ret.extend(both32(0));
+    ret
+}
+
+/// Rock Ridge alternate name, the real name of the file.
+fn rock_nm(name: &str) -> Vec<u8> {
+    let mut ret = vec![b'N', b'M', (5 + name.len()) as u8, 1, 0];
+    ret.extend(name.as_bytes());
+    ret
+}
+
+/// The entries of the root's own record announcing Rock Ridge.
+fn rock_root() -> Vec<u8> {
+    const ID: &[u8] = b"RRIP_1991A";
+    const DESCRIPTION: &[u8] = b"ROCK RIDGE POSIX EXTENSIONS";
+    const SOURCE: &[u8] = b"DEB_MIRROR";
+    let mut ret = vec![b'S', b'P', 7, 1, 0xbe, 0xef, 0];
+    ret.extend([
+        b'E',
+        b'R',
+        (8 + ID.len() + DESCRIPTION.len() + SOURCE.len()) as u8,
+        1,
+        ID.len() as u8,
+        DESCRIPTION.len() as u8,
+        SOURCE.len() as u8,
+        1,
+    ]);
+    ret.extend(ID);
+    ret.extend(DESCRIPTION);
+    ret.extend(SOURCE);
+    ret.extend(rock_px(true));
+    ret
+}
+
+fn record(id: &[u8], extent: u32, size: u32, dir: bool, date: [u8; 7], extra: &[u8]) -> Vec<u8> {
+    let pad = if id.len().is_multiple_of(2) { 1 } else { 0 };
+    let mut ret = vec![0u8; 33];
+    ret[2..10].copy_from_slice(&both32(extent));
+    ret[10..18].copy_from_slice(&both32(size));
+    ret[18..25].copy_from_slice(&date);
+    ret[25] = if dir { 2 } else { 0 };
+    ret[28..32].copy_from_slice(&both16(1));
+    ret[32] = id.len() as u8;
+    ret.extend(id);
+    ret.extend(std::iter::repeat_n(0, pad));
+    ret.extend(extra);
+    if ret.len() % 2 == 1 {
+        ret.push(0);
+    }
+    ret[0] = ret.len() as u8;
+    ret
+}
+
+/// Lays out the records of a directory in sectors; a record never crosses
+/// a sector boundary.
+fn pack_records(records: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for x in records {
+        let used = ret.len() as u64 % SECTOR;
+        if used + x.len() as u64 > SECTOR {
+            ret.resize(ret.len() + (SECTOR - used) as usize, 0);
+        }
+        ret.extend(x);
+    }
+    ret.resize((sectors(ret.len() as u64) * SECTOR) as usize, 0);
+    ret
+}
+
+fn text_field(field: &mut [u8], text: &str) {
+    field.fill(b' ');
+    let n = text.len().min(field.len());
+    field[..n].copy_from_slice(&text.as_bytes()[..n]);
+}
+
+struct Layout {
+    dirs: Vec<Dir>,
+    /// Breadth first order of the directories.
+    order: Vec<usize>,
+    /// First sector of each file.
+    extents: Vec<u32>,
+    path_table: (Vec<u8>, Vec<u8>),
+    total: u64,
+}
+
+fn build(files: &[IsoFile]) -> anyhow::Result<Layout> {
+    let mut dirs = vec![Dir::default()];
+    let mut taken: Vec<HashSet<String>> = vec![HashSet::new()];
+    for (index, file) in files.iter().enumerate() {
+        let parts: Vec<&str> = file.path.split('/').filter(|x| !x.is_empty()).collect();
+        let (name, parents) = parts
+            .split_last()
+            .ok_or_else(|| anyhow::format_err!("empty path on the image"))?;
+        let mut current = 0;
+        for part in parents {
+            current = match dirs[current].dirs.iter().find(|x| dirs[**x].name == *part) {
+                Some(o) => *o,
+                None => {
+                    let id = iso_id(part, true, &mut taken[current]);
+                    dirs.push(Dir {
+                        name: part.to_string(),
+                        id,
+                        parent: current,
+                        ..Dir::default()
+                    });
+                    taken.push(HashSet::new());
+                    let new = dirs.len() - 1;
+                    dirs[current].dirs.push(new);
+                    new
+                }
+            };
+        }
+        if file.size > u32::MAX as u64 {
+            return Err(anyhow::format_err!(
+                "{} is too large for the image",
+                file.path
+            ));
+        }
+        if name.len() > MAX_NAME {
+            return Err(anyhow::format_err!(
+                "{} has too long a name for the image",
+                file.path
+            ));
+        }
+        let id = iso_id(name, false, &mut taken[current]);
+        dirs[current].files.push((id, index));
+    }
+    for x in dirs.iter().map(|x| &x.name) {
+        if x.len() > MAX_NAME {
+            return Err(anyhow::format_err!("directory {} has too long a name", x));
+        }
+    }
+
+    // Records are sorted by identifier, so is the path table.
+    for i in 0..dirs.len() {
+        let mut children = std::mem::take(&mut dirs[i].dirs);
+        children.sort_by(|a, b| dirs[*a].id.cmp(&dirs[*b].id));
+        dirs[i].dirs = children;
+        dirs[i].files.sort();
+    }
+    let mut order = vec![0];
+    let mut i = 0;
+    while i < order.len() {
+        order.extend(dirs[order[i]].dirs.iter().copied());
+        i += 1;
+    }
+    // Path table entries name their parent by a 16 bit number.
+    for (n, x) in order.iter().enumerate() {
+        dirs[*x].number = u16::try_from(n + 1)
+            .map_err(|_| anyhow::format_err!("the image has more than {} directories", u16::MAX))?;
+    }
+
+    // Sizes come first, the records have the same length whatever extent
+    // they end up pointing at.
+    for x in 0..dirs.len() {
+        let size = dir_records(&dirs, x, files, &[], 0).len();
+        dirs[x].size = size as u32;
+    }
+    let table_size: u64 = order
+        .iter()
+        .map(|x| {
+            let n = dirs[*x].id.len().max(1) as u64;
+            8 + n + n % 2
+        })
+        .sum();
+    // The volume descriptors, then both path tables.
+    let mut next = SYSTEM_AREA + 2 + 2 * sectors(table_size);
+    for x in order.iter() {
+        dirs[*x].extent = next as u32;
+        next += sectors(dirs[*x].size as u64);
+    }
+    let mut extents = Vec::with_capacity(files.len());
+    for x in files.iter() {
+        extents.push(next as u32);
+        next += sectors(x.size);
+    }
+    if next > u32::MAX as u64 {
+        return Err(anyhow::format_err!("the image is too large for ISO 9660"));
+    }
+
+    let mut l = Vec::new();
+    let mut m = Vec::new();
+    for x in order.iter() {
+        let dir = &dirs[*x];
+        let id: &[u8] = if *x == 0 { &[0] } else { dir.id.as_bytes() };
+        let parent = dirs[dir.parent].number;
+        for (table, little) in [(&mut l, true), (&mut m, false)] {
+            table.push(id.len() as u8);
+            table.push(0);
+            if little {
+                table.extend(dir.extent.to_le_bytes());
+                table.extend(parent.to_le_bytes());
+            } else {
+                table.extend(dir.extent.to_be_bytes());
+                table.extend(parent.to_be_bytes());
+            }
+            table.extend(id);
+            if id.len() % 2 == 1 {
+                table.push(0);
+            }
+        }
+    }
+    Ok(Layout {
+        dirs,
+        order,
+        extents,
+        path_table: (l, m),
+        total: next,
+    })
+}
+
+/// The sectors of directory `x`.
+fn dir_records(dirs: &[Dir], x: usize, files: &[IsoFile], extents: &[u32], date: u64) -> Vec<u8> {
+    let date = record_date(date);
+    let dir = &dirs[x];
+    let parent = &dirs[dir.parent];
+    let own = if x == 0 { rock_root() } else { rock_px(true) };
+    let mut records = vec![
+        record(&[0], dir.extent, dir.size, true, date, &own),
+        record(&[1], parent.extent, parent.size, true, date, &rock_px(true)),
+    ];
+    let mut children: Vec<(&str, Vec<u8>)> = Vec::new();
+    for y in dir.dirs.iter() {
+        let child = &dirs[*y];
+        let mut extra = rock_px(true);
+        extra.extend(rock_nm(&child.name));
+        children.push((
+            &child.id,
+            record(
+                child.id.as_bytes(),
+                child.extent,
+                child.size,
+                true,
+                date,
+                &extra,
+            ),
+        ));
+    }
+    for (id, index) in dir.files.iter() {
+        let file = &files[*index];
+        let name = file.path.rsplit('/').next().unwrap_or(&file.path);
+        let mut extra = rock_px(false);
+        extra.extend(rock_nm(name));
+        let extent = extents.get(*index).copied().unwrap_or(0);
+        children.push((
+            id,
+            record(id.as_bytes(), extent, file.size as u32, false, date, &extra),
+        ));
+    }
+    children.sort_by(|a, b| a.0.cmp(b.0));
+    records.extend(children.into_iter().map(|x| x.1));
+    pack_records(records)
+}
+
+fn primary_descriptor(layout: &Layout, volume_id: &str, date: u64) -> Vec<u8> {
+    let mut ret = vec![0u8; SECTOR as usize];
+    ret[0] = 1;
+    ret[1..6].copy_from_slice(b"CD001");
+    ret[6] = 1;
+    text_field(&mut ret[8..40], "LINUX");
+    text_field(&mut ret[40..72], volume_id);
+    ret[80..88].copy_from_slice(&both32(layout.total as u32));
+    ret[120..124].copy_from_slice(&both16(1));
+    ret[124..128].copy_from_slice(&both16(1));
+    ret[128..132].copy_from_slice(&both16(SECTOR as u16));
+    let table_size = layout.path_table.0.len() as u32;
+    ret[132..140].copy_from_slice(&both32(table_size));
+    let l_table = (SYSTEM_AREA + 2) as u32;
+    let m_table = l_table + sectors(table_size as u64) as u32;
+    ret[140..144].copy_from_slice(&l_table.to_le_bytes());
+    ret[148..152].copy_from_slice(&m_table.to_be_bytes());
+    let root = &layout.dirs[0];
+    let root_record = record(&[0], root.extent, root.size, true, record_date(date), &[]);
+    ret[156..190].copy_from_slice(&root_record);
+    for range in [190..318, 318..446, 446..574, 574..702] {
+        text_field(&mut ret[range], "");
+    }
+    text_field(&mut ret[574..702], "DEB_MIRROR");
+    for range in [702..739, 739..776, 776..813] {
+        text_field(&mut ret[range], "");
+    }
+    let created = volume_date(date);
+    ret[813..830].copy_from_slice(&created);
+    ret[830..847].copy_from_slice(&created);
+    ret[847..863].copy_from_slice(b"0000000000000000");
+    ret[864..881].copy_from_slice(&created);
+    ret[881] = 1;
+    ret
+}
+
+fn pad_to_sector(out: &mut impl Write, written: u64) -> std::io::Result<()> {
+    let rest = (SECTOR - written % SECTOR) % SECTOR;
+    out.write_all(&vec![0u8; rest as usize])
+}
+
+/// Size of the image write_iso makes of `files`, without writing it.
+pub(crate) fn image_size(files: &[IsoFile]) -> anyhow::Result<u64> {
+    Ok(build(files)?.total * SECTOR)
+}
+
+/// Writes an ISO 9660 image with Rock Ridge names holding `files`, and
+/// returns its size. `volume_id` is reduced to the characters allowed.
+pub(crate) fn write_iso(
+    path: &std::path::Path,
+    volume_id: &str,
+    files: &[IsoFile],
+    date: u64,
+) -> anyhow::Result<u64> {
+    let layout = build(files)?;
+    let volume_id: String = volume_id
+        .chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ ('A'..='Z' | '0'..='9' | '_') => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect();
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    out.write_all(&vec![0u8; (SYSTEM_AREA * SECTOR) as usize])?;
+    out.write_all(&primary_descriptor(&layout, &volume_id, date))?;
+    let mut terminator = vec![0u8; SECTOR as usize];
+    terminator[0] = 255;
+    terminator[1..6].copy_from_slice(b"CD001");
+    terminator[6] = 1;
+    out.write_all(&terminator)?;
+    for table in [&layout.path_table.0, &layout.path_table.1] {
+        out.write_all(table)?;
+        pad_to_sector(&mut out, table.len() as u64)?;
+    }
+    for x in layout.order.iter() {
+        out.write_all(&dir_records(&layout.dirs, *x, files, &layout.extents, date))?;
+    }
+    for file in files.iter() {
+        let written = match &file.content {
+            Content::Bytes(o) => {
+                out.write_all(o)?;
+                o.len() as u64
+            }
+            Content::File(o) => {
+                let input = std::fs::File::open(o)?;
+                std::io::copy(&mut input.take(file.size), &mut out)?
+            }
+        };
+        if written != file.size {
+            return Err(anyhow::format_err!(
+                "{} changed size while written to the image",
+                file.path
+            ));
+        }
+        pad_to_sector(&mut out, written)?;
+    }
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(layout.total * SECTOR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_d_characters() {
+        let mut taken = HashSet::new();
+        assert_eq!(
+            iso_id("hello_2.10-3_amd64.deb", false, &mut taken),
+            "HELLO_2_10_3_AMD64.DEB;1"
+        );
+        assert_eq!(iso_id("main", true, &mut taken), "MAIN");
+        assert_eq!(iso_id("Release", false, &mut taken), "RELEASE.;1");
+        assert_eq!(iso_id(".disk", true, &mut taken), "_DISK");
+        assert_eq!(iso_id("binary-amd64", true, &mut taken), "BINARY_AMD64");
+    }
+
+    #[test]
+    fn ids_are_unique_and_short() {
+        let mut taken = HashSet::new();
+        assert_eq!(iso_id("a+b", true, &mut taken), "A_B");
+        assert_eq!(iso_id("a-b", true, &mut taken), "A_B_1");
+        assert_eq!(iso_id("a.b", true, &mut taken), "A_B_2");
+        let long = format!("{}.tar.xz", "x".repeat(40));
+        let first = iso_id(&long, false, &mut taken);
+        let second = iso_id(&long, false, &mut taken);
+        assert_eq!(first, format!("{}.XZ;1", "X".repeat(27)));
+        assert_eq!(second, format!("{}_1.XZ;1", "X".repeat(25)));
+        assert_eq!(iso_id(&"d".repeat(40), true, &mut taken).len(), 31);
+    }
+
+    #[test]
+    fn images_are_as_large_as_planned() {
+        let files: Vec<IsoFile> = (0..50)
+            .map(|i| {
+                let data = vec![b'x'; i * 300];
+                IsoFile {
+                    path: format!("pool/main/p{}/package-{}_1.0_amd64.deb", i % 7, i),
+                    size: data.len() as u64,
+                    content: Content::Bytes(data),
+                }
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("mysync-iso-{}.iso", std::process::id()));
+        let written = write_iso(&path, "test volume", &files, 1_792_231_200).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), written);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image_size(&files).unwrap(), written);
+    }
+}
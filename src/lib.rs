@@ -0,0 +1,45 @@
+//! Debian and Ubuntu mirroring over a content addressed store.
+//!
+//! Every pool file is kept once in a [`BlobStore`] named by its SHA256, and
+//! the mirror trees link into it. The building blocks are public so other
+//! tools can parse sources.list entries ([`SourceEntry`]), Release files
+//! ([`ReleaseFile`]) and Packages indices ([`PackageStanza`]), or work with
+//! the store and the mirrors file ([`Mirror`]) directly. The deb_mirror
+//! binary is a command line front end over the command functions of the
+//! modules below.
+
+pub mod bundle;
+pub mod daemon;
+pub mod download_dist;
+pub mod fsck;
+pub mod gc;
+mod http;
+mod iso;
+mod link_mode;
+pub mod logging;
+pub mod media;
+pub mod metrics;
+pub mod mirrors;
+pub mod packages;
+pub mod paths;
+mod pins;
+mod progress;
+pub mod proxy;
+pub mod release;
+pub mod replicate;
+pub mod retention;
+pub mod schedule;
+pub mod serve;
+pub mod snapshot;
+pub mod sources;
+pub mod store;
+pub mod sync;
+mod tar;
+mod trace;
+
+pub use mirrors::Mirror;
+pub use mirrors::MirrorCtx;
+pub use packages::PackageStanza;
+pub use release::ReleaseFile;
+pub use sources::SourceEntry;
+pub use store::BlobStore;
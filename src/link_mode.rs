@@ -0,0 +1,214 @@
+use anyhow::Context;
+use std::os::unix::fs::MetadataExt;
+
+use crate::download_dist::do_link;
+use crate::mirrors::MirrorCtx;
+use crate::store::blob_path;
+use tracing::warn;
+
+/// How pool files are published from the SHA256 store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    Symlink,
+    Hardlink,
+    Reflink,
+    Copy,
+}
+
+impl std::str::FromStr for LinkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<LinkMode> {
+        match s.trim() {
+            "symlink" => Ok(LinkMode::Symlink),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "reflink" => Ok(LinkMode::Reflink),
+            "copy" => Ok(LinkMode::Copy),
+            x => Err(anyhow::format_err!("Unknown link mode {}", x)),
+        }
+    }
+}
+
+/// The link mode named in the link mode file at `path`, symlinks when there
+/// is none.
+pub(crate) fn read_link_mode(path: &str) -> LinkMode {
+    match std::fs::read_to_string(path) {
+        Ok(o) => match o.parse() {
+            Ok(o) => o,
+            Err(e) => {
+                warn!(file = %path, "{}, using symlinks", e);
+                LinkMode::Symlink
+            }
+        },
+        Err(_) => LinkMode::Symlink,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(src: &std::fs::File, dest: &std::fs::File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // FICLONE from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x40049409;
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &std::fs::File, _dest: &std::fs::File) -> std::io::Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+/// Writes a private copy of `src` at `tmp`, cloning the extents when asked
+/// to and the filesystem allows it.
+fn copy_blob(src: &str, tmp: &str, try_reflink: bool) -> std::io::Result<()> {
+    let mut input = std::fs::File::open(src)?;
+    let mut output = std::fs::File::create(tmp)?;
+    if try_reflink && reflink(&input, &output).is_ok() {
+        return Ok(());
+    }
+    std::io::copy(&mut input, &mut output)?;
+    output.sync_all()
+}
+
+/// Publishes the blob `sha` at the pool location `loc` using the link mode
+/// of the tree. Existing pool files are left untouched.
+pub(crate) async fn link_file(ctx: &MirrorCtx, sha: &str, loc: &str) -> anyhow::Result<()> {
+    let mode = ctx.link_mode();
+    if mode == LinkMode::Symlink {
+        return do_link(sha, loc).await;
+    }
+
+    if tokio::fs::symlink_metadata(loc).await.is_ok() {
+        return Ok(());
+    }
+    if let Some(parent_dir) = std::path::Path::new(loc).parent() {
+        tokio::fs::create_dir_all(parent_dir).await?;
+    }
+
+    let src = blob_path(sha);
+    match mode {
+        LinkMode::Hardlink => tokio::fs::hard_link(&src, loc)
+            .await
+            .with_context(|| format!("Failed creating hardlink from {} to {}", loc, src)),
+        _ => {
+            // Copy next to the destination and rename, so a client never sees
+            // a partially written file.
+            let tmp = format!("{}.tmp", loc);
+            let try_reflink = mode == LinkMode::Reflink;
+            let (src_ref, tmp_ref) = (src.clone(), tmp.clone());
+            tokio::task::spawn_blocking(move || copy_blob(&src_ref, &tmp_ref, try_reflink))
+                .await?
+                .with_context(|| format!("Failed copying {} to {}", src, tmp))?;
+            tokio::fs::rename(&tmp, loc)
+                .await
+                .with_context(|| format!("Failed to move {} to {}", tmp, loc))
+        }
+    }
+}
+
+/// Like link_file for a pool file of a new generation. In the copy and
+/// reflink modes the copy `previous` holds in the generation before is
+/// hardlinked when it still matches the blob, so a publish only copies the
+/// files that changed rather than the whole pool.
+pub(crate) async fn link_file_reusing(
+    ctx: &MirrorCtx,
+    sha: &str,
+    loc: &str,
+    previous: &str,
+) -> anyhow::Result<()> {
+    let mode = ctx.link_mode();
+    if (mode == LinkMode::Copy || mode == LinkMode::Reflink)
+        && check_pool_file(ctx, sha, previous).await?
+    {
+        if let Some(parent_dir) = std::path::Path::new(loc).parent() {
+            tokio::fs::create_dir_all(parent_dir).await?;
+        }
+        if tokio::fs::hard_link(previous, loc).await.is_ok() {
+            return Ok(());
+        }
+    }
+    link_file(ctx, sha, loc).await
+}
+
+/// Checks that the pool file at `loc` publishes the blob `sha` in the
+/// link mode of the tree. Copies are compared by size only.
+pub(crate) async fn check_pool_file(ctx: &MirrorCtx, sha: &str, loc: &str) -> anyhow::Result<bool> {
+    let src = blob_path(sha);
+    let blob = match tokio::fs::metadata(&src).await {
+        Ok(o) => o,
+        Err(_) => return Ok(false),
+    };
+    let link = match tokio::fs::symlink_metadata(loc).await {
+        Ok(o) => o,
+        Err(_) => return Ok(false),
+    };
+
+    match ctx.link_mode() {
+        LinkMode::Symlink => {
+            if !link.file_type().is_symlink() {
+                return Ok(false);
+            }
+            let target = match tokio::fs::metadata(loc).await {
+                Ok(o) => o,
+                Err(_) => return Ok(false),
+            };
+            Ok(target.dev() == blob.dev() && target.ino() == blob.ino())
+        }
+        LinkMode::Hardlink => Ok(link.dev() == blob.dev() && link.ino() == blob.ino()),
+        LinkMode::Reflink | LinkMode::Copy => Ok(link.is_file() && link.len() == blob.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_modes_parse_by_name() {
+        assert_eq!("symlink".parse::<LinkMode>().unwrap(), LinkMode::Symlink);
+        assert_eq!(
+            "hardlink\n".parse::<LinkMode>().unwrap(),
+            LinkMode::Hardlink
+        );
+        assert_eq!(" reflink ".parse::<LinkMode>().unwrap(), LinkMode::Reflink);
+        assert_eq!("copy".parse::<LinkMode>().unwrap(), LinkMode::Copy);
+        assert!("Copy".parse::<LinkMode>().is_err());
+        assert!("".parse::<LinkMode>().is_err());
+    }
+
+    #[test]
+    fn trees_without_a_valid_link_mode_use_symlinks() {
+        let dir = std::env::temp_dir().join(format!("mysync-link-mode-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("link_mode.txt").to_string_lossy().to_string();
+        assert_eq!(read_link_mode(&path), LinkMode::Symlink);
+        std::fs::write(&path, "hardlink\n").unwrap();
+        assert_eq!(read_link_mode(&path), LinkMode::Hardlink);
+        std::fs::write(&path, "bogus\n").unwrap();
+        assert_eq!(read_link_mode(&path), LinkMode::Symlink);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copies_fall_back_from_reflinks() {
+        let dir = std::env::temp_dir().join(format!("mysync-copy-blob-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("blob").to_string_lossy().to_string();
+        std::fs::write(&src, vec![3u8; 10000]).unwrap();
+        for try_reflink in [false, true] {
+            let tmp = dir.join(format!("copy-{}", try_reflink));
+            let tmp = tmp.to_string_lossy().to_string();
+            copy_blob(&src, &tmp, try_reflink).unwrap();
+            assert_eq!(std::fs::read(&tmp).unwrap(), vec![3u8; 10000]);
+            assert_ne!(
+                std::fs::metadata(&tmp).unwrap().ino(),
+                std::fs::metadata(&src).unwrap().ino()
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::Context;
+use std::io::IsTerminal;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// Where log records go and how much of them. Records are written to stderr
+/// unless a log file is given; RUST_LOG overrides the level.
+#[derive(Debug, Clone, clap::Args)]
+pub struct LogOptions {
+    /// Log more, -v for progress and summaries, -vv for every file handled
+    /// and -vvv for everything
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Format of the log records
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Write the log to this file instead of stderr, rotated as set by
+    /// --log-rotation with the date appended to the name
+    #[arg(long, global = true)]
+    pub log_file: Option<std::path::PathBuf>,
+
+    /// How often the log file is started afresh
+    #[arg(long, global = true, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+
+    /// Number of rotated log files kept, older ones are deleted; 0 keeps
+    /// them all
+    #[arg(long, global = true, default_value_t = 14)]
+    pub log_keep: usize,
+}
+
+/// Keeps the background log writer alive; dropping it flushes the log.
+pub struct LogGuard {
+    _guard: Option<tracing_appender::non_blocking::WorkerGuard>,
+}
+
+/// Installs the global subscriber. Only problems and the access log of the
+/// servers are logged at the default level, summaries at info and every
+/// file handled at debug.
+pub fn init_logging(options: &LogOptions) -> anyhow::Result<LogGuard> {
+    let level = match options.verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(o) => EnvFilter::try_new(o).context("invalid RUST_LOG")?,
+        Err(_) => EnvFilter::new(format!("warn,deb_mirror={},deb_mirror::access=info", level)),
+    };
+
+    let (writer, guard) = match &options.log_file {
+        Some(path) => {
+            let dir = match path.parent() {
+                Some(o) if !o.as_os_str().is_empty() => o,
+                _ => std::path::Path::new("."),
+            };
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow::format_err!("invalid log file {}", path.display()))?;
+            let rotation = match options.log_rotation {
+                LogRotation::Never => tracing_appender::rolling::Rotation::NEVER,
+                LogRotation::Hourly => tracing_appender::rolling::Rotation::HOURLY,
+                LogRotation::Daily => tracing_appender::rolling::Rotation::DAILY,
+            };
+            let appender = tracing_appender::rolling::RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(name.to_string_lossy())
+                .max_log_files(options.log_keep)
+                .build(dir)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(options.log_file.is_none() && std::io::stderr().is_terminal());
+    let result = match options.log_format {
+        LogFormat::Text => builder.with_target(false).try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    result.map_err(|e| anyhow::format_err!("failed to set up logging: {}", e))?;
+    Ok(LogGuard { _guard: guard })
+}
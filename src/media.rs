@@ -0,0 +1,559 @@
+use anyhow::Context;
+use sha2::Digest;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::download_dist::has_packages;
+use crate::download_dist::read_list_dist_packages;
+use crate::download_dist::read_packages;
+use crate::gc::now_seconds;
+use crate::iso::image_size;
+use crate::iso::write_iso;
+use crate::iso::Content;
+use crate::iso::IsoFile;
+use crate::mirrors::shared_path;
+use crate::mirrors::MirrorCtx;
+use crate::packages::read_stanzas;
+use crate::packages::PackageStanza;
+use crate::pins::glob_match;
+use crate::release::gzip;
+use crate::release::ReleaseFile;
+use crate::snapshot::timestamp_name;
+use crate::store::blob_path;
+use crate::store::lock_store;
+use tracing::debug;
+use tracing::info;
+
+const SECTOR: u64 = 2048;
+
+/// Room kept on every volume for the volume descriptors, the Release files,
+/// .disk/info and the dists directories, beside the sectors of the indices.
+const VOLUME_RESERVE: u64 = 1 << 20;
+
+/// Largest directory record, its Rock Ridge entries included.
+const RECORD_SIZE: u64 = 255;
+
+/// Largest path table entry, of which there are two per directory.
+const PATH_ENTRY_SIZE: u64 = 40;
+
+/// Release fields copied from upstream onto each volume.
+const RELEASE_FIELDS: [&str; 7] = [
+    "Origin",
+    "Label",
+    "Suite",
+    "Codename",
+    "Version",
+    "Date",
+    "Architectures",
+];
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct MediaOptions {
+    /// Capacity of a volume: cd, dvd, dvd-dl, bd, bd-dl or a size in bytes
+    /// with an optional K, M or G suffix
+    #[arg(long, default_value = "dvd", value_parser = parse_media_size)]
+    pub size: u64,
+
+    /// Only press packages whose name matches one of these globs
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Leave out packages whose name matches one of these globs
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Name of the set, shown by apt-cdrom and naming the images; the
+    /// upstream Label when unset
+    #[arg(long)]
+    pub label: Option<String>,
+
+    /// Directory the LABEL-N.iso images are written to
+    pub output: PathBuf,
+}
+
+/// Parses a media capacity such as `dvd` or `700M`.
+fn parse_media_size(text: &str) -> Result<u64, String> {
+    let named = match text.to_ascii_lowercase().as_str() {
+        "cd" => Some(737_280_000),
+        "dvd" => Some(4_700_372_992),
+        "dvd-dl" => Some(8_543_666_176),
+        "bd" => Some(25_025_314_816),
+        "bd-dl" => Some(50_050_629_632),
+        _ => None,
+    };
+    if let Some(o) = named {
+        return Ok(o);
+    }
+    let (digits, unit) = match text.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&text[..i], c.to_ascii_uppercase()),
+        _ => (text, 'B'),
+    };
+    let scale: u64 = match unit {
+        'B' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(format!("unknown size unit in {}", text)),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("{} is not a media name or a size", text))?;
+    let size = value
+        .checked_mul(scale)
+        .ok_or_else(|| format!("{} is too large", text))?;
+    if size <= VOLUME_RESERVE {
+        return Err(format!("{} is too small for a volume", text));
+    }
+    Ok(size)
+}
+
+/// Name of the package a pool file belongs to, `foo` for
+/// `pool/main/f/foo/foo_1.0_amd64.deb`.
+fn package_name(filename: &str) -> &str {
+    let base = filename.rsplit('/').next().unwrap_or(filename);
+    base.split('_').next().unwrap_or(base)
+}
+
+fn wanted(options: &MediaOptions, filename: &str) -> bool {
+    let name = package_name(filename);
+    (options.include.is_empty() || options.include.iter().any(|x| glob_match(x, name)))
+        && !options.exclude.iter().any(|x| glob_match(x, name))
+}
+
+/// A pool file to press, its data taken from the store.
+#[derive(Debug, Clone)]
+struct PoolFile {
+    filename: String,
+    blob: String,
+    size: u64,
+    /// Bytes of its stanzas in every Packages index listing it, one per
+    /// architecture and suite.
+    stanza_size: u64,
+}
+
+/// The directories above `filename`: `pool`, `pool/main` and so on.
+fn parent_dirs(filename: &str) -> Vec<&str> {
+    filename
+        .match_indices('/')
+        .map(|(i, _)| &filename[..i])
+        .collect()
+}
+
+/// Splits the pool files in order over volumes of `capacity` bytes, of which
+/// `reserve` is kept for what does not grow with the files. A file costs at
+/// most its sectors, its directory record and its stanzas in the plain and
+/// the compressed indices, and every directory it opens on the volume a
+/// sector, its record and its path table entries.
+fn plan_volumes(
+    files: Vec<PoolFile>,
+    capacity: u64,
+    reserve: u64,
+) -> anyhow::Result<Vec<Vec<PoolFile>>> {
+    let room = capacity.saturating_sub(reserve);
+    let mut ret: Vec<Vec<PoolFile>> = Vec::new();
+    let mut used = room;
+    let mut dirs: HashSet<String> = HashSet::new();
+    for x in files {
+        let parents = parent_dirs(&x.filename);
+        let cost = |dirs: &HashSet<String>| {
+            let opened = parents.iter().filter(|d| !dirs.contains(**d)).count() as u64;
+            x.size.div_ceil(SECTOR) * SECTOR
+                + RECORD_SIZE
+                + 2 * x.stanza_size
+                + opened * (SECTOR + RECORD_SIZE + 2 * PATH_ENTRY_SIZE)
+        };
+        if cost(&HashSet::new()) > room {
+            return Err(anyhow::format_err!(
+                "{} is larger than a volume",
+                x.filename
+            ));
+        }
+        if used + cost(&dirs) > room {
+            ret.push(Vec::new());
+            used = 0;
+            dirs.clear();
+        }
+        used += cost(&dirs);
+        dirs.extend(parents.iter().map(|d| d.to_string()));
+        if let Some(o) = ret.last_mut() {
+            o.push(x);
+        }
+    }
+    Ok(ret)
+}
+
+/// One Packages index of the mirror, split into its suite and its path
+/// below the suite.
+struct Index {
+    suite: String,
+    path: String,
+    stanzas: Vec<PackageStanza>,
+}
+
+async fn read_indices(ctx: &MirrorCtx) -> anyhow::Result<Vec<Index>> {
+    let list = read_list_dist_packages(ctx).await?;
+    let mut ret = Vec::new();
+    for file in list.split('\n').filter(|x| has_packages(x)) {
+        let rest = file.strip_prefix("dists/").unwrap_or(file);
+        let (suite, path) = rest
+            .split_once('/')
+            .ok_or_else(|| anyhow::format_err!("{} is not below a suite", file))?;
+        ret.push(Index {
+            suite: suite.to_string(),
+            path: path.to_string(),
+            stanzas: read_stanzas(&ctx.path(file)).await?,
+        });
+    }
+    Ok(ret)
+}
+
+/// The Release file of `suite` on a volume, listing `indices` by path and
+/// content.
+fn volume_release(
+    upstream: Option<&ReleaseFile>,
+    suite: &str,
+    indices: &[(String, Vec<u8>)],
+) -> String {
+    let mut ret = String::new();
+    let mut has_suite = false;
+    if let Some(release) = upstream {
+        for name in RELEASE_FIELDS {
+            if let Some(o) = release.get(name) {
+                ret.push_str(&format!("{}: {}\n", name, o));
+                has_suite |= name == "Suite";
+            }
+        }
+    }
+    if !has_suite {
+        ret.push_str(&format!("Suite: {}\n", suite));
+    }
+    let mut components: Vec<&str> = indices
+        .iter()
+        .filter_map(|(x, _)| x.split_once('/').map(|(c, _)| c))
+        .collect();
+    components.dedup();
+    ret.push_str(&format!("Components: {}\n", components.join(" ")));
+    ret.push_str("SHA256:\n");
+    for (path, data) in indices.iter() {
+        ret.push_str(&format!(
+            " {} {:>16} {}\n",
+            hex::encode(sha2::Sha256::digest(data)),
+            data.len(),
+            path
+        ));
+    }
+    ret
+}
+
+/// The dists files of a volume: the Packages indices restricted to its pool
+/// files and a Release file per suite.
+async fn volume_dists(
+    ctx: &MirrorCtx,
+    indices: &[Index],
+    volume: &[PoolFile],
+) -> anyhow::Result<Vec<IsoFile>> {
+    let on_volume: HashSet<&str> = volume.iter().map(|x| x.filename.as_str()).collect();
+    let mut suites: BTreeMap<&str, Vec<(String, Vec<u8>)>> = BTreeMap::new();
+    for index in indices.iter() {
+        let mut text = String::new();
+        for stanza in index.stanzas.iter() {
+            if stanza
+                .get("Filename")
+                .is_some_and(|x| on_volume.contains(x))
+            {
+                text.push_str(&stanza.to_text());
+            }
+        }
+        let entries = suites.entry(&index.suite).or_default();
+        let compressed = gzip(text.as_bytes())?;
+        entries.push((index.path.clone(), text.into_bytes()));
+        entries.push((format!("{}.gz", index.path), compressed));
+    }
+
+    let mut ret = Vec::new();
+    for (suite, entries) in suites {
+        let release_path = format!("dists/{}/Release", suite);
+        let local = ctx.path(&release_path);
+        let upstream = match tokio::fs::try_exists(&local).await? {
+            true => Some(ReleaseFile::read(&local).await?),
+            false => None,
+        };
+        let release = volume_release(upstream.as_ref(), suite, &entries);
+        for (path, data) in entries {
+            ret.push(IsoFile {
+                path: format!("dists/{}/{}", suite, path),
+                size: data.len() as u64,
+                content: Content::Bytes(data),
+            });
+        }
+        ret.push(IsoFile {
+            path: release_path,
+            size: release.len() as u64,
+            content: Content::Bytes(release.into_bytes()),
+        });
+    }
+    Ok(ret)
+}
+
+fn disk_info(info: String) -> IsoFile {
+    IsoFile {
+        path: String::from(".disk/info"),
+        size: info.len() as u64,
+        content: Content::Bytes(info.into_bytes()),
+    }
+}
+
+/// Everything on a volume but .disk/info: its dists and its pool files.
+async fn volume_image(
+    ctx: &MirrorCtx,
+    indices: &[Index],
+    volume: &[PoolFile],
+) -> anyhow::Result<Vec<IsoFile>> {
+    let mut ret = volume_dists(ctx, indices, volume).await?;
+    for x in volume.iter() {
+        ret.push(IsoFile {
+            path: x.filename.clone(),
+            size: x.size,
+            content: Content::File(PathBuf::from(&x.blob)),
+        });
+    }
+    Ok(ret)
+}
+
+/// Splits the pool files of the mirror over volumes of the given size and
+/// writes each as an ISO 9660 image apt-cdrom can add: the volume's pool
+/// files, Packages indices listing just them, a Release file per suite and
+/// .disk/info naming the set and the disk.
+pub async fn write_media(ctx: &MirrorCtx, options: &MediaOptions) -> anyhow::Result<()> {
+    let _store = lock_store(false).await?;
+    let indices = read_indices(ctx).await?;
+    let mut stanza_sizes: HashMap<&str, u64> = HashMap::new();
+    for index in indices.iter() {
+        for stanza in index.stanzas.iter() {
+            if let Some(o) = stanza.get("Filename") {
+                *stanza_sizes.entry(o).or_default() += stanza.to_text().len() as u64;
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for x in read_packages(ctx).await? {
+        if !wanted(options, &x.filename) || !seen.insert(x.filename.clone()) {
+            continue;
+        }
+        let blob = blob_path(&x.sha256);
+        let size = tokio::fs::metadata(&blob)
+            .await
+            .with_context(|| format!("{} is not in the store", x.filename))?
+            .len();
+        let stanza_size = stanza_sizes.get(x.filename.as_str()).copied();
+        files.push(PoolFile {
+            filename: x.filename,
+            blob,
+            size,
+            stanza_size: stanza_size.unwrap_or(0),
+        });
+    }
+    if files.is_empty() {
+        return Err(anyhow::format_err!("no package is left to press"));
+    }
+    files.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    // The plan is an estimate. Each volume is checked against the layout of
+    // its image, and the files it has no room for move on to the next one.
+    // .disk/info is not known before the number of volumes is, it is laid
+    // out with a placeholder taking the same single sector.
+    let reserve = VOLUME_RESERVE + 2 * SECTOR * indices.len() as u64;
+    let mut volumes: Vec<(Vec<PoolFile>, Vec<IsoFile>)> = Vec::new();
+    let mut rest = files;
+    while !rest.is_empty() {
+        let mut planned = plan_volumes(rest, options.size, reserve)?.into_iter();
+        let mut volume = planned.next().unwrap_or_default();
+        let mut moved = Vec::new();
+        let image = loop {
+            let mut image = volume_image(ctx, &indices, &volume).await?;
+            image.push(disk_info(String::from("-")));
+            let size = image_size(&image)?;
+            if size <= options.size {
+                break image;
+            }
+            if volume.len() == 1 {
+                return Err(anyhow::format_err!(
+                    "{} is larger than a volume",
+                    volume[0].filename
+                ));
+            }
+            debug!(
+                size,
+                capacity = options.size,
+                "volume overfull, moving files on"
+            );
+            let mut freed = 0;
+            while freed < size - options.size && volume.len() > 1 {
+                let Some(x) = volume.pop() else { break };
+                freed += x.size.div_ceil(SECTOR) * SECTOR;
+                moved.push(x);
+            }
+        };
+        rest = moved.into_iter().rev().chain(planned.flatten()).collect();
+        volumes.push((volume, image));
+    }
+
+    let suites: Vec<&str> = {
+        let mut x: Vec<&str> = indices.iter().map(|x| x.suite.as_str()).collect();
+        x.dedup();
+        x
+    };
+    let label = match &options.label {
+        Some(o) => o.clone(),
+        None => {
+            let release = ctx.path(&format!("dists/{}/Release", suites.first().unwrap_or(&"")));
+            let upstream = match tokio::fs::try_exists(&release).await? {
+                true => ReleaseFile::read(&release)
+                    .await?
+                    .get("Label")
+                    .map(|x| x.to_string()),
+                false => None,
+            };
+            upstream
+                .or_else(|| ctx.name().map(|x| x.to_string()))
+                .unwrap_or_else(|| String::from("mirror"))
+        }
+    };
+    let now = now_seconds();
+    // Like the shared store, the output is taken from the root rather than
+    // from the mirror subtree.
+    let output = PathBuf::from(shared_path(&options.output.to_string_lossy()));
+    tokio::fs::create_dir_all(&output)
+        .await
+        .with_context(|| format!("failed to create {}", output.display()))?;
+
+    let total = volumes.len();
+    for (n, (volume, mut image)) in volumes.into_iter().enumerate() {
+        let number = n + 1;
+        let info = format!(
+            "{} {} - Disk {}/{} ({})",
+            label,
+            suites.join(" "),
+            number,
+            total,
+            timestamp_name(now)
+        );
+        image.pop();
+        image.push(disk_info(info));
+        let name = format!("{}-{}.iso", label, number);
+        let path = output.join(&name);
+        let tmp = output.join(format!("{}.tmp", name));
+        let volume_id = format!("{}_{}", label, number);
+        debug!(image = %name, files = volume.len(), "writing");
+        let written = {
+            let tmp = tmp.clone();
+            tokio::task::spawn_blocking(move || write_iso(&tmp, &volume_id, &image, now)).await??
+        };
+        if written > options.size {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(anyhow::format_err!(
+                "{} would take {} bytes, more than the {} of a volume",
+                name,
+                written,
+                options.size
+            ));
+        }
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("failed to rename {}", tmp.display()))?;
+        info!(
+            volume = number,
+            of = total,
+            packages = volume.len(),
+            bytes = written,
+            "wrote {}",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool_file(filename: &str, size: u64) -> PoolFile {
+        PoolFile {
+            filename: filename.to_string(),
+            blob: String::new(),
+            size,
+            stanza_size: 0,
+        }
+    }
+
+    fn names(volumes: &[Vec<PoolFile>]) -> Vec<Vec<&str>> {
+        volumes
+            .iter()
+            .map(|x| x.iter().map(|x| x.filename.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parses_media_sizes() {
+        assert_eq!(parse_media_size("DVD"), Ok(4_700_372_992));
+        assert_eq!(parse_media_size("cd"), Ok(737_280_000));
+        assert_eq!(parse_media_size("700M"), Ok(700 << 20));
+        assert_eq!(parse_media_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_media_size("5000000"), Ok(5_000_000));
+        assert!(parse_media_size("1M").is_err());
+        assert!(parse_media_size("10T").is_err());
+        assert!(parse_media_size("big").is_err());
+        assert!(parse_media_size("99999999999999999G").is_err());
+    }
+
+    #[test]
+    fn volumes_charge_files_and_the_directories_they_open() {
+        let file = SECTOR + RECORD_SIZE;
+        let dir = SECTOR + RECORD_SIZE + 2 * PATH_ENTRY_SIZE;
+        let room = file * 2 + dir * 2;
+        let files = vec![
+            pool_file("pool/main/a.deb", 1),
+            pool_file("pool/main/b.deb", SECTOR),
+            pool_file("pool/main/c.deb", 1),
+        ];
+        let volumes = plan_volumes(files.clone(), room + 100, 100).unwrap();
+        assert_eq!(
+            names(&volumes),
+            vec![
+                vec!["pool/main/a.deb", "pool/main/b.deb"],
+                vec!["pool/main/c.deb"]
+            ]
+        );
+        // A file in a new directory also pays for it.
+        let mut files = files;
+        files[1].filename = String::from("pool/contrib/b.deb");
+        let volumes = plan_volumes(files, room, 0).unwrap();
+        assert_eq!(
+            names(&volumes),
+            vec![
+                vec!["pool/main/a.deb"],
+                vec!["pool/contrib/b.deb"],
+                vec!["pool/main/c.deb"]
+            ]
+        );
+    }
+
+    #[test]
+    fn volumes_charge_stanzas_twice() {
+        let mut x = pool_file("pool/a.deb", 1);
+        let room = SECTOR + RECORD_SIZE + SECTOR + RECORD_SIZE + 2 * PATH_ENTRY_SIZE;
+        assert_eq!(plan_volumes(vec![x.clone()], room, 0).unwrap().len(), 1);
+        x.stanza_size = 1;
+        assert!(plan_volumes(vec![x.clone()], room + 1, 0).is_err());
+        assert_eq!(plan_volumes(vec![x], room + 2, 0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn files_larger_than_a_volume_are_refused() {
+        assert!(plan_volumes(vec![pool_file("pool/a.deb", 10 * SECTOR)], 4 * SECTOR, 0).is_err());
+        assert!(plan_volumes(Vec::new(), 0, 0).unwrap().is_empty());
+    }
+}
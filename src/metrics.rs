@@ -0,0 +1,296 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+/// One metric family of the Prometheus text format.
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    /// Labelled with the mirror it is about, false for the shared store.
+    pub per_mirror: bool,
+}
+
+pub const DOWNLOADED_BYTES: Metric = Metric {
+    name: "deb_mirror_downloaded_bytes_total",
+    help: "Bytes of pool files downloaded.",
+    kind: Kind::Counter,
+    per_mirror: true,
+};
+
+pub const DOWNLOADED_FILES: Metric = Metric {
+    name: "deb_mirror_downloaded_files_total",
+    help: "Pool files downloaded.",
+    kind: Kind::Counter,
+    per_mirror: true,
+};
+
+pub const FAILED_FILES: Metric = Metric {
+    name: "deb_mirror_failed_files_total",
+    help: "Pool files that failed to download from every upstream mirror.",
+    kind: Kind::Counter,
+    per_mirror: true,
+};
+
+pub const HASH_MISMATCHES: Metric = Metric {
+    name: "deb_mirror_hash_mismatches_total",
+    help: "Downloads and blobs whose content did not match their SHA256.",
+    kind: Kind::Counter,
+    per_mirror: true,
+};
+
+pub const STORE_BYTES: Metric = Metric {
+    name: "deb_mirror_store_bytes",
+    help: "Bytes of all blobs in the store.",
+    kind: Kind::Gauge,
+    per_mirror: false,
+};
+
+pub const STORE_BLOBS: Metric = Metric {
+    name: "deb_mirror_store_blobs",
+    help: "Number of blobs in the store.",
+    kind: Kind::Gauge,
+    per_mirror: false,
+};
+
+pub const LAST_SUCCESS: Metric = Metric {
+    name: "deb_mirror_last_success_timestamp_seconds",
+    help: "Unix time of the last successful sync of a suite.",
+    kind: Kind::Gauge,
+    per_mirror: true,
+};
+
+pub const STAGE_DURATION: Metric = Metric {
+    name: "deb_mirror_stage_duration_seconds",
+    help: "Duration of the last run of a sync stage.",
+    kind: Kind::Gauge,
+    per_mirror: true,
+};
+
+pub const SYNC_RUNS: Metric = Metric {
+    name: "deb_mirror_sync_runs_total",
+    help: "Sync runs by result.",
+    kind: Kind::Counter,
+    per_mirror: true,
+};
+
+const METRICS: [&Metric; 9] = [
+    &DOWNLOADED_BYTES,
+    &DOWNLOADED_FILES,
+    &FAILED_FILES,
+    &HASH_MISMATCHES,
+    &STORE_BYTES,
+    &STORE_BLOBS,
+    &LAST_SUCCESS,
+    &STAGE_DURATION,
+    &SYNC_RUNS,
+];
+
+/// Values recorded by this process, keyed by metric name and rendered labels.
+static VALUES: Mutex<BTreeMap<(String, String), f64>> = Mutex::new(BTreeMap::new());
+
+/// Counter values already added to the textfile by this process.
+static WRITTEN: Mutex<BTreeMap<(String, String), f64>> = Mutex::new(BTreeMap::new());
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Renders labels as `{a="x",b="y"}`. Per mirror metrics are given the
+/// `mirror` label by the caller, which is `default` when left out.
+fn render_labels(metric: &Metric, labels: &[(&str, &str)]) -> String {
+    let mut all = Vec::new();
+    if metric.per_mirror && !labels.iter().any(|(k, _)| *k == "mirror") {
+        all.push(String::from("mirror=\"default\""));
+    }
+    for (k, v) in labels {
+        all.push(format!("{}=\"{}\"", k, escape(v)));
+    }
+    if all.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", all.join(","))
+    }
+}
+
+/// Adds `value` to a counter.
+pub fn add(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    let key = (metric.name.to_string(), render_labels(metric, labels));
+    *VALUES.lock().unwrap().entry(key).or_insert(0.0) += value;
+}
+
+/// Sets a gauge.
+pub fn set(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    let key = (metric.name.to_string(), render_labels(metric, labels));
+    VALUES.lock().unwrap().insert(key, value);
+}
+
+fn kind_of(name: &str) -> Option<Kind> {
+    METRICS.iter().find(|x| x.name == name).map(|x| x.kind)
+}
+
+fn format_series(values: &BTreeMap<(String, String), f64>) -> String {
+    let mut ret = String::new();
+    for metric in METRICS.iter() {
+        let series: Vec<_> = values
+            .iter()
+            .filter(|((k, _), _)| k == metric.name)
+            .collect();
+        if series.is_empty() {
+            continue;
+        }
+        ret.push_str(&format!("# HELP {} {}\n", metric.name, metric.help));
+        ret.push_str(&format!("# TYPE {} {}\n", metric.name, metric.kind.name()));
+        for ((name, labels), value) in series {
+            ret.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    }
+    ret
+}
+
+/// The values of this process in the Prometheus text format.
+pub fn render() -> String {
+    format_series(&VALUES.lock().unwrap())
+}
+
+fn parse_series(text: &str) -> BTreeMap<(String, String), f64> {
+    let mut ret = BTreeMap::new();
+    for line in text.lines().filter(|x| !x.starts_with('#')) {
+        let (series, value) = match line.rsplit_once(' ') {
+            Some(o) => o,
+            None => continue,
+        };
+        let value: f64 = match value.parse() {
+            Ok(o) => o,
+            Err(_) => continue,
+        };
+        let (name, labels) = match series.find('{') {
+            Some(i) => (&series[..i], &series[i..]),
+            None => (series, ""),
+        };
+        ret.insert((name.to_string(), labels.to_string()), value);
+    }
+    ret
+}
+
+/// Merges `values` into the series of a textfile. Counters grow by what was
+/// recorded since `written`, which is updated, and gauges are replaced.
+fn merge_series(
+    merged: &mut BTreeMap<(String, String), f64>,
+    values: &BTreeMap<(String, String), f64>,
+    written: &mut BTreeMap<(String, String), f64>,
+) {
+    for (key, value) in values.iter() {
+        match kind_of(&key.0) {
+            Some(Kind::Counter) => {
+                let before = written.insert(key.clone(), *value).unwrap_or(0.0);
+                *merged.entry(key.clone()).or_insert(0.0) += value - before;
+            }
+            _ => {
+                merged.insert(key.clone(), *value);
+            }
+        }
+    }
+}
+
+/// Writes the metrics to a node_exporter textfile. The series of earlier
+/// runs are kept, counters are carried over and gauges replaced, so mirrors
+/// synced by separate runs share one file.
+pub async fn write_textfile(path: &str) -> anyhow::Result<()> {
+    let mut merged = match tokio::fs::read_to_string(path).await {
+        Ok(o) => parse_series(&o),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path)),
+    };
+    let values = VALUES.lock().unwrap().clone();
+    let mut written = WRITTEN.lock().unwrap().clone();
+    merge_series(&mut merged, &values, &mut written);
+    // Written next to the target and renamed, node_exporter must never read
+    // a partial file.
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, format_series(&merged))
+        .await
+        .with_context(|| format!("failed to write {}", tmp))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("failed to move {} to {}", tmp, path))?;
+    *WRITTEN.lock().unwrap() = written;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(metric: &Metric, labels: &[(&str, &str)]) -> (String, String) {
+        (metric.name.to_string(), render_labels(metric, labels))
+    }
+
+    #[test]
+    fn labels_name_the_mirror() {
+        assert_eq!(
+            render_labels(&DOWNLOADED_BYTES, &[]),
+            "{mirror=\"default\"}"
+        );
+        assert_eq!(
+            render_labels(&DOWNLOADED_BYTES, &[("mirror", "a\"b")]),
+            "{mirror=\"a\\\"b\"}"
+        );
+        assert_eq!(render_labels(&STORE_BLOBS, &[]), "");
+    }
+
+    #[test]
+    fn series_survive_a_round_trip() {
+        let mut values = BTreeMap::new();
+        values.insert(key(&DOWNLOADED_BYTES, &[("mirror", "full")]), 1024.0);
+        values.insert(key(&STORE_BLOBS, &[]), 7.0);
+        let text = format_series(&values);
+        assert!(text.contains("# TYPE deb_mirror_downloaded_bytes_total counter\n"));
+        assert_eq!(parse_series(&text), values);
+        assert!(parse_series("garbage\nname nan-ish\n").is_empty());
+    }
+
+    #[test]
+    fn counters_add_up_and_gauges_are_replaced() {
+        let bytes = key(&DOWNLOADED_BYTES, &[("mirror", "full")]);
+        let other = key(&DOWNLOADED_BYTES, &[("mirror", "small")]);
+        let blobs = key(&STORE_BLOBS, &[]);
+        let mut merged = BTreeMap::new();
+        merged.insert(bytes.clone(), 100.0);
+        merged.insert(other.clone(), 5.0);
+        merged.insert(blobs.clone(), 3.0);
+        let mut values = BTreeMap::new();
+        values.insert(bytes.clone(), 10.0);
+        values.insert(blobs.clone(), 4.0);
+        let mut written = BTreeMap::new();
+        merge_series(&mut merged, &values, &mut written);
+        assert_eq!(merged[&bytes], 110.0);
+        assert_eq!(merged[&other], 5.0);
+        assert_eq!(merged[&blobs], 4.0);
+
+        // A second write of the same process only adds what is new.
+        values.insert(bytes.clone(), 15.0);
+        merge_series(&mut merged, &values, &mut written);
+        assert_eq!(merged[&bytes], 115.0);
+        assert_eq!(written[&bytes], 15.0);
+    }
+}
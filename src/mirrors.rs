@@ -0,0 +1,443 @@
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::link_mode::read_link_mode;
+use crate::link_mode::LinkMode;
+use crate::paths::paths;
+use crate::paths::Paths;
+use crate::pins::glob_match;
+use crate::schedule::Schedule;
+
+/// One upstream repository, synced into the subtree named after its section.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    /// Upstream base URLs. Dists come from the one serving the freshest
+    /// consistent Release file, the first among equals.
+    pub urls: Vec<String>,
+    /// Upstream base URLs of architectures `urls` does not carry, such as
+    /// ports.ubuntu.com for arm64.
+    #[serde(default)]
+    pub arch_urls: BTreeMap<String, Vec<String>>,
+    pub suites: Vec<String>,
+    #[serde(default = "default_components")]
+    pub components: Vec<String>,
+    #[serde(default = "default_architectures")]
+    pub architectures: Vec<String>,
+    /// Also mirror the source indices of every component.
+    #[serde(default = "default_sources")]
+    pub sources: bool,
+    /// Keyring the Release files are checked against with gpgv.
+    pub keyring: Option<String>,
+    /// Package name patterns to mirror, everything when empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Package name patterns left out even when included.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// When the daemon syncs the mirror, an interval such as `6h` or a cron
+    /// expression such as `0 */6 * * *`, the daemon's default when unset.
+    pub schedule: Option<String>,
+    /// Serve the mirror as a caching proxy instead of syncing it in full.
+    #[serde(default)]
+    pub proxy: bool,
+}
+
+fn default_components() -> Vec<String> {
+    vec![String::from("main")]
+}
+
+fn default_architectures() -> Vec<String> {
+    vec![String::from("amd64"), String::from("i386")]
+}
+
+fn default_sources() -> bool {
+    true
+}
+
+impl Mirror {
+    /// Whether the filters let `package` into the mirror.
+    pub(crate) fn wants(&self, package: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| glob_match(x, package)))
+            && !self.exclude.iter().any(|x| glob_match(x, package))
+    }
+
+    pub(crate) fn has_filters(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+}
+
+/// The mirrors file, e.g.
+///
+/// ```toml
+/// [mirror.debian]
+/// urls = ["http://deb.debian.org/debian"]
+/// suites = ["bookworm", "bookworm-updates"]
+/// components = ["main", "contrib"]
+/// schedule = "0 */6 * * *"
+/// keyring = "/usr/share/keyrings/debian-archive-keyring.gpg"
+///
+/// [mirror.ubuntu]
+/// urls = ["http://archive.ubuntu.com/ubuntu"]
+/// arch_urls = { arm64 = ["http://ports.ubuntu.com/ubuntu-ports"] }
+/// suites = ["noble", "noble-updates", "noble-security"]
+/// components = ["main", "universe"]
+/// architectures = ["amd64", "arm64"]
+///
+/// [mirror.docker]
+/// urls = ["https://download.docker.com/linux/debian"]
+/// suites = ["bookworm"]
+/// components = ["stable"]
+/// architectures = ["amd64"]
+/// sources = false
+/// ```
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorsFile {
+    #[serde(default)]
+    pub mirror: BTreeMap<String, Mirror>,
+}
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+static MIRRORS: OnceLock<MirrorsFile> = OnceLock::new();
+
+/// Reads the mirrors file of `paths` if there is one, and takes the working
+/// directory as the mirror root the mirror subtrees are below.
+pub fn load_mirrors(paths: &Paths) -> anyhow::Result<bool> {
+    let path = &paths.mirrors;
+    let _ = ROOT.set(std::env::current_dir()?);
+    let content = match std::fs::read_to_string(path) {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path)),
+    };
+    let file: MirrorsFile =
+        toml::from_str(&content).with_context(|| format!("invalid {}", path))?;
+    check_mirrors(&file, paths)?;
+    let _ = MIRRORS.set(file);
+    Ok(true)
+}
+
+/// Checks what the types of the mirrors file cannot: the names, that every
+/// mirror has somewhere to sync from and its schedule.
+fn check_mirrors(file: &MirrorsFile, paths: &Paths) -> anyhow::Result<()> {
+    for (name, mirror) in file.mirror.iter() {
+        check_mirror_name(name, paths)?;
+        if mirror.urls.is_empty() || mirror.suites.is_empty() {
+            return Err(anyhow::format_err!(
+                "mirror {} needs at least one url and one suite",
+                name
+            ));
+        }
+        for (arch, urls) in mirror.arch_urls.iter() {
+            if !mirror.architectures.contains(arch) {
+                return Err(anyhow::format_err!(
+                    "mirror {} has urls for {}, which it does not mirror",
+                    name,
+                    arch
+                ));
+            }
+            if urls.is_empty() {
+                return Err(anyhow::format_err!(
+                    "mirror {} needs at least one url for {}",
+                    name,
+                    arch
+                ));
+            }
+        }
+        if let Some(schedule) = &mirror.schedule {
+            Schedule::parse(schedule)
+                .with_context(|| format!("invalid schedule of mirror {}", name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Refuses a mirror name that is not a single directory name, or whose
+/// subtree would overlap a file or directory of the mirror root such as
+/// the store, the mirrors file or the generations of a tree without one.
+fn check_mirror_name(name: &str, paths: &Paths) -> anyhow::Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(anyhow::format_err!("invalid mirror name {:?}", name));
+    }
+    let taken = paths
+        .all()
+        .into_iter()
+        .filter(|x| !x.starts_with('/'))
+        .find(|x| x.split('/').next() == Some(name));
+    if let Some(path) = taken {
+        return Err(anyhow::format_err!(
+            "mirror name {:?} is taken by {} below the mirror root",
+            name,
+            path
+        ));
+    }
+    Ok(())
+}
+
+fn mirror_root() -> PathBuf {
+    match ROOT.get() {
+        Some(o) => o.clone(),
+        None => std::env::current_dir().unwrap_or_default(),
+    }
+}
+
+/// Names of the configured mirrors, empty without a mirrors file.
+pub fn mirror_names() -> Vec<String> {
+    match MIRRORS.get() {
+        Some(o) => o.mirror.keys().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Names of the mirrors that are synced in full, leaving out caching
+/// proxies.
+pub fn synced_mirror_names() -> Vec<String> {
+    match MIRRORS.get() {
+        Some(o) => o
+            .mirror
+            .iter()
+            .filter(|(_, v)| !v.proxy)
+            .map(|(k, _)| k.clone())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+pub(crate) fn find_mirror(name: &str) -> Option<Mirror> {
+    MIRRORS.get()?.mirror.get(name).cloned()
+}
+
+/// The tree a command works on: the subtree of a mirror of the mirrors
+/// file, or the mirror root itself without one. Its paths are absolute, so
+/// several trees can be worked on at once without changing directory.
+#[derive(Debug, Clone)]
+pub struct MirrorCtx {
+    name: Option<String>,
+    mirror: Option<Mirror>,
+    dir: PathBuf,
+    paths: Paths,
+    link_mode: OnceLock<LinkMode>,
+}
+
+impl MirrorCtx {
+    /// The mirror root as the one tree, for a root without a mirrors file.
+    pub fn root() -> MirrorCtx {
+        MirrorCtx::new(None, None, mirror_root())
+    }
+
+    /// The subtree of mirror `name`, created on first use.
+    pub fn open(name: &str) -> anyhow::Result<MirrorCtx> {
+        let ret = MirrorCtx::configured(name)?;
+        std::fs::create_dir_all(&ret.dir)
+            .with_context(|| format!("failed to create {}", ret.dir.display()))?;
+        Ok(ret)
+    }
+
+    /// The subtree of mirror `name`, which need not exist yet.
+    fn configured(name: &str) -> anyhow::Result<MirrorCtx> {
+        if MIRRORS.get().is_none() {
+            return Err(anyhow::format_err!(
+                "no mirrors are configured in {}",
+                paths().mirrors
+            ));
+        }
+        let mirror = find_mirror(name).ok_or_else(|| {
+            anyhow::format_err!("mirror {} is not configured in {}", name, paths().mirrors)
+        })?;
+        let dir = mirror_root().join(name);
+        Ok(MirrorCtx::new(Some(name.to_string()), Some(mirror), dir))
+    }
+
+    fn new(name: Option<String>, mirror: Option<Mirror>, dir: PathBuf) -> MirrorCtx {
+        MirrorCtx {
+            name,
+            mirror,
+            paths: paths().below(&dir),
+            dir,
+            link_mode: OnceLock::new(),
+        }
+    }
+
+    /// Name of the mirror, none for the mirror root.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Name of the tree in metrics and in the status API, `default` for the
+    /// mirror root.
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    /// Configuration of the mirror in the mirrors file, if it comes from one.
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self.mirror.as_ref()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn paths(&self) -> &Paths {
+        &self.paths
+    }
+
+    /// The file `rel` of the tree, such as `dists/bookworm/Release`.
+    pub fn path(&self, rel: &str) -> String {
+        self.dir.join(rel).to_string_lossy().to_string()
+    }
+
+    /// The link mode of the tree's link mode file, read on first use.
+    pub(crate) fn link_mode(&self) -> LinkMode {
+        *self
+            .link_mode
+            .get_or_init(|| read_link_mode(&self.paths.link_mode))
+    }
+}
+
+/// Every tree sharing the store: the subtree of each configured mirror, or
+/// the mirror root without a mirrors file. Subtrees are not created, one
+/// never synced simply has nothing in it.
+pub(crate) fn all_trees() -> anyhow::Result<Vec<MirrorCtx>> {
+    let names = mirror_names();
+    if names.is_empty() {
+        return Ok(vec![MirrorCtx::root()]);
+    }
+    names.iter().map(|x| MirrorCtx::configured(x)).collect()
+}
+
+/// Turns the paths of the store and its bookkeeping, which every mirror
+/// shares, into absolute paths below the mirror root.
+pub fn shared_path(path: &str) -> String {
+    mirror_root().join(path).to_string_lossy().to_string()
+}
+
+/// Writes the url and dists lists of the mirror tree `ctx`, the counterpart of
+/// make_config for a mirrors file.
+pub(crate) async fn write_mirror_lists(ctx: &MirrorCtx, mirror: &Mirror) -> anyhow::Result<()> {
+    let mut urls = String::new();
+    for x in mirror.urls.iter() {
+        urls.push_str(x.trim_end_matches('/'));
+        urls.push('\n');
+    }
+    tokio::fs::write(&ctx.paths().url_mirrors, urls)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().url_mirrors))?;
+    let mut arch_urls = String::new();
+    for (arch, urls) in mirror.arch_urls.iter() {
+        for x in urls.iter() {
+            arch_urls.push_str(&format!("{} {}\n", arch, x.trim_end_matches('/')));
+        }
+    }
+    tokio::fs::write(&ctx.paths().arch_mirrors, arch_urls)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().arch_mirrors))?;
+
+    let mut dists = String::new();
+    for suite in mirror.suites.iter() {
+        dists.push_str(&format!("dists/{}/Release\n", suite));
+        if mirror.keyring.is_some() {
+            dists.push_str(&format!("dists/{}/Release.gpg\n", suite));
+        }
+        for component in mirror.components.iter() {
+            let mut indices = Vec::new();
+            if mirror.sources {
+                indices.push(format!("dists/{}/{}/source/Sources", suite, component));
+            }
+            for arch in mirror.architectures.iter() {
+                indices.push(format!(
+                    "dists/{}/{}/binary-{}/Packages",
+                    suite, component, arch
+                ));
+            }
+            for x in indices {
+                dists.push_str(&format!("{}\n{}.gz\n{}.xz\n", x, x, x));
+            }
+        }
+    }
+    tokio::fs::write(&ctx.paths().dist_packages, dists)
+        .await
+        .with_context(|| format!("failed to write {}", ctx.paths().dist_packages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_names_stay_apart_from_the_root() {
+        let paths = Paths::default();
+        for x in ["debian", "ubuntu-ports", "SHA256-old", "project-x"] {
+            assert!(check_mirror_name(x, &paths).is_ok(), "{:?}", x);
+        }
+        for x in [
+            "",
+            ".hidden",
+            "a/b",
+            "SHA256",
+            "TMP",
+            "WASTE",
+            "QUARANTINE",
+            "snapshots",
+            "current",
+            "mirrors.toml",
+            "sync.lock",
+            "project",
+        ] {
+            assert!(check_mirror_name(x, &paths).is_err(), "{:?}", x);
+        }
+        // A store kept elsewhere leaves its name free.
+        let paths = Paths {
+            store: String::from("/srv/store/SHA256"),
+            ..Paths::default()
+        };
+        assert!(check_mirror_name("SHA256", &paths).is_ok());
+    }
+
+    fn check(text: &str) -> anyhow::Result<()> {
+        let file: MirrorsFile = toml::from_str(text)?;
+        check_mirrors(&file, &Paths::default())
+    }
+
+    #[test]
+    fn architectures_need_their_own_urls() {
+        let base = "[mirror.ubuntu]\nurls = [\"http://a\"]\nsuites = [\"noble\"]\n";
+        assert!(check(base).is_ok());
+        assert!(check(&format!(
+            "{}architectures = [\"amd64\", \"arm64\"]\narch_urls = {{ arm64 = [\"http://b\"] }}\n",
+            base
+        ))
+        .is_ok());
+        // arm64 is not among the default architectures.
+        assert!(check(&format!(
+            "{}arch_urls = {{ arm64 = [\"http://b\"] }}\n",
+            base
+        ))
+        .is_err());
+        assert!(check(&format!(
+            "{}architectures = [\"arm64\"]\narch_urls = {{ arm64 = [] }}\n",
+            base
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn mirrors_need_urls_suites_and_a_valid_schedule() {
+        assert!(check("[mirror.a]\nurls = []\nsuites = [\"x\"]\n").is_err());
+        assert!(check("[mirror.a]\nurls = [\"http://a\"]\nsuites = []\n").is_err());
+        assert!(check("[mirror.a]\nsuites = [\"x\"]\n").is_err());
+        assert!(
+            check("[mirror.a]\nurls = [\"http://a\"]\nsuites = [\"x\"]\nschedule = \"6h\"\n")
+                .is_ok()
+        );
+        assert!(check(
+            "[mirror.a]\nurls = [\"http://a\"]\nsuites = [\"x\"]\nschedule = \"often\"\n"
+        )
+        .is_err());
+        assert!(check("[mirror.SHA256]\nurls = [\"http://a\"]\nsuites = [\"x\"]\n").is_err());
+    }
+}
@@ -1,22 +1,58 @@
-use std::process::Command;
+use clap::Parser;
+use deb_mirror::logging::init_logging;
+use deb_mirror::logging::LogOptions;
+use deb_mirror::replicate::copy_tree;
+use deb_mirror::replicate::destination;
+use deb_mirror::replicate::replicate_mirror;
+use deb_mirror::replicate::CopyOptions;
+use tracing::error;
 
-fn mysync(mut args: Vec<String>) {
-    args[0] = String::from("--progress");
-    args.push(String::from("-avh"));
+/// Copy a directory tree, such as a mirror, to another disk or server
+///
+/// As with rsync, a SOURCE ending in a slash has its contents copied into
+/// DEST, any other is copied below DEST under its own name. With --mirror,
+/// SOURCE is a deb_mirror root and DEST becomes its replica.
+#[derive(Parser)]
+#[command(name = "mysync", version)]
+struct Cli {
+    #[command(flatten)]
+    log: LogOptions,
 
-    Command::new("rsync")
-        .args(args)
-        .spawn()
-        .unwrap()
-        .wait()
-        .unwrap();
+    #[command(flatten)]
+    copy: CopyOptions,
 
-    Command::new("sync").spawn().unwrap().wait().unwrap();
+    /// Replicate a deb_mirror root: the blobs the replica lacks, then the
+    /// published generation of each mirror, then its current link
+    #[arg(long)]
+    mirror: bool,
 
-    Command::new("sync").spawn().unwrap().wait().unwrap();
+    source: std::path::PathBuf,
+
+    dest: std::path::PathBuf,
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    mysync(args);
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let log = match init_logging(&cli.log) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let result = if cli.mirror {
+        replicate_mirror(&cli.source, &cli.dest, &cli.copy)
+            .await
+            .map(|_| ())
+    } else {
+        let dest = destination(&cli.source, &cli.dest);
+        copy_tree(&cli.source, &dest, &cli.copy).await.map(|_| ())
+    };
+    if let Err(e) = result {
+        error!("{:#}", e);
+        // Exiting skips destructors, flush the log first.
+        drop(log);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,231 @@
+use anyhow::Context;
+use std::cmp::Ordering;
+
+/// One paragraph of a Packages index, keeping every field in its original
+/// order so the stanza can be written back unchanged.
+#[derive(Debug, Clone)]
+pub struct PackageStanza {
+    pub fields: Vec<(String, String)>,
+}
+
+impl PackageStanza {
+    /// Value of the first field called `name`, without the leading space.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn package(&self) -> &str {
+        self.get("Package").unwrap_or("")
+    }
+
+    pub fn version(&self) -> &str {
+        self.get("Version").unwrap_or("")
+    }
+
+    pub fn architecture(&self) -> &str {
+        self.get("Architecture").unwrap_or("")
+    }
+
+    pub fn sha256(&self) -> &str {
+        self.get("SHA256").unwrap_or("")
+    }
+
+    /// The stanza in deb822 form, terminated by a blank line.
+    pub fn to_text(&self) -> String {
+        let mut ret = String::new();
+        for (k, v) in self.fields.iter() {
+            ret.push_str(k);
+            ret.push(':');
+            if !v.is_empty() && !v.starts_with('\n') {
+                ret.push(' ');
+            }
+            ret.push_str(v);
+            ret.push('\n');
+        }
+        ret.push('\n');
+        ret
+    }
+}
+
+/// Splits the text of a Packages file into stanzas. Continuation lines are
+/// kept verbatim as part of the preceding field's value.
+pub fn parse_stanzas(text: &str) -> Vec<PackageStanza> {
+    let mut ret = Vec::new();
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in text.split('\n') {
+        if line.trim().is_empty() {
+            if !fields.is_empty() {
+                ret.push(PackageStanza {
+                    fields: std::mem::take(&mut fields),
+                });
+            }
+        } else if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, v)) = fields.last_mut() {
+                v.push('\n');
+                v.push_str(line);
+            }
+        } else if let Some((k, v)) = line.split_once(':') {
+            fields.push((k.to_string(), v.trim_start().to_string()));
+        }
+    }
+    if !fields.is_empty() {
+        ret.push(PackageStanza { fields });
+    }
+    ret
+}
+
+pub async fn read_stanzas(file: &str) -> anyhow::Result<Vec<PackageStanza>> {
+    let text = tokio::fs::read_to_string(file)
+        .await
+        .with_context(|| format!("failed to read the Packages file {}", file))?;
+    Ok(parse_stanzas(&text))
+}
+
+fn order(c: u8) -> i32 {
+    if c.is_ascii_alphabetic() {
+        c as i32
+    } else if c == b'~' {
+        -1
+    } else {
+        c as i32 + 256
+    }
+}
+
+/// Compares a non-epoch part of a version the way dpkg does: alternating
+/// runs of non-digits (letters before symbols, `~` before everything) and
+/// numbers.
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !a[i].is_ascii_digit()) || (j < b.len() && !b[j].is_ascii_digit()) {
+            let x = if i < a.len() && !a[i].is_ascii_digit() {
+                order(a[i])
+            } else {
+                0
+            };
+            let y = if j < b.len() && !b[j].is_ascii_digit() {
+                order(b[j])
+            } else {
+                0
+            };
+            if x != y {
+                return x.cmp(&y);
+            }
+            i += 1;
+            j += 1;
+        }
+        while i < a.len() && a[i] == b'0' {
+            i += 1;
+        }
+        while j < b.len() && b[j] == b'0' {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while i < a.len() && a[i].is_ascii_digit() && j < b.len() && b[j].is_ascii_digit() {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if i < a.len() && a[i].is_ascii_digit() {
+            return Ordering::Greater;
+        }
+        if j < b.len() && b[j].is_ascii_digit() {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+fn split_version(v: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match v.split_once(':') {
+        Some((e, r)) => (e.parse().unwrap_or(0), r),
+        None => (0, v),
+    };
+    match rest.rfind('-') {
+        Some(i) => (epoch, &rest[..i], &rest[i + 1..]),
+        None => (epoch, rest, ""),
+    }
+}
+
+/// Orders two Debian version strings as dpkg --compare-versions would.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (ea, ua, ra) = split_version(a);
+    let (eb, ub, rb) = split_version(b);
+    ea.cmp(&eb)
+        .then_with(|| compare_part(ua, ub))
+        .then_with(|| compare_part(ra, rb))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_order_like_dpkg() {
+        let ordered = [
+            "0.9",
+            "1.0~rc1",
+            "1.0",
+            "1.0-1",
+            "1.0-1ubuntu1",
+            "1.0-2",
+            "1.0a",
+            "1.0+b1",
+            "1.2",
+            "1.10",
+            "1:0.1",
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(
+                compare_versions(pair[0], pair[1]),
+                Ordering::Less,
+                "{:?}",
+                pair
+            );
+            assert_eq!(
+                compare_versions(pair[1], pair[0]),
+                Ordering::Greater,
+                "{:?}",
+                pair
+            );
+        }
+    }
+
+    #[test]
+    fn versions_ignore_leading_zeros() {
+        assert_eq!(compare_versions("1.01", "1.1"), Ordering::Equal);
+        assert_eq!(compare_versions("0:1.0-1", "1.0-1"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0~~", "1.0~"), Ordering::Less);
+    }
+
+    #[test]
+    fn stanzas_keep_fields_and_continuations() {
+        let text = "Package: hello\nVersion: 1.0\nDescription: greets\n the world\n .\n twice\n\n\nPackage: foo\nDepends:\n bar\n";
+        let stanzas = parse_stanzas(text);
+        assert_eq!(stanzas.len(), 2);
+        assert_eq!(stanzas[0].package(), "hello");
+        assert_eq!(stanzas[0].version(), "1.0");
+        assert_eq!(
+            stanzas[0].get("description"),
+            Some("greets\n the world\n .\n twice")
+        );
+        assert_eq!(stanzas[1].get("Depends"), Some("\n bar"));
+        assert_eq!(stanzas[1].sha256(), "");
+    }
+
+    #[test]
+    fn stanzas_write_back_unchanged() {
+        let text = "Package: hello\nVersion: 1.0\nDescription: greets\n the world\n\nPackage: foo\nDepends:\n bar\n\n";
+        let written: String = parse_stanzas(text).iter().map(|x| x.to_text()).collect();
+        assert_eq!(written, text);
+    }
+}
@@ -0,0 +1,230 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Default of every path, shared by the command line and Paths::default.
+const DEFAULT_CONFIG: &str = "sources.list";
+const DEFAULT_MIRRORS: &str = "mirrors.toml";
+const DEFAULT_STORE: &str = "SHA256";
+const DEFAULT_TMP: &str = "TMP";
+const DEFAULT_WASTE: &str = "WASTE";
+const DEFAULT_QUARANTINE: &str = "QUARANTINE";
+const DEFAULT_SNAPSHOTS: &str = "snapshots";
+const DEFAULT_CURRENT: &str = "current";
+const DEFAULT_URL_MIRRORS: &str = "list.url_mirrors.txt";
+const DEFAULT_ARCH_MIRRORS: &str = "list.arch_mirrors.txt";
+const DEFAULT_FRESH_MIRRORS: &str = "list.fresh_mirrors.txt";
+const DEFAULT_DIST_PACKAGES: &str = "list.dist_packages.txt";
+const DEFAULT_PINS: &str = "list.pins.txt";
+const DEFAULT_LINK_MODE: &str = "link_mode.txt";
+const DEFAULT_GC_STATE: &str = "list.gc_unreferenced.txt";
+const DEFAULT_RETENTION_STATE: &str = "list.retention_seen.txt";
+const DEFAULT_RETAINED: &str = "retained";
+const DEFAULT_LOCK: &str = "sync.lock";
+const DEFAULT_SYNC_STATE: &str = "sync.state";
+const DEFAULT_TRACE: &str = "project/trace";
+
+/// Locations of every working file and directory. Relative paths are taken
+/// from the mirror root, which is the working directory once `--root` has
+/// been applied; those of a single mirror's tree from that tree, see
+/// MirrorCtx.
+#[derive(Debug, Clone, clap::Args)]
+pub struct Paths {
+    /// Mirror configuration read by the config command
+    #[arg(long, global = true, default_value = DEFAULT_CONFIG)]
+    pub config: String,
+
+    /// Mirrors file naming every mirror synced below the root
+    #[arg(long, global = true, default_value = DEFAULT_MIRRORS)]
+    pub mirrors: String,
+
+    /// Content addressed store holding every blob by its SHA256
+    #[arg(long, global = true, default_value = DEFAULT_STORE)]
+    pub store: String,
+
+    /// Scratch directory for downloads in progress
+    #[arg(long, global = true, default_value = DEFAULT_TMP)]
+    pub tmp: String,
+
+    /// Directory the store is moved to by hand before the clean command
+    #[arg(long, global = true, default_value = DEFAULT_WASTE)]
+    pub waste: String,
+
+    /// Where verify moves blobs whose content does not match their name
+    #[arg(long, global = true, default_value = DEFAULT_QUARANTINE)]
+    pub quarantine: String,
+
+    /// Directory holding the published generations
+    #[arg(long, global = true, default_value = DEFAULT_SNAPSHOTS)]
+    pub snapshots: String,
+
+    /// Symlink to the generation clients are served from
+    #[arg(long, global = true, default_value = DEFAULT_CURRENT)]
+    pub current: String,
+
+    /// List of upstream mirror URLs written by the config command
+    #[arg(long, global = true, default_value = DEFAULT_URL_MIRRORS)]
+    pub url_mirrors: String,
+
+    /// Upstream URLs of single architectures, `ARCH URL` per line, written
+    /// by the config command
+    #[arg(long, global = true, default_value = DEFAULT_ARCH_MIRRORS)]
+    pub arch_mirrors: String,
+
+    /// Upstream URLs serving the Release files dist chose, tried first for
+    /// pool files
+    #[arg(long, global = true, default_value = DEFAULT_FRESH_MIRRORS)]
+    pub fresh_mirrors: String,
+
+    /// List of dists files written by the config command
+    #[arg(long, global = true, default_value = DEFAULT_DIST_PACKAGES)]
+    pub dist_packages: String,
+
+    /// Packages held at a validated version when publishing
+    #[arg(long, global = true, default_value = DEFAULT_PINS)]
+    pub pins: String,
+
+    /// File naming the link mode used for pool files: symlink, hardlink,
+    /// reflink or copy. Every generation holds its own pool, so the copy
+    /// and reflink modes take the room of a full pool per generation, less
+    /// the unchanged files a publish hardlinks from the generation before
+    #[arg(long, global = true, default_value = DEFAULT_LINK_MODE)]
+    pub link_mode: String,
+
+    /// When each unreferenced blob was first seen by gc
+    #[arg(long, global = true, default_value = DEFAULT_GC_STATE)]
+    pub gc_state: String,
+
+    /// When each published package version was first seen
+    #[arg(long, global = true, default_value = DEFAULT_RETENTION_STATE)]
+    pub retention_state: String,
+
+    /// Directory of the older versions retention and pins keep published,
+    /// one Packages file per index, so they outlive their generations
+    #[arg(long, global = true, default_value = DEFAULT_RETAINED)]
+    pub retained: String,
+
+    /// Lock file held while a sync runs
+    #[arg(long, global = true, default_value = DEFAULT_LOCK)]
+    pub lock: String,
+
+    /// Last finished stage of an interrupted sync
+    #[arg(long, global = true, default_value = DEFAULT_SYNC_STATE)]
+    pub sync_state: String,
+
+    /// Directory of the ftpsync style trace files, served as project/trace
+    #[arg(long, global = true, default_value = DEFAULT_TRACE)]
+    pub trace: String,
+}
+
+impl Default for Paths {
+    fn default() -> Self {
+        Paths {
+            config: String::from(DEFAULT_CONFIG),
+            mirrors: String::from(DEFAULT_MIRRORS),
+            store: String::from(DEFAULT_STORE),
+            tmp: String::from(DEFAULT_TMP),
+            waste: String::from(DEFAULT_WASTE),
+            quarantine: String::from(DEFAULT_QUARANTINE),
+            snapshots: String::from(DEFAULT_SNAPSHOTS),
+            current: String::from(DEFAULT_CURRENT),
+            url_mirrors: String::from(DEFAULT_URL_MIRRORS),
+            arch_mirrors: String::from(DEFAULT_ARCH_MIRRORS),
+            fresh_mirrors: String::from(DEFAULT_FRESH_MIRRORS),
+            dist_packages: String::from(DEFAULT_DIST_PACKAGES),
+            pins: String::from(DEFAULT_PINS),
+            link_mode: String::from(DEFAULT_LINK_MODE),
+            gc_state: String::from(DEFAULT_GC_STATE),
+            retention_state: String::from(DEFAULT_RETENTION_STATE),
+            retained: String::from(DEFAULT_RETAINED),
+            lock: String::from(DEFAULT_LOCK),
+            sync_state: String::from(DEFAULT_SYNC_STATE),
+            trace: String::from(DEFAULT_TRACE),
+        }
+    }
+}
+
+impl Paths {
+    /// Every path, in the order they are declared.
+    pub(crate) fn all(&self) -> [&str; 20] {
+        [
+            &self.config,
+            &self.mirrors,
+            &self.store,
+            &self.tmp,
+            &self.waste,
+            &self.quarantine,
+            &self.snapshots,
+            &self.current,
+            &self.url_mirrors,
+            &self.arch_mirrors,
+            &self.fresh_mirrors,
+            &self.dist_packages,
+            &self.pins,
+            &self.link_mode,
+            &self.gc_state,
+            &self.retention_state,
+            &self.retained,
+            &self.lock,
+            &self.sync_state,
+            &self.trace,
+        ]
+    }
+
+    /// The paths of a mirror tree at `dir`: the files of the tree taken from
+    /// `dir`, those shared by every mirror as they are.
+    pub(crate) fn below(&self, dir: &Path) -> Paths {
+        let join = |x: &String| dir.join(x).to_string_lossy().to_string();
+        Paths {
+            config: join(&self.config),
+            mirrors: self.mirrors.clone(),
+            store: self.store.clone(),
+            tmp: join(&self.tmp),
+            waste: self.waste.clone(),
+            quarantine: self.quarantine.clone(),
+            snapshots: join(&self.snapshots),
+            current: join(&self.current),
+            url_mirrors: join(&self.url_mirrors),
+            arch_mirrors: join(&self.arch_mirrors),
+            fresh_mirrors: join(&self.fresh_mirrors),
+            dist_packages: join(&self.dist_packages),
+            pins: join(&self.pins),
+            link_mode: join(&self.link_mode),
+            gc_state: self.gc_state.clone(),
+            retention_state: join(&self.retention_state),
+            retained: join(&self.retained),
+            lock: join(&self.lock),
+            sync_state: join(&self.sync_state),
+            trace: join(&self.trace),
+        }
+    }
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+/// Installs the paths chosen on the command line. Must be called before any
+/// command runs; later calls are ignored.
+pub fn set_paths(paths: Paths) {
+    let _ = PATHS.set(paths);
+}
+
+pub fn paths() -> &'static Paths {
+    PATHS.get_or_init(Paths::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        paths: Paths,
+    }
+
+    #[test]
+    fn command_line_defaults_are_the_defaults() {
+        let cli = Cli::parse_from(["deb_mirror"]);
+        assert_eq!(cli.paths.all(), Paths::default().all());
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Context;
+
+use crate::mirrors::MirrorCtx;
+use crate::packages::PackageStanza;
+use crate::snapshot::IndexHistory;
+use crate::store::blob_path;
+use tracing::info;
+use tracing::warn;
+
+#[derive(Debug)]
+pub(crate) struct Pin {
+    pattern: String,
+    version: Option<String>,
+}
+
+pub(crate) async fn read_pins(ctx: &MirrorCtx) -> anyhow::Result<Vec<Pin>> {
+    let content = match tokio::fs::read_to_string(&ctx.paths().pins).await {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", &ctx.paths().pins)),
+    };
+    let mut ret = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+        if let Some(pattern) = fields.next() {
+            ret.push(Pin {
+                pattern: pattern.to_string(),
+                version: fields.next().map(|x| x.to_string()),
+            });
+        }
+    }
+    Ok(ret)
+}
+
+/// Shell style matching with `*` for any run of characters and `?` for one.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n) = (pattern.as_bytes(), name.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while j < n.len() {
+        if i < p.len() && (p[i] == b'?' || p[i] == n[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == b'*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    while i < p.len() && p[i] == b'*' {
+        i += 1;
+    }
+    i == p.len()
+}
+
+/// Replaces the upstream stanzas of every pinned package with the held
+/// version from an older generation, provided its blob is still in the
+/// store. Returns the stanzas to publish and whether anything changed.
+pub(crate) async fn apply_pins(
+    pins: &[Pin],
+    index: &str,
+    history: &IndexHistory,
+    stanzas: Vec<PackageStanza>,
+) -> anyhow::Result<(Vec<PackageStanza>, bool)> {
+    if pins.is_empty() {
+        return Ok((stanzas, false));
+    }
+    let is_pinned = |package: &str| pins.iter().find(|x| glob_match(&x.pattern, package));
+
+    let mut names: Vec<String> = Vec::new();
+    for package in stanzas
+        .iter()
+        .map(|x| x.package())
+        .chain(history.packages())
+    {
+        if is_pinned(package).is_some() && !names.iter().any(|x| x == package) {
+            names.push(package.to_string());
+        }
+    }
+
+    let mut held: Vec<PackageStanza> = Vec::new();
+    for package in names.iter() {
+        let pin = is_pinned(package).unwrap();
+        let version = match pin.version.as_deref().or(history.current_version(package)) {
+            Some(o) => o.to_string(),
+            None => continue,
+        };
+        if stanzas
+            .iter()
+            .any(|x| x.package() == package && x.version() == version)
+        {
+            let mut upstream: Vec<PackageStanza> = stanzas
+                .iter()
+                .filter(|x| x.package() == package && x.version() == version)
+                .cloned()
+                .collect();
+            held.append(&mut upstream);
+            continue;
+        }
+        match history.find(package, &version) {
+            Some(stanza) => {
+                if tokio::fs::try_exists(blob_path(stanza.sha256())).await? {
+                    info!(package = %package, version = %version, index, "holding pinned package");
+                    held.push(stanza.clone());
+                } else {
+                    warn!(
+                        package = %package,
+                        version = %version,
+                        index,
+                        "pinned version is missing from the store, serving upstream"
+                    );
+                }
+            }
+            None => {
+                warn!(
+                    package = %package,
+                    version = %version,
+                    index,
+                    "pinned version has vanished from every retained snapshot"
+                );
+            }
+        }
+    }
+
+    let held_names: Vec<&str> = held.iter().map(|x| x.package()).collect();
+    let mut changed = false;
+    let mut ret: Vec<PackageStanza> = Vec::new();
+    for stanza in stanzas.into_iter() {
+        if !held_names.contains(&stanza.package()) {
+            ret.push(stanza);
+            continue;
+        }
+        let keep = held
+            .iter()
+            .any(|x| x.package() == stanza.package() && x.version() == stanza.version());
+        if keep {
+            ret.push(stanza);
+        } else {
+            changed = true;
+        }
+    }
+    for stanza in held.into_iter() {
+        if !ret
+            .iter()
+            .any(|x| x.package() == stanza.package() && x.version() == stanza.version())
+        {
+            ret.push(stanza);
+            changed = true;
+        }
+    }
+
+    Ok((ret, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_like_the_shell() {
+        assert!(glob_match("hello", "hello"));
+        assert!(!glob_match("hello", "hello2"));
+        assert!(glob_match("lib*", "libc6"));
+        assert!(glob_match("*-dev", "libc6-dev"));
+        assert!(!glob_match("*-dev", "libc6-dbg"));
+        assert!(glob_match("lib?6", "libc6"));
+        assert!(!glob_match("lib?6", "lib6"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxbxxa"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("?", ""));
+    }
+}
@@ -0,0 +1,227 @@
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::info;
+
+/// How often the progress line is redrawn on a terminal.
+const DRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often a summary line is printed when stdout is not a terminal.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Counters of a pool download, shared by every download task.
+pub(crate) struct Progress {
+    files_total: u64,
+    bytes_total: u64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    /// Bytes actually fetched, as opposed to found in the store already.
+    bytes_downloaded: AtomicU64,
+    failures: AtomicU64,
+    active: Vec<(String, AtomicU64)>,
+    started: Instant,
+}
+
+impl Progress {
+    pub(crate) fn new(files_total: u64, bytes_total: u64, mirrors: &[&str]) -> Progress {
+        Progress {
+            files_total,
+            bytes_total,
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+            bytes_downloaded: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            active: mirrors
+                .iter()
+                .map(|x| (x.to_string(), AtomicU64::new(0)))
+                .collect(),
+            started: Instant::now(),
+        }
+    }
+
+    fn active_for(&self, mirror: &str) -> Option<&AtomicU64> {
+        self.active
+            .iter()
+            .find(|(k, _)| k == mirror)
+            .map(|(_, v)| v)
+    }
+
+    /// Marks a download from `mirror` as running.
+    pub(crate) fn begin(&self, mirror: &str) {
+        if let Some(x) = self.active_for(mirror) {
+            x.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn end(&self, mirror: &str) {
+        if let Some(x) = self.active_for(mirror) {
+            x.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a finished file, `downloaded` telling whether it was fetched
+    /// or already in the store.
+    pub(crate) fn done(&self, size: u64, downloaded: bool) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(size, Ordering::Relaxed);
+        if downloaded {
+            self.bytes_downloaded.fetch_add(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a file that failed on every mirror.
+    pub(crate) fn failed(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn status(&self, rate: f64) -> String {
+        let files_done = self.files_done.load(Ordering::Relaxed);
+        let bytes_done = self.bytes_done.load(Ordering::Relaxed);
+        let failures = self.failures.load(Ordering::Relaxed);
+        let remaining = self.bytes_total.saturating_sub(bytes_done);
+        let eta = if remaining == 0 {
+            String::from("0s")
+        } else if rate > 0.0 {
+            format_duration(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            String::from("?")
+        };
+        let active: Vec<String> = self
+            .active
+            .iter()
+            .map(|(k, v)| format!("{} {}", host_of(k), v.load(Ordering::Relaxed)))
+            .collect();
+        format!(
+            "files {}/{}  {}/{}  {}/s  ETA {}  active: {}  failed {}",
+            files_done,
+            self.files_total,
+            format_bytes(bytes_done),
+            format_bytes(self.bytes_total),
+            format_bytes(rate as u64),
+            eta,
+            active.join(", "),
+            failures
+        )
+    }
+}
+
+/// Draws the progress of a download until it is finished.
+pub(crate) struct Reporter {
+    progress: Arc<Progress>,
+    task: tokio::task::JoinHandle<()>,
+    tty: bool,
+}
+
+impl Reporter {
+    /// Starts redrawing a progress line on stdout, or printing a summary
+    /// line now and then when stdout is not a terminal, so cron and daemon
+    /// runs show progress without -v.
+    pub(crate) fn start(progress: Arc<Progress>) -> Reporter {
+        let tty = std::io::stdout().is_terminal();
+        let shared = Arc::clone(&progress);
+        let task = tokio::spawn(async move {
+            let interval = if tty { DRAW_INTERVAL } else { SUMMARY_INTERVAL };
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            let mut last_bytes = 0;
+            let mut last_time = Instant::now();
+            let mut rate: f64 = 0.0;
+            loop {
+                ticker.tick().await;
+                let bytes = shared.bytes_downloaded.load(Ordering::Relaxed);
+                let now = Instant::now();
+                let current = (bytes - last_bytes) as f64 / (now - last_time).as_secs_f64();
+                // Smoothed, so the ETA does not jump with every file.
+                rate = if rate == 0.0 {
+                    current
+                } else {
+                    0.7 * rate + 0.3 * current
+                };
+                last_bytes = bytes;
+                last_time = now;
+                if tty {
+                    let mut out = std::io::stdout().lock();
+                    let _ = write!(out, "\r\x1b[K{}", shared.status(rate));
+                    let _ = out.flush();
+                } else {
+                    println!("{}", shared.status(rate));
+                }
+            }
+        });
+        Reporter {
+            progress,
+            task,
+            tty,
+        }
+    }
+
+    /// Stops redrawing, prints the totals and logs them.
+    pub(crate) async fn finish(self) {
+        self.task.abort();
+        let _ = self.task.await;
+        if self.tty {
+            println!();
+        }
+        let elapsed = self.progress.started.elapsed();
+        let downloaded = self.progress.bytes_downloaded.load(Ordering::Relaxed);
+        let rate = (downloaded as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        println!(
+            "downloaded {} at {}/s, {}/{} files, {} failed, {}",
+            format_bytes(downloaded),
+            format_bytes(rate),
+            self.progress.files_done.load(Ordering::Relaxed),
+            self.progress.files_total,
+            self.progress.failures.load(Ordering::Relaxed),
+            format_duration(elapsed)
+        );
+        info!(
+            files = self.progress.files_done.load(Ordering::Relaxed),
+            total = self.progress.files_total,
+            downloaded_bytes = downloaded,
+            failed = self.progress.failures.load(Ordering::Relaxed),
+            seconds = elapsed.as_secs(),
+            "downloaded {} at {}/s",
+            format_bytes(downloaded),
+            format_bytes(rate)
+        );
+    }
+}
+
+fn host_of(url: &str) -> &str {
+    let rest = url.split_once("://").map(|x| x.1).unwrap_or(url);
+    rest.split('/').next().unwrap_or(rest)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
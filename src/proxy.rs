@@ -0,0 +1,749 @@
+use anyhow::Context;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+
+use crate::download_dist::sha256_digest;
+use crate::http;
+use crate::http::Request;
+use crate::http::Response;
+use crate::mirrors::find_mirror;
+use crate::mirrors::mirror_names;
+use crate::mirrors::MirrorCtx;
+use crate::packages::parse_stanzas;
+use crate::paths::paths;
+use crate::release::decompress;
+use crate::release::verify_signature;
+use crate::release::ReleaseFile;
+use crate::schedule::parse_duration;
+use crate::serve::file_response;
+use crate::serve::AccessLog;
+use crate::store::blob_path;
+use crate::store::lock_store;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// The port apt-cacher-ng listens on, which clients are often set up for.
+const DEFAULT_LISTEN: &str = "0.0.0.0:3142";
+const DEFAULT_INDEX_MAX_AGE: &str = "30m";
+
+/// Files of a suite fetched together, so they always match each other.
+const RELEASE_FILES: [&str; 3] = ["InRelease", "Release", "Release.gpg"];
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ProxyOptions {
+    /// Address apt clients connect to
+    #[arg(long, default_value = DEFAULT_LISTEN)]
+    pub listen: String,
+
+    /// Age after which the Release files of a suite are fetched again, such
+    /// as 30m or 2h
+    #[arg(long, default_value = DEFAULT_INDEX_MAX_AGE, value_parser = parse_duration)]
+    pub index_max_age: Duration,
+
+    /// Append requests to this file in the combined log format, instead of
+    /// logging them
+    #[arg(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+}
+
+/// A file of the pool as listed in a Packages or Sources index.
+#[derive(Debug, Clone)]
+struct PoolEntry {
+    sha256: String,
+    /// 0 when the index does not list one.
+    size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IndexKind {
+    Packages,
+    Sources,
+}
+
+/// One proxied mirror, caching its dists below `dir`.
+struct Tree {
+    /// URL prefix, empty for the single tree served at the top.
+    name: String,
+    dir: PathBuf,
+    urls: Vec<String>,
+    keyring: Option<String>,
+    /// Every pool file of the indices cached so far, by path.
+    pool: RwLock<HashMap<String, PoolEntry>>,
+    /// SHA256 of cached index files by path, valid while the modification
+    /// time is unchanged.
+    hashes: Mutex<HashMap<PathBuf, (SystemTime, String)>>,
+    /// Held while the Release files of a suite are fetched.
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+struct Proxy {
+    trees: Vec<Tree>,
+    client: reqwest::Client,
+    max_age: Duration,
+    /// Blobs being fetched, so concurrent misses download once.
+    fetching: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    access_log: AccessLog,
+}
+
+/// Tells the temporary files of concurrent writes apart.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn tmp_name(path: &Path) -> PathBuf {
+    let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}.{}.tmp", std::process::id(), n));
+    PathBuf::from(name)
+}
+
+async fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = tmp_name(path);
+    tokio::fs::write(&tmp, data)
+        .await
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("failed to move {} to {}", tmp.display(), path.display()))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(sha2::Sha256::digest(data))
+}
+
+/// Whether a path below a suite is a Packages or Sources index, compressed
+/// or not, including the by-hash copies of them.
+fn index_kind(rest: &str) -> Option<IndexKind> {
+    let name = rest.rsplit('/').next().unwrap_or(rest);
+    let by_hash = rest.contains("/by-hash/");
+    if rest.contains(".diff/") {
+        None
+    } else if name.starts_with("Packages") || (by_hash && rest.contains("/binary-")) {
+        Some(IndexKind::Packages)
+    } else if name.starts_with("Sources") || (by_hash && rest.contains("/source/")) {
+        Some(IndexKind::Sources)
+    } else {
+        None
+    }
+}
+
+/// The pool files an index lists, by path.
+fn parse_index(kind: IndexKind, text: &str) -> Vec<(String, PoolEntry)> {
+    let mut ret = Vec::new();
+    for stanza in parse_stanzas(text) {
+        match kind {
+            IndexKind::Packages => {
+                if let (Some(file), Some(sha256)) = (stanza.get("Filename"), stanza.get("SHA256")) {
+                    let size = stanza.get("Size").and_then(|x| x.parse().ok()).unwrap_or(0);
+                    ret.push((
+                        file.to_string(),
+                        PoolEntry {
+                            sha256: sha256.to_string(),
+                            size,
+                        },
+                    ));
+                }
+            }
+            IndexKind::Sources => {
+                let directory = stanza.get("Directory").unwrap_or("");
+                let checksums = stanza.get("Checksums-Sha256").unwrap_or("");
+                for line in checksums.lines() {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    if fields.len() == 3 {
+                        ret.push((
+                            format!("{}/{}", directory, fields[2]),
+                            PoolEntry {
+                                sha256: fields[0].to_string(),
+                                size: fields[1].parse().unwrap_or(0),
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    ret
+}
+
+fn age(metadata: &std::fs::Metadata) -> Duration {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.elapsed().ok())
+        .unwrap_or_default()
+}
+
+impl Tree {
+    /// Adds the pool files of an index to the lookup table.
+    fn learn(&self, kind: IndexKind, data: &[u8]) {
+        let text = match decompress(data) {
+            Ok(o) => String::from_utf8_lossy(&o).to_string(),
+            Err(e) => {
+                warn!(error = %e, "failed to decompress an index");
+                return;
+            }
+        };
+        let entries = parse_index(kind, &text);
+        let mut pool = self.pool.write().unwrap();
+        for (k, v) in entries {
+            pool.insert(k, v);
+        }
+    }
+
+    /// Reads every index already cached, so pool files requested right
+    /// after a restart are known.
+    async fn load(&self) -> anyhow::Result<()> {
+        let dists = self.dir.join("dists");
+        let mut stack = vec![dists.clone()];
+        while let Some(dir) = stack.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(o) => o,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to list {}", dir.display()))
+                }
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    stack.push(path);
+                    continue;
+                }
+                let rest = path.strip_prefix(&dists)?.to_string_lossy().to_string();
+                if let Some(kind) = index_kind(&rest) {
+                    self.learn(kind, &tokio::fs::read(&path).await?);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// SHA256 of a cached file, hashed again only after it changed.
+    async fn cached_sha256(&self, path: &Path) -> Option<String> {
+        let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        if let Some((time, sha256)) = self.hashes.lock().unwrap().get(path) {
+            if *time == modified {
+                return Some(sha256.clone());
+            }
+        }
+        let sha256 = sha256_digest(&path.to_string_lossy()).await.ok()?;
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (modified, sha256.clone()));
+        Some(sha256)
+    }
+
+    /// The Release file of a suite as cached, from InRelease when there is
+    /// no detached one.
+    async fn release(&self, suite: &str) -> Option<ReleaseFile> {
+        let dir = self.dir.join("dists").join(suite);
+        for name in ["Release", "InRelease"] {
+            if let Ok(o) = ReleaseFile::read(&dir.join(name).to_string_lossy()).await {
+                return Some(o);
+            }
+        }
+        None
+    }
+}
+
+impl Proxy {
+    /// Fetches a URL, None when the upstream does not have it.
+    async fn get(&self, url: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("failed to fetch {}", url))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response
+            .error_for_status()
+            .with_context(|| format!("failed to fetch {}", url))?;
+        let data = response
+            .bytes()
+            .await
+            .with_context(|| format!("failed to fetch {}", url))?;
+        Ok(Some(data.to_vec()))
+    }
+
+    /// Fetches the Release files of `suite` again once they are older than
+    /// the maximum age, all of them from the same upstream mirror. With a
+    /// keyring the new Release has to be signed by it.
+    async fn refresh_suite(&self, tree: &Tree, suite: &str) -> anyhow::Result<()> {
+        let dir = tree.dir.join("dists").join(suite);
+        let fresh = |path: PathBuf| async move {
+            match tokio::fs::metadata(&path).await {
+                Ok(o) => age(&o) < self.max_age,
+                Err(_) => false,
+            }
+        };
+        if fresh(dir.join("Release")).await || fresh(dir.join("InRelease")).await {
+            return Ok(());
+        }
+        let _guard = tree.refreshing.lock().await;
+        // Another request may have refreshed the suite meanwhile.
+        if fresh(dir.join("Release")).await || fresh(dir.join("InRelease")).await {
+            return Ok(());
+        }
+
+        let mut last_error = anyhow::format_err!("no upstream mirror has suite {}", suite);
+        for base in tree.urls.iter() {
+            let mut files = Vec::new();
+            let mut failed = None;
+            for name in RELEASE_FILES {
+                let url = format!("{}/dists/{}/{}", base, suite, name);
+                match self.get(&url).await {
+                    Ok(o) => files.push((name, o)),
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                }
+            }
+            if let Some(e) = failed {
+                warn!(url = %base, error = %format!("{:#}", e), "upstream failed");
+                last_error = e;
+                continue;
+            }
+            if files
+                .iter()
+                .all(|(name, data)| *name == "Release.gpg" || data.is_none())
+            {
+                continue;
+            }
+            if let Err(e) = self.check_release(tree, &dir, &files).await {
+                warn!(url = %base, suite, error = %format!("{:#}", e), "rejected upstream Release");
+                last_error = e;
+                continue;
+            }
+            for (name, data) in files {
+                let path = dir.join(name);
+                match data {
+                    Some(o) => write_atomic(&path, &o).await?,
+                    None => {
+                        let _ = tokio::fs::remove_file(&path).await;
+                    }
+                }
+            }
+            info!(mirror = %tree.name, suite, url = %base, "refreshed Release");
+            return Ok(());
+        }
+        Err(last_error)
+    }
+
+    /// Checks freshly fetched Release files before they replace the cached
+    /// ones: the detached signature against the keyring, and InRelease
+    /// against Release.
+    async fn check_release(
+        &self,
+        tree: &Tree,
+        dir: &Path,
+        files: &[(&str, Option<Vec<u8>>)],
+    ) -> anyhow::Result<()> {
+        let get = |name: &str| {
+            files
+                .iter()
+                .find(|(k, _)| *k == name)
+                .and_then(|(_, v)| v.as_deref())
+        };
+        if let (Some(inline), Some(release)) = (get("InRelease"), get("Release")) {
+            let a = ReleaseFile::parse(&String::from_utf8_lossy(inline));
+            let b = ReleaseFile::parse(&String::from_utf8_lossy(release));
+            if a.entries != b.entries {
+                return Err(anyhow::format_err!(
+                    "InRelease and Release list other files"
+                ));
+            }
+        }
+        let keyring = match &tree.keyring {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let (release, signature) = match (get("Release"), get("Release.gpg")) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Err(anyhow::format_err!("no Release and Release.gpg to verify")),
+        };
+        tokio::fs::create_dir_all(dir).await?;
+        let release_tmp = tmp_name(&dir.join("Release"));
+        let signature_tmp = tmp_name(&dir.join("Release.gpg"));
+        tokio::fs::write(&release_tmp, release).await?;
+        tokio::fs::write(&signature_tmp, signature).await?;
+        let result = verify_signature(
+            &release_tmp.to_string_lossy(),
+            &signature_tmp.to_string_lossy(),
+            keyring,
+        )
+        .await;
+        let _ = tokio::fs::remove_file(&release_tmp).await;
+        let _ = tokio::fs::remove_file(&signature_tmp).await;
+        result
+    }
+
+    /// Serves a file below dists/. Release files are refreshed by age,
+    /// indices they list are fetched whenever the cached copy does not match
+    /// the Release, anything else is cached by age.
+    async fn dists_file(
+        &self,
+        request: &Request,
+        tree: &Tree,
+        suite: &str,
+        rest: &str,
+    ) -> Response {
+        let dir = tree.dir.join("dists").join(suite);
+        let path = dir.join(rest);
+        let name = rest.rsplit('/').next().unwrap_or(rest);
+        if let Err(e) = self.refresh_suite(tree, suite).await {
+            warn!(mirror = %tree.name, suite, error = %format!("{:#}", e), "serving cached Release");
+        }
+        if RELEASE_FILES.contains(&rest) {
+            return file_response(request, &path, name).await;
+        }
+
+        let expected = if rest.contains("/by-hash/SHA256/") {
+            Some(name.to_string())
+        } else {
+            match tree.release(suite).await {
+                Some(o) => o.entry(rest).map(|x| x.sha256.clone()),
+                None => return Response::text(502, "no Release file for the suite"),
+            }
+        };
+        let cached = match &expected {
+            Some(sha256) => tree.cached_sha256(&path).await.as_ref() == Some(sha256),
+            None => match tokio::fs::metadata(&path).await {
+                Ok(o) => age(&o) < self.max_age,
+                Err(_) => false,
+            },
+        };
+        if cached {
+            return file_response(request, &path, name).await;
+        }
+
+        let mut fetched = None;
+        for base in tree.urls.iter() {
+            let url = format!("{}/dists/{}/{}", base, suite, rest);
+            match self.get(&url).await {
+                Ok(Some(o)) => {
+                    if expected.as_ref().is_some_and(|x| *x != sha256_hex(&o)) {
+                        warn!(url, "index does not match the Release file");
+                        continue;
+                    }
+                    fetched = Some(o);
+                    break;
+                }
+                Ok(None) => debug!(url, "not upstream"),
+                Err(e) => warn!(error = %format!("{:#}", e), "upstream failed"),
+            }
+        }
+        match fetched {
+            Some(data) => {
+                if let Err(e) = write_atomic(&path, &data).await {
+                    warn!(error = %format!("{:#}", e), "failed to cache an index");
+                    return Response::text(500, "failed to cache the file");
+                }
+                if let Some(kind) = index_kind(rest) {
+                    tree.learn(kind, &data);
+                }
+            }
+            // Stale beats nothing for files the Release does not cover.
+            None if expected.is_none() && path.exists() => {}
+            None => return Response::text(404, "not found"),
+        }
+        file_response(request, &path, name).await
+    }
+
+    /// Downloads a pool file from the first upstream mirror that has it
+    /// with the right content into the store.
+    async fn fetch_blob(&self, tree: &Tree, file: &str, entry: &PoolEntry) -> anyhow::Result<()> {
+        let lock = {
+            let mut fetching = self.fetching.lock().unwrap();
+            Arc::clone(fetching.entry(entry.sha256.clone()).or_default())
+        };
+        let _guard = lock.lock().await;
+        let dest = PathBuf::from(blob_path(&entry.sha256));
+        let result = if dest.exists() {
+            Ok(())
+        } else {
+            self.download_blob(tree, file, entry, &dest).await
+        };
+        self.fetching.lock().unwrap().remove(&entry.sha256);
+        result
+    }
+
+    async fn download_blob(
+        &self,
+        tree: &Tree,
+        file: &str,
+        entry: &PoolEntry,
+        dest: &Path,
+    ) -> anyhow::Result<()> {
+        let tmp_dir = tree.dir.join(&paths().tmp);
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        let tmp = tmp_name(&tmp_dir.join(&entry.sha256));
+        let mut last_error = anyhow::format_err!("no upstream mirror has {}", file);
+        for base in tree.urls.iter() {
+            let url = format!("{}/{}", base, file);
+            match self.download_to(&url, &tmp).await {
+                Ok((sha256, size))
+                    if sha256 == entry.sha256 && (entry.size == 0 || size == entry.size) =>
+                {
+                    if let Some(parent) = dest.parent() {
+                        tokio::fs::create_dir_all(parent).await?;
+                    }
+                    tokio::fs::rename(&tmp, dest).await.with_context(|| {
+                        format!("failed to move {} to {}", tmp.display(), dest.display())
+                    })?;
+                    info!(mirror = %tree.name, file, size, "cached");
+                    return Ok(());
+                }
+                Ok(_) => {
+                    warn!(url, "hash mismatch");
+                    last_error = anyhow::format_err!("{} does not match its SHA256", url);
+                }
+                Err(e) => {
+                    warn!(error = %format!("{:#}", e), "upstream failed");
+                    last_error = e;
+                }
+            }
+            let _ = tokio::fs::remove_file(&tmp).await;
+        }
+        Err(last_error)
+    }
+
+    /// Streams a URL into `path`, returning the SHA256 and size of what was
+    /// received.
+    async fn download_to(&self, url: &str, path: &Path) -> anyhow::Result<(String, u64)> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .with_context(|| format!("failed to fetch {}", url))?;
+        let mut file = tokio::fs::File::create(path)
+            .await
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        let mut hasher = sha2::Sha256::new();
+        let mut size: u64 = 0;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("failed to fetch {}", url))?
+        {
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        Ok((hex::encode(hasher.finalize()), size))
+    }
+
+    async fn pool_file(&self, request: &Request, tree: &Tree, file: &str) -> Response {
+        let entry = match tree.pool.read().unwrap().get(file) {
+            Some(o) => o.clone(),
+            None => return Response::text(404, "not in any cached index"),
+        };
+        // Held until the file is sent, so gc or migrate cannot take the
+        // blob away in between.
+        let _store = match lock_store(false).await {
+            Ok(o) => o,
+            Err(e) => {
+                warn!(error = %format!("{:#}", e), "failed to lock the store");
+                return Response::text(500, "failed to lock the store");
+            }
+        };
+        if let Err(e) = self.fetch_blob(tree, file, &entry).await {
+            warn!(mirror = %tree.name, file, error = %format!("{:#}", e), "failed to cache");
+            return Response::text(502, "failed to fetch the file upstream");
+        }
+        let name = file.rsplit('/').next().unwrap_or(file);
+        file_response(request, Path::new(&blob_path(&entry.sha256)), name).await
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Response::text(405, "method not allowed").header("Allow", "GET, HEAD");
+        }
+        let parts: Vec<&str> = request.path.split('/').filter(|x| !x.is_empty()).collect();
+        if parts.iter().any(|x| *x == "." || *x == "..") {
+            return Response::text(404, "not found");
+        }
+        let (tree, rest) = match self.trees.as_slice() {
+            [tree] if tree.name.is_empty() => (tree, &parts[..]),
+            trees => match parts
+                .first()
+                .and_then(|x| trees.iter().find(|t| t.name == *x))
+            {
+                Some(o) => (o, &parts[1..]),
+                None => return Response::text(404, "not found"),
+            },
+        };
+        match rest {
+            ["dists", suite, rest @ ..] if !rest.is_empty() => {
+                self.dists_file(request, tree, suite, &rest.join("/")).await
+            }
+            ["pool", ..] => self.pool_file(request, tree, &rest.join("/")).await,
+            _ => Response::text(404, "not found"),
+        }
+    }
+}
+
+/// Proxies the caching mirrors of the mirrors file below their names, or
+/// the selected mirror or legacy tree at the top. Dists files are cached in
+/// the mirror's subtree and checked against its Release file, pool files
+/// are fetched on the first request, checked against the cached indices
+/// and kept in the shared store.
+///
+/// The store keeps blobs the full mirrors share, while gc drops the others
+/// after its grace period, and they are fetched again when asked for.
+pub async fn proxy(selected: Option<&MirrorCtx>, options: &ProxyOptions) -> anyhow::Result<()> {
+    let root = MirrorCtx::root();
+    let mut trees = Vec::new();
+    let selected = selected.and_then(|x| x.name());
+    let names: Vec<String> = match selected {
+        Some(o) => vec![o.to_string()],
+        None => mirror_names()
+            .into_iter()
+            .filter(|x| find_mirror(x).is_some_and(|m| m.proxy))
+            .collect(),
+    };
+    if names.is_empty() && !mirror_names().is_empty() {
+        return Err(anyhow::format_err!(
+            "{} has no mirror with proxy = true",
+            paths().mirrors
+        ));
+    }
+    for name in names.iter() {
+        let mirror = find_mirror(name).unwrap();
+        let dir = root.dir().join(name);
+        trees.push(Tree {
+            name: if selected.is_some() {
+                String::new()
+            } else {
+                name.clone()
+            },
+            dir,
+            urls: mirror
+                .urls
+                .iter()
+                .map(|x| x.trim_end_matches('/').to_string())
+                .collect(),
+            keyring: mirror.keyring.clone(),
+            pool: RwLock::new(HashMap::new()),
+            hashes: Mutex::new(HashMap::new()),
+            refreshing: tokio::sync::Mutex::new(()),
+        });
+    }
+    if trees.is_empty() {
+        let path = &root.paths().url_mirrors;
+        let urls = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read {}, run config first", path))?;
+        trees.push(Tree {
+            name: String::new(),
+            dir: root.dir().to_path_buf(),
+            urls: urls
+                .lines()
+                .filter(|x| !x.is_empty())
+                .map(|x| x.trim_end_matches('/').to_string())
+                .collect(),
+            keyring: None,
+            pool: RwLock::new(HashMap::new()),
+            hashes: Mutex::new(HashMap::new()),
+            refreshing: tokio::sync::Mutex::new(()),
+        });
+    }
+    for tree in trees.iter() {
+        tree.load().await?;
+        debug!(mirror = %tree.name, files = tree.pool.read().unwrap().len(), "loaded cached indices");
+    }
+
+    let proxy = Arc::new(Proxy {
+        trees,
+        client: reqwest::Client::new(),
+        max_age: options.index_max_age,
+        fetching: Mutex::new(HashMap::new()),
+        access_log: AccessLog::open(options.access_log.as_deref())?,
+    });
+
+    let listener = tokio::net::TcpListener::bind(&options.listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", options.listen))?;
+    info!(listen = %options.listen, "proxying");
+    http::serve(listener, move |request| {
+        let proxy = Arc::clone(&proxy);
+        async move {
+            let response = proxy.respond(&request).await;
+            proxy.access_log.log(&request, &response);
+            response
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indices_are_told_apart_by_path() {
+        for (rest, kind) in [
+            ("main/binary-amd64/Packages", Some(IndexKind::Packages)),
+            ("main/binary-amd64/Packages.xz", Some(IndexKind::Packages)),
+            (
+                "main/binary-amd64/by-hash/SHA256/0123abcd",
+                Some(IndexKind::Packages),
+            ),
+            ("main/source/Sources.gz", Some(IndexKind::Sources)),
+            (
+                "main/source/by-hash/SHA256/0123abcd",
+                Some(IndexKind::Sources),
+            ),
+            ("main/binary-amd64/Packages.diff/Index", None),
+            ("main/binary-amd64/Release", None),
+            ("main/i18n/Translation-en.bz2", None),
+            ("main/Contents-amd64.gz", None),
+        ] {
+            assert_eq!(index_kind(rest), kind, "{}", rest);
+        }
+    }
+
+    #[test]
+    fn indices_list_their_pool_files() {
+        let packages = "Package: a\nFilename: pool/main/a/a_1_all.deb\nSize: 12\nSHA256: aa\n\n\
+            Package: b\nFilename: pool/main/b/b_1_all.deb\nSHA256: bb\n\n\
+            Package: c\nFilename: pool/main/c/c_1_all.deb\n";
+        let entries = parse_index(IndexKind::Packages, packages);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, "pool/main/a/a_1_all.deb");
+        assert_eq!(
+            (entries[0].1.sha256.as_str(), entries[0].1.size),
+            ("aa", 12)
+        );
+        assert_eq!((entries[1].1.sha256.as_str(), entries[1].1.size), ("bb", 0));
+
+        let sources = "Package: a\nDirectory: pool/main/a\nChecksums-Sha256:\n \
+            aa 10 a_1.dsc\n bb 20 a_1.tar.xz\n";
+        let entries = parse_index(IndexKind::Sources, sources);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].0, "pool/main/a/a_1.tar.xz");
+        assert_eq!(
+            (entries[1].1.sha256.as_str(), entries[1].1.size),
+            ("bb", 20)
+        );
+    }
+}
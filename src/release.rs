@@ -0,0 +1,504 @@
+use anyhow::Context;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::io::prelude::*;
+
+use crate::packages::parse_stanzas;
+use crate::snapshot::days_from_civil;
+use tracing::debug;
+
+/// One index listed in the SHA256 section of a Release file, with its path
+/// relative to the Release file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReleaseEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// A parsed Release or InRelease file.
+#[derive(Debug, Clone)]
+pub struct ReleaseFile {
+    /// Every field in its original order, checksum sections included.
+    pub fields: Vec<(String, String)>,
+    /// The SHA256 section.
+    pub entries: Vec<ReleaseEntry>,
+}
+
+impl ReleaseFile {
+    /// Parses a Release file, or the signed text of an InRelease file.
+    pub fn parse(text: &str) -> ReleaseFile {
+        let text = strip_clearsign(text);
+        let fields = parse_stanzas(&text)
+            .into_iter()
+            .next()
+            .map(|x| x.fields)
+            .unwrap_or_default();
+        let mut entries = Vec::new();
+        if let Some((_, v)) = fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("SHA256"))
+        {
+            for line in v.lines() {
+                let x: Vec<&str> = line.split_whitespace().collect();
+                if x.len() == 3 {
+                    if let Ok(size) = x[1].parse() {
+                        entries.push(ReleaseEntry {
+                            path: x[2].to_string(),
+                            size,
+                            sha256: x[0].to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        ReleaseFile { fields, entries }
+    }
+
+    pub async fn read(path: &str) -> anyhow::Result<ReleaseFile> {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read {}", path))?;
+        Ok(ReleaseFile::parse(&text))
+    }
+
+    /// Value of the first field called `name`, e.g. `Suite` or `Date`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn entry(&self, path: &str) -> Option<&ReleaseEntry> {
+        self.entries.iter().find(|x| x.path == path)
+    }
+
+    /// Checks `data` against the size and SHA256 listed for `path`.
+    pub fn verify(&self, path: &str, data: &[u8]) -> anyhow::Result<()> {
+        let entry = self
+            .entry(path)
+            .ok_or_else(|| anyhow::format_err!("{} is not listed in the Release file", path))?;
+        let sha256 = hex::encode(sha2::Sha256::digest(data));
+        if entry.size != data.len() as u64 || entry.sha256 != sha256 {
+            return Err(anyhow::format_err!(
+                "{} does not match the Release file, expected {} {} but got {} {}",
+                path,
+                entry.sha256,
+                entry.size,
+                sha256,
+                data.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The signed text of a clearsigned InRelease file, or `text` unchanged.
+fn strip_clearsign(text: &str) -> String {
+    if !text.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+        return text.to_string();
+    }
+    let mut ret = String::new();
+    // Armor headers such as `Hash:` end at the first blank line.
+    let mut lines = text
+        .lines()
+        .skip(1)
+        .skip_while(|x| !x.trim().is_empty())
+        .skip(1);
+    for line in lines.by_ref() {
+        if line.starts_with("-----BEGIN PGP SIGNATURE-----") {
+            break;
+        }
+        ret.push_str(line.strip_prefix("- ").unwrap_or(line));
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Parses the Date of a Release file, e.g. `Sat, 17 Oct 2026 10:00:00 UTC`,
+/// into unix seconds.
+pub(crate) fn parse_release_date(text: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let mut words: Vec<&str> = text.split_whitespace().collect();
+    if words.first().is_some_and(|x| x.ends_with(',')) {
+        words.remove(0);
+    }
+    if words.len() < 4 {
+        return None;
+    }
+    let day: i64 = words[0].parse().ok()?;
+    let month = MONTHS
+        .iter()
+        .position(|x| x.eq_ignore_ascii_case(words[1]))? as i64
+        + 1;
+    let year: i64 = words[2].parse().ok()?;
+    let time: Vec<i64> = words[3]
+        .split(':')
+        .map(|x| x.parse().ok())
+        .collect::<Option<_>>()?;
+    if time.len() != 3 || !(1..=31).contains(&day) || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    let offset = match words.get(4).copied().unwrap_or("UTC") {
+        "UTC" | "GMT" | "Z" => 0,
+        zone => {
+            let sign = match zone.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let hhmm: i64 = zone.get(1..5)?.parse().ok()?;
+            sign * ((hhmm / 100) * 3600 + (hhmm % 100) * 60)
+        }
+    };
+    let seconds =
+        days_from_civil(year, month, day) * 86400 + time[0] * 3600 + time[1] * 60 + time[2]
+            - offset;
+    u64::try_from(seconds).ok()
+}
+
+/// Checks the detached `signature` of `release` against `keyring` with gpgv.
+pub async fn verify_signature(release: &str, signature: &str, keyring: &str) -> anyhow::Result<()> {
+    let output = tokio::process::Command::new("gpgv")
+        .arg("--keyring")
+        .arg(keyring)
+        .arg(signature)
+        .arg(release)
+        .output()
+        .await
+        .context("failed to run gpgv")?;
+    let message = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(anyhow::format_err!(
+            "{} is not signed by a key in {}: {}",
+            release,
+            keyring,
+            message.trim()
+        ));
+    }
+    debug!(release, "{}", message.trim());
+    Ok(())
+}
+
+pub(crate) fn gzip(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+pub(crate) fn xz(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompresses a gzip or xz index recognised by its magic number, or
+/// returns anything else unchanged.
+pub(crate) fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut ret = Vec::new();
+    if data.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::GzDecoder::new(data).read_to_end(&mut ret)?;
+    } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        xz2::read::XzDecoder::new(data).read_to_end(&mut ret)?;
+    } else {
+        ret.extend_from_slice(data);
+    }
+    Ok(ret)
+}
+
+/// Compresses `data` according to the extension of `name`, or returns it
+/// unchanged for an uncompressed index.
+pub(crate) fn encode_for(name: &str, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    if name.ends_with(".gz") {
+        gzip(data)
+    } else if name.ends_with(".xz") {
+        xz(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+/// Rewrites the checksum sections of a Release file for the files in
+/// `changed`, keyed by their path relative to the Release file. SHA256 and
+/// SHA512 entries get the new hashes, while the MD5Sum and SHA1 entries of
+/// changed files are dropped since apt only needs one strong hash.
+pub(crate) fn rewrite_release(text: &str, changed: &HashMap<String, Vec<u8>>) -> String {
+    #[derive(PartialEq)]
+    enum Section {
+        Other,
+        Weak,
+        Sha256,
+        Sha512,
+    }
+
+    let mut section = Section::Other;
+    let mut ret = String::new();
+    for line in text.lines() {
+        if !line.starts_with(' ') {
+            section = match line.trim_end() {
+                "MD5Sum:" | "SHA1:" => Section::Weak,
+                "SHA256:" => Section::Sha256,
+                "SHA512:" => Section::Sha512,
+                _ => Section::Other,
+            };
+            ret.push_str(line);
+            ret.push('\n');
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let data = match (fields.len(), section != Section::Other) {
+            (3, true) => changed.get(fields[2]),
+            _ => None,
+        };
+        match (data, &section) {
+            (None, _) | (Some(_), Section::Other) => {
+                ret.push_str(line);
+                ret.push('\n');
+            }
+            (Some(_), Section::Weak) => {}
+            (Some(data), Section::Sha256) => {
+                let hash = hex::encode(sha2::Sha256::digest(data));
+                ret.push_str(&format!(" {} {:>16} {}\n", hash, data.len(), fields[2]));
+            }
+            (Some(data), Section::Sha512) => {
+                let hash = hex::encode(sha2::Sha512::digest(data));
+                ret.push_str(&format!(" {} {:>16} {}\n", hash, data.len(), fields[2]));
+            }
+        }
+    }
+    ret
+}
+
+/// Merges the Release files several hosts publish for one suite, such as
+/// archive.ubuntu.com and ports.ubuntu.com, into one: the fields of the
+/// first, the union of their Architectures and the checksum entries of
+/// every file the first does not list.
+pub(crate) fn merge_releases(texts: &[String]) -> String {
+    const SECTIONS: [&str; 4] = ["MD5Sum:", "SHA1:", "SHA256:", "SHA512:"];
+
+    let first = match texts.first() {
+        Some(o) => o,
+        None => return String::new(),
+    };
+    let mut architectures: Vec<String> = Vec::new();
+    let mut entries: HashMap<&str, Vec<(String, String)>> = HashMap::new();
+    for text in texts.iter() {
+        let mut section = "";
+        for line in text.lines() {
+            if !line.starts_with(' ') {
+                section = SECTIONS
+                    .iter()
+                    .find(|x| line.trim_end() == **x)
+                    .copied()
+                    .unwrap_or("");
+                if let Some(o) = line.strip_prefix("Architectures:") {
+                    for x in o.split_whitespace() {
+                        if !architectures.iter().any(|y| y == x) {
+                            architectures.push(x.to_string());
+                        }
+                    }
+                }
+                continue;
+            }
+            if section.is_empty() {
+                continue;
+            }
+            let path = match line.split_whitespace().nth(2) {
+                Some(o) => o.to_string(),
+                None => continue,
+            };
+            let listed = entries.entry(section).or_default();
+            if !listed.iter().any(|(x, _)| *x == path) {
+                listed.push((path, line.to_string()));
+            }
+        }
+    }
+
+    let mut ret = String::new();
+    let mut section = "";
+    let flush = |ret: &mut String, section: &str| {
+        if let Some(o) = entries.get(section) {
+            for (_, line) in o.iter() {
+                ret.push_str(line);
+                ret.push('\n');
+            }
+        }
+    };
+    for line in first.lines() {
+        if line.starts_with(' ') {
+            // The section is written whole from the merged entries.
+            if section.is_empty() {
+                ret.push_str(line);
+                ret.push('\n');
+            }
+            continue;
+        }
+        flush(&mut ret, section);
+        section = SECTIONS
+            .iter()
+            .find(|x| line.trim_end() == **x)
+            .copied()
+            .unwrap_or("");
+        if line.starts_with("Architectures:") {
+            ret.push_str(&format!("Architectures: {}\n", architectures.join(" ")));
+        } else {
+            ret.push_str(line);
+            ret.push('\n');
+        }
+    }
+    flush(&mut ret, section);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELEASE: &str = "\
+Origin: Debian
+Suite: stable
+MD5Sum:
+ 0123 10 main/binary-amd64/Packages
+ 4567 20 main/binary-amd64/Release
+SHA256:
+ aaaa 10 main/binary-amd64/Packages
+ bbbb 20 main/binary-amd64/Release
+SHA512:
+ cccc 10 main/binary-amd64/Packages
+";
+
+    #[test]
+    fn parses_clearsigned_releases() {
+        let text = "\
+-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA512
+
+Origin: Debian
+Suite: stable
+SHA256:
+ aaaa 10 main/binary-amd64/Packages
+- -dashed line
+-----BEGIN PGP SIGNATURE-----
+
+abcd
+-----END PGP SIGNATURE-----
+";
+        assert_eq!(
+            strip_clearsign(text),
+            "Origin: Debian\nSuite: stable\nSHA256:\n aaaa 10 main/binary-amd64/Packages\n-dashed line\n"
+        );
+        let release = ReleaseFile::parse(text);
+        assert_eq!(release.get("suite"), Some("stable"));
+        assert_eq!(
+            release.entries,
+            [ReleaseEntry {
+                path: String::from("main/binary-amd64/Packages"),
+                size: 10,
+                sha256: String::from("aaaa"),
+            }]
+        );
+        assert_eq!(strip_clearsign(RELEASE), RELEASE);
+    }
+
+    #[test]
+    fn rewrite_updates_strong_hashes_of_changed_files() {
+        let data = b"Package: hello\n\n".to_vec();
+        let changed = HashMap::from([(String::from("main/binary-amd64/Packages"), data.clone())]);
+        let text = rewrite_release(RELEASE, &changed);
+        let release = ReleaseFile::parse(&text);
+        release.verify("main/binary-amd64/Packages", &data).unwrap();
+        assert_eq!(
+            release.entry("main/binary-amd64/Release").unwrap().sha256,
+            "bbbb"
+        );
+        assert!(!text.contains("0123"));
+        assert!(text.contains(" 4567 20 main/binary-amd64/Release\n"));
+        let sha512 = hex::encode(sha2::Sha512::digest(&data));
+        assert!(text.contains(&format!(
+            " {} {:>16} main/binary-amd64/Packages\n",
+            sha512, 16
+        )));
+        assert!(text.starts_with("Origin: Debian\nSuite: stable\n"));
+    }
+
+    #[test]
+    fn rewrite_leaves_unchanged_files_alone() {
+        assert_eq!(rewrite_release(RELEASE, &HashMap::new()), RELEASE);
+    }
+
+    #[test]
+    fn merges_releases_of_several_hosts() {
+        let archive = String::from(
+            "\
+Origin: Ubuntu
+Architectures: amd64 i386
+SHA256:
+ aaaa 10 main/binary-amd64/Packages
+ bbbb 20 main/binary-i386/Packages
+",
+        );
+        let ports = String::from(
+            "\
+Origin: Ports
+Architectures: arm64 amd64
+MD5Sum:
+ 0123 30 main/binary-arm64/Packages
+SHA256:
+ ffff 10 main/binary-amd64/Packages
+ cccc 30 main/binary-arm64/Packages
+",
+        );
+        assert_eq!(
+            merge_releases(&[archive.clone(), ports]),
+            "\
+Origin: Ubuntu
+Architectures: amd64 i386 arm64
+SHA256:
+ aaaa 10 main/binary-amd64/Packages
+ bbbb 20 main/binary-i386/Packages
+ cccc 30 main/binary-arm64/Packages
+"
+        );
+        assert_eq!(merge_releases(std::slice::from_ref(&archive)), archive);
+        assert_eq!(merge_releases(&[]), "");
+    }
+
+    #[test]
+    fn parses_release_dates() {
+        let saturday = Some(1_792_231_200);
+        assert_eq!(
+            parse_release_date("Sat, 17 Oct 2026 10:00:00 UTC"),
+            saturday
+        );
+        assert_eq!(parse_release_date("17 Oct 2026 10:00:00"), saturday);
+        assert_eq!(
+            parse_release_date("Sat, 17 oct 2026 10:00:00 GMT"),
+            saturday
+        );
+        assert_eq!(
+            parse_release_date("Sat, 17 Oct 2026 12:30:00 +0230"),
+            saturday
+        );
+        assert_eq!(
+            parse_release_date("Sat, 17 Oct 2026 07:00:00 -0300"),
+            saturday
+        );
+        assert_eq!(parse_release_date("Thu, 01 Jan 1970 00:00:00 UTC"), Some(0));
+        for x in [
+            "",
+            "Sat, 17 Oct 2026",
+            "Sat, 17 Foo 2026 10:00:00 UTC",
+            "Sat, 17 Oct 2026 10:00 UTC",
+            "Sat, 17 Oct 2026 25:00:00 UTC",
+            "Sat, 32 Oct 2026 10:00:00 UTC",
+            "Sat, 17 Oct 2026 10:00:00 CEST",
+            "Wed, 31 Dec 1969 23:59:59 UTC",
+        ] {
+            assert_eq!(parse_release_date(x), None, "{:?}", x);
+        }
+    }
+}
@@ -0,0 +1,936 @@
+use anyhow::Context;
+use futures::StreamExt;
+use sha2::Digest;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::download_dist::sha256_file;
+use crate::mirrors::MirrorsFile;
+use crate::paths::paths;
+use crate::store::is_sha256_name;
+use crate::store::BlobStore;
+use crate::store::LAYOUT_FILE;
+use tracing::debug;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Instrument;
+
+const DEFAULT_JOBS: usize = 8;
+
+/// Suffix of the files written next to their destination before the rename.
+const TMP_SUFFIX: &str = ".mysync-tmp";
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct CopyOptions {
+    /// Compare files by SHA256 rather than by size and modification time
+    #[arg(short, long)]
+    pub checksum: bool,
+
+    /// Treat modification times this many seconds apart as equal, for
+    /// filesystems such as FAT that store them coarsely
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub modify_window: u64,
+
+    /// Delete what the destination holds beyond the source
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Files copied at the same time
+    #[arg(short, long, default_value_t = DEFAULT_JOBS)]
+    pub jobs: usize,
+
+    /// Only report what would be copied and deleted
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+/// What a copy changed. Files are counted whether copied or not.
+#[derive(Debug, Clone, Default)]
+pub struct CopySummary {
+    pub files: u64,
+    pub copied: u64,
+    pub bytes: u64,
+    pub links: u64,
+    pub dirs: u64,
+    pub deleted: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Dir {
+        mode: u32,
+        mtime: SystemTime,
+    },
+    File {
+        size: u64,
+        mode: u32,
+        mtime: SystemTime,
+    },
+    Symlink(PathBuf),
+    /// Another name of the file at this path of the tree.
+    HardLink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Path below the tree root, empty for the root itself.
+    path: PathBuf,
+    kind: Kind,
+}
+
+/// Where copying `source` into `dest` puts it. As with rsync, a source
+/// ending in a slash is copied into `dest` itself, any other below it.
+pub fn destination(source: &Path, dest: &Path) -> PathBuf {
+    match source.file_name() {
+        Some(name) if !source.as_os_str().as_bytes().ends_with(b"/") => dest.join(name),
+        _ => dest.to_path_buf(),
+    }
+}
+
+/// Lists the tree below `root` without following symlinks, every directory
+/// before its contents. Files sharing an inode are listed once as a file,
+/// then as hard links to it.
+fn walk(root: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut ret = Vec::new();
+    let mut inodes: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        let metadata = std::fs::metadata(root.join(&dir))?;
+        ret.push(Entry {
+            path: dir.clone(),
+            kind: Kind::Dir {
+                mode: metadata.mode(),
+                mtime: metadata.modified()?,
+            },
+        });
+        let mut names = Vec::new();
+        for x in std::fs::read_dir(root.join(&dir))? {
+            names.push(x?.file_name());
+        }
+        names.sort();
+        let mut subdirs = Vec::new();
+        for name in names {
+            let path = dir.join(&name);
+            let metadata = std::fs::symlink_metadata(root.join(&path))?;
+            let kind = if metadata.is_dir() {
+                subdirs.push(path);
+                continue;
+            } else if metadata.file_type().is_symlink() {
+                Kind::Symlink(std::fs::read_link(root.join(&path))?)
+            } else if !metadata.is_file() {
+                warn!(file = %root.join(&path).display(), "skipping special file");
+                continue;
+            } else if let Some(first) = inodes.get(&(metadata.dev(), metadata.ino())) {
+                Kind::HardLink(first.clone())
+            } else {
+                if metadata.nlink() > 1 {
+                    inodes.insert((metadata.dev(), metadata.ino()), path.clone());
+                }
+                Kind::File {
+                    size: metadata.len(),
+                    mode: metadata.mode(),
+                    mtime: metadata.modified()?,
+                }
+            };
+            ret.push(Entry { path, kind });
+        }
+        // Popped from the end, so reversed to keep the walk in name order.
+        pending.extend(subdirs.into_iter().rev());
+    }
+    Ok(ret)
+}
+
+/// Paths of the regular files below `root`, relative to it.
+pub(crate) fn list_files(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    Ok(walk(root)?
+        .into_iter()
+        .filter(|x| matches!(x.kind, Kind::File { .. } | Kind::HardLink(_)))
+        .map(|x| x.path)
+        .collect())
+}
+
+fn same_time(a: SystemTime, b: SystemTime, window: u64) -> bool {
+    let seconds = |x: SystemTime| {
+        x.duration_since(SystemTime::UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0)
+    };
+    seconds(a).abs_diff(seconds(b)) <= window
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Removes whatever is at `path`, so something of another kind can take
+/// its place.
+fn remove_any(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(o) if o.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves `tmp` over `path`. A rename replaces files and symlinks in one
+/// step, a directory in the way is removed first.
+fn replace(tmp: &Path, path: &Path) -> std::io::Result<()> {
+    if std::fs::symlink_metadata(path).is_ok_and(|x| x.is_dir()) {
+        std::fs::remove_dir_all(path)?;
+    }
+    std::fs::rename(tmp, path)
+}
+
+/// Whether the file at `dest` already matches `source`.
+fn unchanged(source: &Path, dest: &Path, kind: &Kind, options: &CopyOptions) -> bool {
+    let (size, mtime) = match kind {
+        Kind::File { size, mtime, .. } => (*size, *mtime),
+        _ => return false,
+    };
+    let metadata = match std::fs::symlink_metadata(dest) {
+        Ok(o) if o.is_file() => o,
+        _ => return false,
+    };
+    if metadata.len() != size {
+        return false;
+    }
+    if options.checksum {
+        return matches!(
+            (sha256_file(source), sha256_file(dest)),
+            (Ok(a), Ok(b)) if a == b
+        );
+    }
+    metadata
+        .modified()
+        .is_ok_and(|x| same_time(x, mtime, options.modify_window))
+}
+
+/// Copies one file unless the destination matches it already, writing next
+/// to it and renaming once the data is on disk. The size copied, None if
+/// the file was unchanged.
+fn copy_file(
+    source: &Path,
+    dest: &Path,
+    kind: &Kind,
+    options: &CopyOptions,
+) -> std::io::Result<Option<u64>> {
+    if unchanged(source, dest, kind, options) {
+        return Ok(None);
+    }
+    let (mode, mtime) = match kind {
+        Kind::File { mode, mtime, .. } => (*mode, *mtime),
+        _ => return Ok(None),
+    };
+    if options.dry_run {
+        return Ok(Some(std::fs::metadata(source)?.len()));
+    }
+    let tmp = tmp_path(dest);
+    let mut input = std::fs::File::open(source)?;
+    let mut output = std::fs::File::create(&tmp)?;
+    let size = std::io::copy(&mut input, &mut output)?;
+    output.set_permissions(std::fs::Permissions::from_mode(mode & 0o7777))?;
+    output.set_modified(mtime)?;
+    output.sync_all()?;
+    drop(output);
+    replace(&tmp, dest)?;
+    Ok(Some(size))
+}
+
+/// Points the symlink at `dest` to `target` unless it does already.
+fn copy_symlink(target: &Path, dest: &Path, dry_run: bool) -> std::io::Result<bool> {
+    if std::fs::read_link(dest).is_ok_and(|x| x == target) {
+        return Ok(false);
+    }
+    if dry_run {
+        return Ok(true);
+    }
+    let tmp = tmp_path(dest);
+    let _ = std::fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(target, &tmp)?;
+    replace(&tmp, dest)?;
+    Ok(true)
+}
+
+/// Links `dest` to the already copied `first` unless they are one file.
+fn copy_hard_link(first: &Path, dest: &Path, dry_run: bool) -> std::io::Result<bool> {
+    if let (Ok(a), Ok(b)) = (
+        std::fs::symlink_metadata(first),
+        std::fs::symlink_metadata(dest),
+    ) {
+        if a.dev() == b.dev() && a.ino() == b.ino() {
+            return Ok(false);
+        }
+    }
+    if dry_run {
+        return Ok(true);
+    }
+    let tmp = tmp_path(dest);
+    let _ = std::fs::remove_file(&tmp);
+    std::fs::hard_link(first, &tmp)?;
+    replace(&tmp, dest)?;
+    Ok(true)
+}
+
+/// Makes sure a directory is at `dest`. True if it had to be created.
+fn make_dir(dest: &Path, dry_run: bool) -> std::io::Result<bool> {
+    match std::fs::symlink_metadata(dest) {
+        Ok(o) if o.is_dir() => return Ok(false),
+        Ok(_) if !dry_run => std::fs::remove_file(dest)?,
+        _ => {}
+    }
+    if !dry_run {
+        std::fs::create_dir(dest)?;
+    }
+    Ok(true)
+}
+
+/// Flushes a directory, making the renames and links in it durable.
+fn sync_dir(path: &Path) -> std::io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+/// Removes everything below `dest` that is not in `keep`, deepest first.
+fn delete_extra(
+    dest: &Path,
+    keep: &BTreeSet<PathBuf>,
+    dry_run: bool,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for entry in walk(dest)?.iter().rev() {
+        if entry.path.as_os_str().is_empty() || keep.contains(&entry.path) {
+            continue;
+        }
+        // Everything below a removed directory goes with it.
+        if ret.iter().any(|x: &PathBuf| entry.path.starts_with(x)) {
+            continue;
+        }
+        let path = dest.join(&entry.path);
+        debug!(file = %path.display(), "deleting");
+        if !dry_run {
+            remove_any(&path)?;
+        }
+        ret.push(entry.path.clone());
+    }
+    Ok(ret)
+}
+
+pub(crate) async fn blocking<T, F>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f).await??)
+}
+
+/// Makes the tree at `dest` a copy of the one at `source`: directories,
+/// files with their permissions and modification times, symlinks as they
+/// are and hard links between files of the tree. Only files that differ
+/// are copied, several at a time, each written beside its destination,
+/// flushed and renamed into place, and every directory changed is flushed
+/// at the end, so an interrupted copy never leaves a truncated file behind.
+pub async fn copy_tree(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> anyhow::Result<CopySummary> {
+    let started = std::time::Instant::now();
+    let source = source.to_path_buf();
+    let dest = dest.to_path_buf();
+    if !std::fs::metadata(&source).is_ok_and(|x| x.is_dir()) {
+        return Err(anyhow::format_err!(
+            "{} is not a directory",
+            source.display()
+        ));
+    }
+    let root = source.clone();
+    let entries = blocking(move || walk(&root))
+        .await
+        .with_context(|| format!("failed to read {}", source.display()))?;
+    let mut summary = CopySummary::default();
+    // Directories whose entries changed and have to be flushed.
+    let mut touched: BTreeSet<PathBuf> = BTreeSet::new();
+    let touch = |touched: &mut BTreeSet<PathBuf>, path: &Path| {
+        touched.insert(dest.join(path.parent().unwrap_or(Path::new(""))));
+    };
+
+    if !options.dry_run {
+        if let Some(parent) = dest.parent().filter(|x| !x.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+    }
+    for entry in entries.iter() {
+        if let Kind::Dir { .. } = entry.kind {
+            let path = dest.join(&entry.path);
+            let created = make_dir(&path, options.dry_run)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            if created {
+                debug!(dir = %path.display(), "created");
+                summary.dirs += 1;
+                touch(&mut touched, &entry.path);
+                touched.insert(path);
+            }
+        }
+    }
+
+    let files = entries
+        .iter()
+        .filter(|x| matches!(x.kind, Kind::File { .. }))
+        .cloned();
+    let results = futures::stream::iter(files.map(|entry| {
+        let (from, to) = (source.join(&entry.path), dest.join(&entry.path));
+        let options = options.clone();
+        async move {
+            let ret = blocking(move || copy_file(&from, &to, &entry.kind, &options)).await;
+            (entry.path, ret)
+        }
+    }))
+    .buffer_unordered(options.jobs.max(1))
+    .collect::<Vec<_>>()
+    .await;
+    for (path, result) in results {
+        summary.files += 1;
+        match result {
+            Ok(Some(size)) => {
+                debug!(file = %path.display(), size, "copied");
+                summary.copied += 1;
+                summary.bytes += size;
+                touch(&mut touched, &path);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(file = %path.display(), "failed to copy: {:#}", e);
+                summary.failed += 1;
+            }
+        }
+    }
+
+    for entry in entries.iter() {
+        let path = dest.join(&entry.path);
+        let result = match &entry.kind {
+            Kind::Symlink(target) => copy_symlink(target, &path, options.dry_run),
+            Kind::HardLink(first) => {
+                summary.files += 1;
+                copy_hard_link(&dest.join(first), &path, options.dry_run)
+            }
+            _ => continue,
+        };
+        match result {
+            Ok(true) => {
+                debug!(file = %path.display(), "linked");
+                summary.links += 1;
+                touch(&mut touched, &entry.path);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                warn!(file = %path.display(), "failed to link: {:#}", e);
+                summary.failed += 1;
+            }
+        }
+    }
+
+    if options.delete {
+        let keep: BTreeSet<PathBuf> = entries.iter().map(|x| x.path.clone()).collect();
+        let (dir, dry_run) = (dest.clone(), options.dry_run);
+        let deleted = blocking(move || delete_extra(&dir, &keep, dry_run))
+            .await
+            .with_context(|| format!("failed to delete from {}", dest.display()))?;
+        summary.deleted = deleted.len() as u64;
+        for x in deleted.iter() {
+            touch(&mut touched, x);
+        }
+    }
+
+    if !options.dry_run {
+        let dirs: Vec<(PathBuf, u32, SystemTime)> = entries
+            .iter()
+            .filter_map(|x| match x.kind {
+                Kind::Dir { mode, mtime } => Some((dest.join(&x.path), mode, mtime)),
+                _ => None,
+            })
+            .collect();
+        let touched: Vec<PathBuf> = touched.into_iter().collect();
+        blocking(move || {
+            // Deepest first, so setting a time is not undone by changes
+            // below it.
+            for (path, mode, mtime) in dirs.iter().rev() {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+                std::fs::File::open(path)?.set_modified(*mtime)?;
+            }
+            for x in touched.iter().rev() {
+                sync_dir(x)?;
+            }
+            Ok(())
+        })
+        .await
+        .with_context(|| format!("failed to finish {}", dest.display()))?;
+    }
+
+    info!(
+        files = summary.files,
+        copied = summary.copied,
+        bytes = summary.bytes,
+        links = summary.links,
+        dirs = summary.dirs,
+        deleted = summary.deleted,
+        failed = summary.failed,
+        seconds = started.elapsed().as_secs(),
+        bytes_per_second =
+            (summary.bytes as f64 / started.elapsed().as_secs_f64().max(0.001)) as u64,
+        dry_run = options.dry_run,
+        "copied {} to {}",
+        source.display(),
+        dest.display()
+    );
+    if summary.failed > 0 {
+        return Err(anyhow::format_err!(
+            "{} files could not be copied to {}",
+            summary.failed,
+            dest.display()
+        ));
+    }
+    Ok(summary)
+}
+
+/// What a mirror replication changed.
+#[derive(Debug, Clone, Default)]
+pub struct ReplicateSummary {
+    pub blobs: u64,
+    pub blobs_copied: u64,
+    pub bytes: u64,
+    pub blobs_deleted: u64,
+    pub published: Vec<String>,
+}
+
+/// Copies the file at `source` to `dest`, checking that its content hashes
+/// to `sha256` on the way. Nothing is left at `dest` if it does not.
+pub(crate) fn copy_verified(source: &Path, dest: &Path, sha256: &str) -> std::io::Result<u64> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = tmp_path(dest);
+    let mut input = std::fs::File::open(source)?;
+    let mut output = std::fs::File::create(&tmp)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    let mut size = 0;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        output.write_all(&buffer[..n])?;
+        size += n as u64;
+    }
+    if hex::encode(hasher.finalize()) != sha256 {
+        drop(output);
+        let _ = std::fs::remove_file(&tmp);
+        return Err(std::io::Error::other(format!(
+            "{} does not have the SHA256 {}",
+            source.display(),
+            sha256
+        )));
+    }
+    output.sync_all()?;
+    drop(output);
+    std::fs::rename(&tmp, dest)?;
+    Ok(size)
+}
+
+/// Opens the store of the replica, giving a new one the fan-out of the
+/// source so the relative pool links resolve the same way on both sides.
+fn open_replica_store(source: &BlobStore, dest: &Path, dry_run: bool) -> anyhow::Result<BlobStore> {
+    let layout = dest.join(LAYOUT_FILE);
+    let empty = std::fs::read_dir(dest).map_or(true, |mut x| x.next().is_none());
+    if empty && source.levels() > 0 && !dry_run {
+        std::fs::create_dir_all(dest)?;
+        let tmp = tmp_path(&layout);
+        std::fs::write(&tmp, format!("{}\n", source.levels()))?;
+        std::fs::rename(&tmp, &layout)?;
+    }
+    let ret = BlobStore::open(&dest.to_string_lossy())?;
+    if ret.levels() != source.levels() && !(empty && dry_run) {
+        return Err(anyhow::format_err!(
+            "the store at {} has {} fan-out levels and the source {}, migrate one of them first",
+            dest.display(),
+            ret.levels(),
+            source.levels()
+        ));
+    }
+    Ok(ret)
+}
+
+/// Mirror trees below a deb_mirror root: one per mirror of its mirrors
+/// file, or the root itself.
+fn mirror_trees(root: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let path = root.join(&paths().mirrors);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![PathBuf::new()]),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let file: MirrorsFile =
+        toml::from_str(&content).with_context(|| format!("invalid {}", path.display()))?;
+    Ok(file.mirror.keys().map(PathBuf::from).collect())
+}
+
+/// Pool links of a generation that do not resolve, the ones apt would fail
+/// to fetch.
+fn dangling_pool_links(generation: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let pool = generation.join("pool");
+    if !pool.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(walk(&pool)?
+        .into_iter()
+        .filter(|x| matches!(x.kind, Kind::Symlink(_)))
+        .filter(|x| std::fs::metadata(pool.join(&x.path)).is_err())
+        .map(|x| x.path)
+        .collect())
+}
+
+/// Replicates the published generation of one mirror tree: copies it,
+/// checks that its pool resolves against the replica's store and switches
+/// the replica's current link to it.
+async fn replicate_tree(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> anyhow::Result<Option<String>> {
+    let current = source.join(&paths().current);
+    let target = match std::fs::read_link(&current) {
+        Ok(o) => o,
+        Err(_) => {
+            warn!(tree = %source.display(), "nothing published, skipping");
+            return Ok(None);
+        }
+    };
+    let name = target
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let generation = dest.join(&paths().snapshots).join(&name);
+    // A generation never changes once published, anything else in the copy
+    // is left over from an interrupted run.
+    let exact = CopyOptions {
+        delete: true,
+        ..options.clone()
+    };
+    copy_tree(
+        &source.join(&paths().snapshots).join(&name),
+        &generation,
+        &exact,
+    )
+    .await?;
+
+    if !options.dry_run {
+        let dir = generation.clone();
+        let dangling = blocking(move || dangling_pool_links(&dir)).await?;
+        if let Some(x) = dangling.first() {
+            return Err(anyhow::format_err!(
+                "{} pool files of {} do not resolve on the replica, e.g. {}, not publishing",
+                dangling.len(),
+                generation.display(),
+                x.display()
+            ));
+        }
+    }
+    let link = dest.join(&paths().current);
+    let (dir, dry_run) = (dest.to_path_buf(), options.dry_run);
+    let switched = blocking(move || {
+        let ret = copy_symlink(&target, &link, dry_run)?;
+        if ret && !dry_run {
+            sync_dir(&dir)?;
+        }
+        Ok(ret)
+    })
+    .await?;
+    if switched {
+        info!(tree = %dest.display(), snapshot = %name, "switched current");
+    }
+
+    let trace = source.join(&paths().trace);
+    if trace.is_dir() {
+        copy_tree(&trace, &dest.join(&paths().trace), options).await?;
+    }
+    let snapshots = dest.join(&paths().snapshots);
+    if options.delete && snapshots.is_dir() {
+        for x in std::fs::read_dir(&snapshots)? {
+            let x = x?;
+            if x.file_name().to_string_lossy() != name {
+                debug!(snapshot = %x.path().display(), "deleting");
+                if !options.dry_run {
+                    remove_any(&x.path())?;
+                }
+            }
+        }
+    }
+    Ok(Some(name))
+}
+
+/// Replicates the deb_mirror root at `source` to `dest` in the order that
+/// keeps the replica consistent: first the blobs its store lacks, compared
+/// by name since names are hashes and checked against it while copied, then
+/// the published dists and pool of every mirror, and only then each
+/// mirror's current link, switched in a single rename. With `--delete` the
+/// replica's older generations and the blobs the source no longer holds are
+/// removed afterwards.
+pub async fn replicate_mirror(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> anyhow::Result<ReplicateSummary> {
+    let started = std::time::Instant::now();
+    let source_store = BlobStore::open(&source.join(&paths().store).to_string_lossy())?;
+    let dest_store =
+        open_replica_store(&source_store, &dest.join(&paths().store), options.dry_run)?;
+    let mut summary = ReplicateSummary::default();
+
+    let blobs = source_store.list().await?;
+    summary.blobs = blobs.len() as u64;
+    let missing: Vec<(String, String)> = blobs
+        .iter()
+        .filter(|(name, _)| is_sha256_name(name))
+        .filter(|(name, _)| std::fs::symlink_metadata(dest_store.path(name)).is_err())
+        .cloned()
+        .collect();
+    let results = futures::stream::iter(missing.into_iter().map(|(name, path)| {
+        let to = PathBuf::from(dest_store.path(&name));
+        let dry_run = options.dry_run;
+        async move {
+            let ret = if dry_run {
+                std::fs::metadata(&path)
+                    .map(|x| x.len())
+                    .map_err(anyhow::Error::from)
+            } else {
+                let from = PathBuf::from(&path);
+                let sha256 = name.clone();
+                blocking(move || copy_verified(&from, &to, &sha256)).await
+            };
+            (name, ret)
+        }
+    }))
+    .buffer_unordered(options.jobs.max(1))
+    .collect::<Vec<_>>()
+    .await;
+    let mut failed = 0;
+    let mut touched: BTreeSet<PathBuf> = BTreeSet::new();
+    for (name, result) in results {
+        match result {
+            Ok(size) => {
+                debug!(blob = %name, size, "copied");
+                summary.blobs_copied += 1;
+                summary.bytes += size;
+                if let Some(x) = Path::new(&dest_store.path(&name)).parent() {
+                    touched.insert(x.to_path_buf());
+                }
+            }
+            Err(e) => {
+                warn!(blob = %name, "failed to copy: {:#}", e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow::format_err!(
+            "{} blobs could not be copied to {}, not publishing",
+            failed,
+            dest_store.root()
+        ));
+    }
+    if !options.dry_run {
+        blocking(move || {
+            for x in touched.iter() {
+                sync_dir(x)?;
+            }
+            Ok(())
+        })
+        .await?;
+    }
+
+    for tree in mirror_trees(source)? {
+        let span = info_span!("tree", tree = %tree.display());
+        let published = replicate_tree(&source.join(&tree), &dest.join(&tree), options)
+            .instrument(span)
+            .await?;
+        if published.is_some() {
+            summary.published.push(tree.to_string_lossy().to_string());
+        }
+    }
+    let mirrors = source.join(&paths().mirrors);
+    if mirrors.exists() {
+        let metadata = std::fs::metadata(&mirrors)?;
+        let file = Kind::File {
+            size: metadata.len(),
+            mode: metadata.mode(),
+            mtime: metadata.modified()?,
+        };
+        copy_file(&mirrors, &dest.join(&paths().mirrors), &file, options)?;
+    }
+
+    if options.delete {
+        let keep: HashSet<&str> = blobs.iter().map(|(name, _)| name.as_str()).collect();
+        for (name, path) in dest_store.list().await? {
+            if !keep.contains(name.as_str()) {
+                debug!(blob = %name, "deleting");
+                if !options.dry_run {
+                    tokio::fs::remove_file(&path).await?;
+                }
+                summary.blobs_deleted += 1;
+            }
+        }
+    }
+
+    info!(
+        blobs = summary.blobs,
+        copied = summary.blobs_copied,
+        bytes = summary.bytes,
+        deleted = summary.blobs_deleted,
+        trees = summary.published.len(),
+        seconds = started.elapsed().as_secs(),
+        dry_run = options.dry_run,
+        "replicated {} to {}",
+        source.display(),
+        dest.display()
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let ret =
+            std::env::temp_dir().join(format!("mysync-replicate-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&ret);
+        std::fs::create_dir_all(&ret).unwrap();
+        ret
+    }
+
+    #[test]
+    fn sources_ending_in_a_slash_are_copied_into_dest() {
+        let dest = Path::new("/backup");
+        assert_eq!(
+            destination(Path::new("/srv/mirror"), dest),
+            Path::new("/backup/mirror")
+        );
+        assert_eq!(destination(Path::new("/srv/mirror/"), dest), dest);
+        assert_eq!(destination(Path::new("/"), dest), dest);
+    }
+
+    #[test]
+    fn times_within_the_window_are_the_same() {
+        let t = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_792_231_200);
+        let later = t + std::time::Duration::from_secs(2);
+        assert!(same_time(t, t, 0));
+        assert!(!same_time(t, later, 0));
+        assert!(same_time(later, t, 2));
+        assert!(!same_time(t, later, 1));
+    }
+
+    #[test]
+    fn hard_links_are_listed_after_their_file() {
+        let root = temp_dir("walk");
+        std::fs::create_dir_all(root.join("a/b")).unwrap();
+        std::fs::write(root.join("a/file"), "x").unwrap();
+        std::fs::hard_link(root.join("a/file"), root.join("a/b/link")).unwrap();
+        std::os::unix::fs::symlink("../file", root.join("a/b/symlink")).unwrap();
+        let entries = walk(&root).unwrap();
+        assert_eq!(entries[0].path, PathBuf::new());
+        let files: Vec<_> = entries
+            .iter()
+            .filter_map(|x| match &x.kind {
+                Kind::File { .. } => Some((x.path.clone(), None)),
+                Kind::HardLink(first) => Some((x.path.clone(), Some(first.clone()))),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(files.len(), 2);
+        assert!(files[0].1.is_none());
+        assert_eq!(files[1].1.as_ref(), Some(&files[0].0));
+        assert_eq!(list_files(&root).unwrap().len(), 2);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn replica_stores_take_the_fan_out_of_the_source() {
+        let root = temp_dir("store");
+        let source = root.join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join(LAYOUT_FILE), "2\n").unwrap();
+        let source = BlobStore::open(&source.to_string_lossy()).unwrap();
+
+        // A dry run leaves a new replica alone.
+        let dest = root.join("dest");
+        assert_eq!(
+            open_replica_store(&source, &dest, true).unwrap().levels(),
+            0
+        );
+        assert!(!dest.exists());
+        assert_eq!(
+            open_replica_store(&source, &dest, false).unwrap().levels(),
+            2
+        );
+        assert_eq!(
+            open_replica_store(&source, &dest, false).unwrap().levels(),
+            2
+        );
+
+        // One already holding blobs in another layout is refused.
+        let flat = root.join("flat");
+        std::fs::create_dir_all(&flat).unwrap();
+        std::fs::write(flat.join("0".repeat(64)), "").unwrap();
+        assert!(open_replica_store(&source, &flat, false).is_err());
+        assert!(open_replica_store(&source, &flat, true).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn copies_must_match_their_hash() {
+        let root = temp_dir("verified");
+        let source = root.join("blob");
+        std::fs::write(&source, "Package: hello\n").unwrap();
+        let sha256 = hex::encode(sha2::Sha256::digest("Package: hello\n"));
+        let dest = root.join("a/b/blob");
+        assert_eq!(copy_verified(&source, &dest, &sha256).unwrap(), 15);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"Package: hello\n");
+
+        let dest = root.join("a/b/other");
+        assert!(copy_verified(&source, &dest, &"0".repeat(64)).is_err());
+        assert!(!dest.exists());
+        assert!(!tmp_path(&dest).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dangling_links_of_the_pool() {
+        let root = temp_dir("dangling");
+        assert!(dangling_pool_links(&root).unwrap().is_empty());
+        std::fs::create_dir_all(root.join("pool/main")).unwrap();
+        std::fs::write(root.join("blob"), "").unwrap();
+        std::os::unix::fs::symlink("../../blob", root.join("pool/main/good")).unwrap();
+        std::os::unix::fs::symlink("../../missing", root.join("pool/main/bad")).unwrap();
+        assert_eq!(
+            dangling_pool_links(&root).unwrap(),
+            vec![PathBuf::from("main/bad")]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
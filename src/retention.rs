@@ -0,0 +1,308 @@
+use anyhow::Context;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::mirrors::MirrorCtx;
+use crate::packages::compare_versions;
+use crate::packages::parse_stanzas;
+use crate::packages::PackageStanza;
+use crate::snapshot::IndexHistory;
+use crate::store::blob_path;
+
+/// How many superseded versions stay published next to the upstream ones.
+/// The defaults keep only what upstream lists. Kept versions are saved
+/// apart from the generations, so pruning those to --keep does not limit
+/// how many stay.
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct Retention {
+    /// Newest versions kept per package and architecture
+    #[arg(long, default_value_t = 1)]
+    pub keep_versions: usize,
+
+    /// Also keep versions first published less than this many days ago
+    #[arg(long, default_value_t = 0)]
+    pub keep_days: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            keep_versions: 1,
+            keep_days: 0,
+        }
+    }
+}
+
+impl Retention {
+    pub(crate) fn is_active(&self) -> bool {
+        self.keep_versions > 1 || self.keep_days > 0
+    }
+}
+
+type VersionKey = (String, String, String);
+
+fn key_of(x: &PackageStanza) -> VersionKey {
+    (
+        x.package().to_string(),
+        x.architecture().to_string(),
+        x.version().to_string(),
+    )
+}
+
+/// First publication time of every version, loaded before and written back
+/// after a publish.
+pub(crate) struct FirstSeen {
+    seen: HashMap<VersionKey, u64>,
+    published: HashSet<VersionKey>,
+    now: u64,
+    /// The retention state file of the tree.
+    path: String,
+}
+
+impl FirstSeen {
+    pub(crate) async fn load(ctx: &MirrorCtx, now: u64) -> anyhow::Result<FirstSeen> {
+        let mut ret = FirstSeen {
+            seen: HashMap::new(),
+            published: HashSet::new(),
+            now,
+            path: ctx.paths().retention_state.clone(),
+        };
+        let content = match tokio::fs::read_to_string(&ret.path).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", &ret.path)),
+        };
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() == 4 {
+                if let Ok(since) = fields[3].parse::<u64>() {
+                    let key = (
+                        fields[0].to_string(),
+                        fields[1].to_string(),
+                        fields[2].to_string(),
+                    );
+                    ret.seen.insert(key, since);
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    fn age_days(&self, key: &VersionKey) -> u64 {
+        let since = self.seen.get(key).copied().unwrap_or(self.now);
+        self.now.saturating_sub(since) / 86400
+    }
+
+    /// Marks the stanzas as published in this run.
+    pub(crate) fn record(&mut self, stanzas: &[PackageStanza]) {
+        for x in stanzas {
+            let key = key_of(x);
+            self.seen.entry(key.clone()).or_insert(self.now);
+            self.published.insert(key);
+        }
+    }
+
+    /// Writes the first-seen time of every version published in this run.
+    pub(crate) async fn save(&self) -> anyhow::Result<()> {
+        let mut out = String::new();
+        for key in self.published.iter() {
+            let since = self.seen.get(key).copied().unwrap_or(self.now);
+            out.push_str(&format!("{} {} {} {}\n", key.0, key.1, key.2, since));
+        }
+        let tmp = format!("{}.tmp", &self.path);
+        tokio::fs::write(&tmp, out)
+            .await
+            .with_context(|| format!("failed to write {}", tmp))?;
+        tokio::fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("failed to move {} to {}", tmp, &self.path))
+    }
+}
+
+/// The stanzas of `index` the last publish kept beyond what upstream lists.
+pub(crate) async fn load_retained(
+    ctx: &MirrorCtx,
+    index: &str,
+) -> anyhow::Result<Vec<PackageStanza>> {
+    let path = format!("{}/{}", &ctx.paths().retained, index);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(o) => Ok(parse_stanzas(&o)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path)),
+    }
+}
+
+/// Saves the stanzas of `published` that `upstream` does not list as the
+/// retained versions of `index`. Unlike the generations, which publish
+/// prunes down to --keep, they stay for as long as the policy keeps them.
+pub(crate) async fn save_retained(
+    ctx: &MirrorCtx,
+    index: &str,
+    upstream: &[PackageStanza],
+    published: &[PackageStanza],
+) -> anyhow::Result<()> {
+    let listed: HashSet<VersionKey> = upstream.iter().map(key_of).collect();
+    let text: String = published
+        .iter()
+        .filter(|x| !listed.contains(&key_of(x)))
+        .map(|x| x.to_text())
+        .collect();
+    let path = format!("{}/{}", &ctx.paths().retained, index);
+    if let Some(parent) = std::path::Path::new(&path).parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, text)
+        .await
+        .with_context(|| format!("failed to write {}", tmp))?;
+    tokio::fs::rename(&tmp, &path)
+        .await
+        .with_context(|| format!("failed to move {} to {}", tmp, path))
+}
+
+/// Adds superseded versions from older generations and from the retained
+/// stanzas back into the index, as long as the retention policy keeps them
+/// and their blob is still in the store. Returns the stanzas to publish and
+/// whether anything changed.
+pub(crate) async fn apply_retention(
+    retention: &Retention,
+    history: &IndexHistory,
+    first_seen: &FirstSeen,
+    mut stanzas: Vec<PackageStanza>,
+) -> anyhow::Result<(Vec<PackageStanza>, bool)> {
+    if !retention.is_active() {
+        return Ok((stanzas, false));
+    }
+
+    let mut known: HashSet<VersionKey> = stanzas.iter().map(key_of).collect();
+    let mut candidates: Vec<&PackageStanza> = Vec::new();
+    for x in history.stanzas() {
+        if known.insert(key_of(x)) && tokio::fs::try_exists(blob_path(x.sha256())).await? {
+            candidates.push(x);
+        }
+    }
+    let kept = select_kept(retention, first_seen, &stanzas, candidates);
+    let changed = !kept.is_empty();
+    stanzas.extend(kept.into_iter().cloned());
+    Ok((stanzas, changed))
+}
+
+/// The candidates the policy keeps next to `stanzas`: those among the
+/// newest `keep_versions` of their package and architecture, and those
+/// first seen less than `keep_days` ago.
+fn select_kept<'a>(
+    retention: &Retention,
+    first_seen: &FirstSeen,
+    stanzas: &[PackageStanza],
+    candidates: Vec<&'a PackageStanza>,
+) -> Vec<&'a PackageStanza> {
+    let mut versions: HashMap<(String, String), Vec<String>> = HashMap::new();
+    for x in stanzas.iter().chain(candidates.iter().copied()) {
+        versions
+            .entry((x.package().to_string(), x.architecture().to_string()))
+            .or_default()
+            .push(x.version().to_string());
+    }
+    for v in versions.values_mut() {
+        v.sort_by(|a, b| compare_versions(b, a));
+    }
+
+    candidates
+        .into_iter()
+        .filter(|x| {
+            let key = key_of(x);
+            let rank = versions
+                .get(&(key.0.clone(), key.1.clone()))
+                .and_then(|v| v.iter().position(|y| y.eq(&key.2)))
+                .unwrap_or(usize::MAX);
+            rank < retention.keep_versions || first_seen.age_days(&key) < retention.keep_days
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86400;
+    const NOW: u64 = 1_792_231_200;
+
+    fn stanzas(versions: &[&str]) -> Vec<PackageStanza> {
+        let text: String = versions
+            .iter()
+            .map(|x| format!("Package: hello\nArchitecture: amd64\nVersion: {}\n\n", x))
+            .collect();
+        parse_stanzas(&text)
+    }
+
+    fn first_seen(ages: &[(&str, u64)]) -> FirstSeen {
+        FirstSeen {
+            seen: ages
+                .iter()
+                .map(|(v, days)| {
+                    let key = (String::from("hello"), String::from("amd64"), v.to_string());
+                    (key, NOW - days * DAY)
+                })
+                .collect(),
+            published: HashSet::new(),
+            now: NOW,
+            path: String::new(),
+        }
+    }
+
+    fn kept(retention: Retention, seen: &FirstSeen, older: &[&str]) -> Vec<String> {
+        let upstream = stanzas(&["2.0"]);
+        let older = stanzas(older);
+        select_kept(&retention, seen, &upstream, older.iter().collect())
+            .iter()
+            .map(|x| x.version().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_newest_versions() {
+        let retention = Retention {
+            keep_versions: 3,
+            keep_days: 0,
+        };
+        let seen = first_seen(&[]);
+        assert_eq!(
+            kept(retention, &seen, &["1.0", "1.9", "1.10", "1.2"]),
+            ["1.9", "1.10"]
+        );
+        assert!(kept(Retention::default(), &seen, &["1.9"]).is_empty());
+    }
+
+    #[test]
+    fn keeps_recent_versions() {
+        let retention = Retention {
+            keep_versions: 1,
+            keep_days: 7,
+        };
+        let seen = first_seen(&[("1.0", 30), ("1.1", 6), ("1.2", 7)]);
+        assert_eq!(kept(retention, &seen, &["1.0", "1.1", "1.2"]), ["1.1"]);
+        // Never seen before counts as seen now.
+        assert_eq!(kept(retention, &seen, &["1.3"]), ["1.3"]);
+    }
+
+    #[test]
+    fn versions_are_ranked_per_architecture() {
+        let retention = Retention {
+            keep_versions: 2,
+            keep_days: 0,
+        };
+        let older = parse_stanzas(
+            "Package: hello\nArchitecture: arm64\nVersion: 1.0\n\n\
+             Package: hello\nArchitecture: amd64\nVersion: 1.0\n\n\
+             Package: hello\nArchitecture: amd64\nVersion: 1.5\n\n",
+        );
+        let seen = first_seen(&[]);
+        let upstream = stanzas(&["2.0"]);
+        let kept: Vec<(&str, &str)> =
+            select_kept(&retention, &seen, &upstream, older.iter().collect())
+                .iter()
+                .map(|x| (x.architecture(), x.version()))
+                .collect();
+        assert_eq!(kept, [("arm64", "1.0"), ("amd64", "1.5")]);
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use crate::snapshot::civil_from_days;
+
+/// When the daemon syncs a mirror: every fixed interval such as `6h`, or on
+/// a five field cron expression such as `30 */6 * * *`, taken as UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Cron),
+}
+
+/// The minutes, hours, days of month, months and weekdays a cron expression
+/// matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Whether day of month and weekday were both restricted, in which case
+    /// cron runs on days matching either of them.
+    either_day: bool,
+}
+
+/// Parses a duration such as `90s`, `15m`, `6h` or `1d`, seconds without a
+/// unit.
+pub fn parse_duration(text: &str) -> anyhow::Result<Duration> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|x: char| !x.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow::format_err!("invalid duration {:?}", text))?;
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(anyhow::format_err!("invalid duration {:?}", text)),
+    };
+    let seconds = number
+        .checked_mul(scale)
+        .ok_or_else(|| anyhow::format_err!("duration {:?} is too long", text))?;
+    if seconds == 0 {
+        return Err(anyhow::format_err!("duration {:?} must not be zero", text));
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parses one cron field into a table of `max - min + 1` flags.
+fn parse_field(field: &str, min: usize, max: usize) -> anyhow::Result<Vec<bool>> {
+    let mut ret = vec![false; max - min + 1];
+    let invalid = || anyhow::format_err!("invalid cron field {:?}", field);
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, s)) => (r, s.parse::<usize>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (
+                a.parse().map_err(|_| invalid())?,
+                b.parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let a = range.parse().map_err(|_| invalid())?;
+            // `5/15` means from 5 to the end in steps of 15.
+            (a, if part.contains('/') { max } else { a })
+        };
+        if step == 0 || first < min || last > max || first > last {
+            return Err(invalid());
+        }
+        for x in (first..=last).step_by(step) {
+            ret[x - min] = true;
+        }
+    }
+    Ok(ret)
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> anyhow::Result<Cron> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(anyhow::format_err!(
+                "cron expression {:?} needs five fields",
+                text
+            ));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            either_day: fields[2] != "*" && fields[4] != "*",
+        })
+    }
+
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        if !self.months[month as usize - 1] {
+            return false;
+        }
+        // 1970-01-01 was a Thursday.
+        let weekday = self.weekdays[(days + 4).rem_euclid(7) as usize];
+        let day = self.days[day as usize - 1];
+        if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// First matching minute strictly after unix time `seconds`.
+    pub fn next_after(&self, seconds: u64) -> Option<u64> {
+        let start = seconds / 60 + 1;
+        let first_day = (start / 1440) as i64;
+        let mut minute_of_day = (start % 1440) as usize;
+        // Every valid expression matches within a leap year cycle.
+        for day in first_day..first_day + 366 * 8 {
+            if self.matches_day(day) {
+                for x in minute_of_day..1440 {
+                    if self.hours[x / 60] && self.minutes[x % 60] {
+                        return Some((day as u64 * 1440 + x as u64) * 60);
+                    }
+                }
+            }
+            minute_of_day = 0;
+        }
+        None
+    }
+}
+
+impl Schedule {
+    /// Parses a cron expression if `text` has several fields, an interval
+    /// otherwise.
+    pub fn parse(text: &str) -> anyhow::Result<Schedule> {
+        if text.split_whitespace().count() > 1 {
+            Ok(Schedule::Cron(Cron::parse(text)?))
+        } else {
+            Ok(Schedule::Interval(parse_duration(text)?))
+        }
+    }
+
+    /// Unix time of the run after the one at `previous`. An interval counts
+    /// from the previous scheduled run, so slow syncs do not make it drift.
+    pub fn next_after(&self, previous: u64) -> Option<u64> {
+        match self {
+            Schedule::Interval(o) => Some(previous + o.as_secs()),
+            Schedule::Cron(o) => o.next_after(previous),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Saturday 2026-10-17 10:00:00 UTC.
+    const SATURDAY: u64 = 1_792_231_200;
+    const HOUR: u64 = 3600;
+
+    fn next(cron: &str, after: u64) -> Option<u64> {
+        Cron::parse(cron).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(
+            parse_duration(" 6h ").unwrap(),
+            Duration::from_secs(6 * HOUR)
+        );
+        assert_eq!(
+            parse_duration("1d").unwrap(),
+            Duration::from_secs(24 * HOUR)
+        );
+        assert_eq!(parse_duration("42").unwrap(), Duration::from_secs(42));
+        for x in [
+            "",
+            "0",
+            "0h",
+            "h",
+            "5x",
+            "1.5h",
+            "-1h",
+            "18446744073709551615h",
+        ] {
+            assert!(parse_duration(x).is_err(), "{:?}", x);
+        }
+    }
+
+    #[test]
+    fn cron_runs_strictly_after() {
+        let at = SATURDAY + 2 * HOUR + 30 * 60;
+        assert_eq!(next("30 */6 * * *", SATURDAY), Some(at));
+        assert_eq!(next("30 */6 * * *", at), Some(at + 6 * HOUR));
+        assert_eq!(next("* * * * *", SATURDAY), Some(SATURDAY + 60));
+        assert_eq!(next("0,15 10 * * *", SATURDAY), Some(SATURDAY + 15 * 60));
+        assert_eq!(next("5/20 10-11 * * *", SATURDAY), Some(SATURDAY + 5 * 60));
+    }
+
+    #[test]
+    fn cron_matches_days() {
+        let midnight = SATURDAY - 10 * HOUR;
+        // Monday, and Sunday written both ways.
+        assert_eq!(next("0 0 * * 1", SATURDAY), Some(midnight + 48 * HOUR));
+        assert_eq!(next("0 0 * * 0", SATURDAY), Some(midnight + 24 * HOUR));
+        assert_eq!(next("0 0 * * 7", SATURDAY), Some(midnight + 24 * HOUR));
+        // Day of month and weekday both set match either.
+        assert_eq!(next("0 0 1 * 0", SATURDAY), Some(midnight + 24 * HOUR));
+        assert_eq!(
+            next("0 0 1 11 *", SATURDAY),
+            Some(midnight + 15 * 24 * HOUR)
+        );
+        assert_eq!(next("0 0 31 2 *", SATURDAY), None);
+    }
+
+    #[test]
+    fn cron_rejects_invalid_fields() {
+        for x in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Cron::parse(x).is_err(), "{:?}", x);
+        }
+    }
+
+    #[test]
+    fn schedules_pick_their_kind() {
+        let interval = Schedule::parse("6h").unwrap();
+        assert_eq!(interval, Schedule::Interval(Duration::from_secs(6 * HOUR)));
+        assert_eq!(interval.next_after(SATURDAY), Some(SATURDAY + 6 * HOUR));
+        assert!(matches!(
+            Schedule::parse("0 * * * *"),
+            Ok(Schedule::Cron(_))
+        ));
+        assert!(Schedule::parse("0 *").is_err());
+    }
+}
@@ -0,0 +1,511 @@
+use anyhow::Context;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::gc::now_seconds;
+use crate::http;
+use crate::http::http_date;
+use crate::http::log_date;
+use crate::http::Body;
+use crate::http::Request;
+use crate::http::Response;
+use crate::mirrors::mirror_names;
+use crate::mirrors::MirrorCtx;
+use crate::paths::paths;
+use tracing::info;
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+
+/// Top level directories of a mirror tree that are served, everything else
+/// is bookkeeping.
+const SERVED: [&str; 3] = ["dists", "pool", "project"];
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct ServeOptions {
+    /// Address to serve the mirror on
+    #[arg(long, default_value = DEFAULT_LISTEN)]
+    pub listen: String,
+
+    /// Append requests to this file in the combined log format, instead of
+    /// logging them
+    #[arg(long, value_name = "PATH")]
+    pub access_log: Option<PathBuf>,
+}
+
+/// Serves the published trees read-only.
+struct FileServer {
+    /// Mirror subtrees by URL prefix, a single unnamed one without a
+    /// mirrors file.
+    trees: Vec<(String, PathBuf)>,
+    /// Where pool symlinks may point: the mirror root and the store.
+    allowed: Vec<PathBuf>,
+    access_log: AccessLog,
+}
+
+/// Content type by file name, apt does not care but browsers and proxies
+/// do.
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|x| x.1).unwrap_or("");
+    match extension {
+        "deb" | "udeb" | "ddeb" => "application/vnd.debian.binary-package",
+        "xz" => "application/x-xz",
+        "gz" => "application/gzip",
+        "bz2" => "application/x-bzip2",
+        "zst" => "application/zstd",
+        "lz4" => "application/x-lz4",
+        "tar" => "application/x-tar",
+        "gpg" | "asc" => "application/pgp-signature",
+        "html" => "text/html; charset=utf-8",
+        "json" => "application/json",
+        "dsc" | "changes" | "buildinfo" | "txt" | "diff" => "text/plain; charset=utf-8",
+        _ if matches!(
+            name,
+            "Release" | "InRelease" | "Packages" | "Sources" | "Index" | "Contents"
+        ) =>
+        {
+            "text/plain; charset=utf-8"
+        }
+        _ => "application/octet-stream",
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn href_escape(text: &str) -> String {
+    let mut ret = String::new();
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~+:".contains(&b) {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+fn modified_seconds(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parses a single `bytes=` range against a file of `size` bytes into an
+/// offset and length. None when there is no usable range, in which case the
+/// whole file is sent, Some(Err) when it can not be satisfied.
+fn parse_range(header: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    // Several ranges would need a multipart answer, the whole file will do.
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        let length = suffix.min(size);
+        return Some(Ok((size - length, length)));
+    }
+    let first: u64 = first.parse().ok()?;
+    let last: u64 = if last.is_empty() {
+        size.saturating_sub(1)
+    } else {
+        last.parse::<u64>().ok()?.min(size.saturating_sub(1))
+    };
+    if first >= size || last < first {
+        return Some(Err(()));
+    }
+    Some(Ok((first, last - first + 1)))
+}
+
+impl FileServer {
+    /// The published tree below `base`: the current generation once there
+    /// is one, the tree link_pool builds in place before that.
+    fn published(base: &Path) -> PathBuf {
+        let current = base.join(&paths().current);
+        if current.exists() {
+            current
+        } else {
+            base.to_path_buf()
+        }
+    }
+
+    /// A top level directory of a tree. Trace files change with every sync,
+    /// so project/ comes from the tree itself rather than the published
+    /// snapshot.
+    fn top_dir(base: &Path, name: &str) -> PathBuf {
+        if name == "project" {
+            base.join(name)
+        } else {
+            Self::published(base).join(name)
+        }
+    }
+
+    /// Maps a request path to a file, None for paths outside the served
+    /// directories. The second value is the path below the tree, to tell
+    /// the tree roots apart.
+    fn resolve(&self, path: &str) -> Option<(PathBuf, Vec<String>)> {
+        let parts: Vec<&str> = path.split('/').filter(|x| !x.is_empty()).collect();
+        if parts
+            .iter()
+            .any(|x| *x == "." || *x == ".." || x.contains('\0'))
+        {
+            return None;
+        }
+        let (base, rest) = if self.single() {
+            (&self.trees[0].1, &parts[..])
+        } else {
+            let first = parts.first()?;
+            let (_, base) = self.trees.iter().find(|(name, _)| name == first)?;
+            (base, &parts[1..])
+        };
+        if let Some(top) = rest.first() {
+            if !SERVED.contains(top) {
+                return None;
+            }
+        }
+        let mut ret = match rest.first() {
+            Some(top) => Self::top_dir(base, top),
+            None => base.clone(),
+        };
+        for x in rest.iter().skip(1) {
+            ret.push(x);
+        }
+        Some((ret, rest.iter().map(|x| x.to_string()).collect()))
+    }
+
+    /// Whether a single tree is served at the top rather than every mirror
+    /// below its name.
+    fn single(&self) -> bool {
+        self.trees.len() == 1 && self.trees[0].0.is_empty()
+    }
+
+    /// Whether a resolved path stays inside the mirror root or the store,
+    /// symlinks of the pool point into the latter.
+    fn allowed(&self, path: &Path) -> bool {
+        match path.canonicalize() {
+            Ok(o) => self.allowed.iter().any(|x| o.starts_with(x)),
+            Err(_) => false,
+        }
+    }
+
+    /// Lists the mirrors, the served directories of a tree, or a directory
+    /// below them.
+    async fn listing(&self, request: &Request, dir: Option<&Path>, rest: &[String]) -> Response {
+        let mut entries = Vec::new();
+        let dir = match dir {
+            Some(o) => o,
+            None => {
+                for (name, _) in self.trees.iter() {
+                    entries.push((format!("{}/", name), None, None));
+                }
+                return listing_page(&request.path, entries);
+            }
+        };
+        if rest.is_empty() {
+            for name in SERVED.iter() {
+                if Self::top_dir(dir, name).is_dir() {
+                    entries.push((format!("{}/", name), None, None));
+                }
+            }
+        } else {
+            let mut reader = match tokio::fs::read_dir(dir).await {
+                Ok(o) => o,
+                Err(_) => return Response::text(404, "not found"),
+            };
+            while let Ok(Some(entry)) = reader.next_entry().await {
+                let name = entry.file_name().to_string_lossy().to_string();
+                // Follows the symlinks of the pool to the blobs.
+                let metadata = match tokio::fs::metadata(entry.path()).await {
+                    Ok(o) => o,
+                    Err(_) => continue,
+                };
+                if metadata.is_dir() {
+                    entries.push((
+                        format!("{}/", name),
+                        None,
+                        Some(modified_seconds(&metadata)),
+                    ));
+                } else {
+                    entries.push((
+                        name,
+                        Some(metadata.len()),
+                        Some(modified_seconds(&metadata)),
+                    ));
+                }
+            }
+        }
+        listing_page(&request.path, entries)
+    }
+
+    async fn respond(&self, request: &Request) -> Response {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Response::text(405, "method not allowed").header("Allow", "GET, HEAD");
+        }
+        if !self.single() && request.path == "/" {
+            return self.listing(request, None, &[]).await;
+        }
+        let (path, rest) = match self.resolve(&request.path) {
+            Some(o) => o,
+            None => return Response::text(404, "not found"),
+        };
+        let metadata = match tokio::fs::metadata(&path).await {
+            Ok(o) => o,
+            Err(_) => return Response::text(404, "not found"),
+        };
+        if !self.allowed(&path) {
+            return Response::text(403, "forbidden");
+        }
+        if metadata.is_dir() {
+            if !request.path.ends_with('/') {
+                let location = format!("{}/", href_path(&request.path));
+                return Response::text(301, "moved").header("Location", location);
+            }
+            return self.listing(request, Some(&path), &rest).await;
+        }
+
+        let name = rest.last().map(|x| x.as_str()).unwrap_or("");
+        file_response(request, &path, name).await
+    }
+}
+
+/// An HTML page listing `entries` of name, size and modification time,
+/// directories named with a trailing slash.
+fn listing_page(path: &str, mut entries: Vec<(String, Option<u64>, Option<u64>)>) -> Response {
+    entries.sort();
+    let title = html_escape(path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {}</title></head><body>\n<h1>Index of {}</h1>\n<pre>\n",
+        title, title
+    );
+    if path != "/" {
+        html.push_str("<a href=\"../\">../</a>\n");
+    }
+    for (name, size, modified) in entries {
+        let link = href_escape(name.trim_end_matches('/'));
+        let link = if name.ends_with('/') {
+            format!("{}/", link)
+        } else {
+            link
+        };
+        let shown = html_escape(&name);
+        let pad = 60usize.saturating_sub(name.chars().count());
+        html.push_str(&format!(
+            "<a href=\"{}\">{}</a>{:pad$} {:>20} {:>14}\n",
+            link,
+            shown,
+            "",
+            modified.map(http_date).unwrap_or_default(),
+            size.map(|x| x.to_string())
+                .unwrap_or_else(|| String::from("-")),
+            pad = pad
+        ));
+    }
+    html.push_str("</pre>\n</body></html>\n");
+    Response::new(200, "text/html; charset=utf-8", html)
+}
+
+/// Where requests are logged: appended to a file in the combined log
+/// format, or logged as info events on the `deb_mirror::access` target,
+/// which the default filter lets through, without one.
+pub(crate) struct AccessLog(Option<Mutex<std::fs::File>>);
+
+impl AccessLog {
+    pub(crate) fn open(path: Option<&Path>) -> anyhow::Result<AccessLog> {
+        match path {
+            Some(path) => Ok(AccessLog(Some(Mutex::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open {}", path.display()))?,
+            )))),
+            None => Ok(AccessLog(None)),
+        }
+    }
+
+    pub(crate) fn log(&self, request: &Request, response: &Response) {
+        let referer = request.header("Referer").unwrap_or("-");
+        let agent = request.header("User-Agent").unwrap_or("-");
+        match &self.0 {
+            Some(file) => {
+                let line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"\n",
+                    request.peer.ip(),
+                    log_date(now_seconds()),
+                    request.method,
+                    href_path(&request.path),
+                    request.version,
+                    response.status,
+                    response.body.len(),
+                    referer.replace('"', "\\\""),
+                    agent.replace('"', "\\\"")
+                );
+                let _ = file.lock().unwrap().write_all(line.as_bytes());
+            }
+            None => info!(
+                target: "deb_mirror::access",
+                peer = %request.peer.ip(),
+                method = %request.method,
+                path = %request.path,
+                status = response.status,
+                bytes = response.body.len(),
+                agent,
+                "request"
+            ),
+        }
+    }
+}
+
+/// Sends the file at `path`, named `name`, honouring conditional and range
+/// requests. Symlinks are followed.
+pub(crate) async fn file_response(request: &Request, path: &Path, name: &str) -> Response {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(o) => o,
+        Err(_) => return Response::text(404, "not found"),
+    };
+    let size = metadata.len();
+    let modified = http_date(modified_seconds(&metadata));
+    if request.header("If-Modified-Since") == Some(modified.as_str()) {
+        return Response::new(304, content_type(name), Vec::new())
+            .header("Last-Modified", modified);
+    }
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(o) => o,
+        Err(_) => return Response::text(404, "not found"),
+    };
+    // A range only applies to the version the client already has part of.
+    let range = match (request.header("Range"), request.header("If-Range")) {
+        (Some(_), Some(x)) if x != modified => None,
+        (Some(x), _) => parse_range(x, size),
+        (None, _) => None,
+    };
+    let (status, offset, length) = match range {
+        None => (200, 0, size),
+        Some(Ok((offset, length))) => (206, offset, length),
+        Some(Err(())) => {
+            return Response::text(416, "range not satisfiable")
+                .header("Content-Range", format!("bytes */{}", size));
+        }
+    };
+    let mut response = Response {
+        status,
+        headers: vec![(String::from("Content-Type"), content_type(name).to_string())],
+        body: Body::File {
+            file,
+            offset,
+            length,
+        },
+    }
+    .header("Last-Modified", modified)
+    .header("Accept-Ranges", "bytes");
+    if status == 206 {
+        response = response.header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", offset, offset + length - 1, size),
+        );
+    }
+    response
+}
+
+/// Escapes a decoded request path again for a Location header or the log.
+fn href_path(path: &str) -> String {
+    path.split('/')
+        .map(href_escape)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Resolves the trees to serve, only the `selected` mirror if there is one,
+/// and binds the listen address, returning the server to run.
+pub(crate) async fn start_server(
+    selected: Option<&MirrorCtx>,
+    options: &ServeOptions,
+) -> anyhow::Result<impl std::future::Future<Output = anyhow::Result<()>> + Send + 'static> {
+    let root = MirrorCtx::root();
+    let trees = match selected {
+        Some(o) if o.name().is_some() => vec![(String::new(), o.dir().to_path_buf())],
+        _ if !mirror_names().is_empty() => mirror_names()
+            .into_iter()
+            .map(|x| {
+                let dir = root.dir().join(&x);
+                (x, dir)
+            })
+            .collect(),
+        _ => vec![(String::new(), root.dir().to_path_buf())],
+    };
+    let mut allowed = vec![root.dir().canonicalize()?];
+    if let Ok(o) = Path::new(&paths().store).canonicalize() {
+        allowed.push(o);
+    }
+    let access_log = AccessLog::open(options.access_log.as_deref())?;
+    let server = Arc::new(FileServer {
+        trees,
+        allowed,
+        access_log,
+    });
+
+    let listener = tokio::net::TcpListener::bind(&options.listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", options.listen))?;
+    info!(listen = %options.listen, "serving the mirror");
+    Ok(http::serve(listener, move |request| {
+        let server = Arc::clone(&server);
+        async move {
+            let response = server.respond(&request).await;
+            server.access_log.log(&request, &response);
+            response
+        }
+    }))
+}
+
+/// Serves the published dists and pool trees of every mirror below its
+/// name, or of the selected mirror or legacy tree at the top, with range
+/// requests and directory listings. Pool symlinks into the store are
+/// followed, anything else leading outside the mirror root is refused.
+pub async fn serve(selected: Option<&MirrorCtx>, options: &ServeOptions) -> anyhow::Result<()> {
+    start_server(selected, options).await?.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 100))));
+        assert_eq!(parse_range(" bytes=500- ", 1000), Some(Ok((500, 500))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 100))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Ok((0, 1000))));
+        assert_eq!(parse_range("bytes=900-5000", 1000), Some(Ok((900, 100))));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_errors() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=10-5", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-", 0), Some(Err(())));
+    }
+
+    #[test]
+    fn other_ranges_are_ignored() {
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=5", 1000), None);
+    }
+}
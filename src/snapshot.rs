@@ -0,0 +1,533 @@
+use anyhow::Context;
+use futures::StreamExt;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+use crate::download_dist::has_packages;
+use crate::download_dist::link_pool_in_dist_under;
+use crate::download_dist::package_pair_list;
+use crate::download_dist::read_list_dist_packages;
+use crate::download_dist::read_packages_files;
+use crate::download_dist::relative_target;
+use crate::link_mode::link_file;
+use crate::link_mode::link_file_reusing;
+use crate::mirrors::MirrorCtx;
+use crate::packages::compare_versions;
+use crate::packages::read_stanzas;
+use crate::packages::PackageStanza;
+use crate::pins::apply_pins;
+use crate::pins::read_pins;
+use crate::release::encode_for;
+use crate::release::rewrite_release;
+use crate::retention::apply_retention;
+use crate::retention::load_retained;
+use crate::retention::save_retained;
+use crate::retention::FirstSeen;
+use crate::retention::Retention;
+use crate::store::blob_path;
+use crate::store::lock_store;
+use tracing::info;
+
+pub(crate) const DEFAULT_KEEP: usize = 3;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct PublishOptions {
+    /// Number of generations to keep, including the new one
+    #[arg(long, default_value_t = DEFAULT_KEEP)]
+    pub keep: usize,
+
+    #[command(flatten)]
+    pub retention: Retention,
+}
+
+/// Civil date (year, month, day) of a day counted from 1970-01-01, after
+/// Howard Hinnant.
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Days from 1970-01-01 to a civil date, the inverse of civil_from_days.
+pub(crate) fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Formats unix seconds as a UTC timestamp such as `20240131T235959Z`, the
+/// naming used by snapshot.debian.org.
+pub(crate) fn timestamp_name(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let rem = seconds % 86400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem / 60) % 60,
+        rem % 60
+    )
+}
+
+/// Names of all generations below snapshots/, oldest first.
+pub(crate) async fn list_snapshots(ctx: &MirrorCtx) -> anyhow::Result<Vec<String>> {
+    let mut ret = Vec::new();
+    let mut entries = match tokio::fs::read_dir(&ctx.paths().snapshots).await {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(ret),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to list {}", &ctx.paths().snapshots))
+        }
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await?.is_dir() && !name.starts_with('.') {
+            ret.push(name);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Name of the generation the current symlink points at, if any.
+pub(crate) async fn current_snapshot(ctx: &MirrorCtx) -> Option<String> {
+    let target = tokio::fs::read_link(&ctx.paths().current).await.ok()?;
+    target.file_name().map(|x| x.to_string_lossy().to_string())
+}
+
+/// Every Packages file found below the dists tree of a generation.
+pub(crate) async fn snapshot_packages_files(
+    ctx: &MirrorCtx,
+    name: &str,
+) -> anyhow::Result<Vec<String>> {
+    let mut ret = Vec::new();
+    let mut stack = vec![format!("{}/{}/dists", &ctx.paths().snapshots, name)];
+    while let Some(dir) = stack.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to list {}", dir)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                stack.push(path);
+            } else if file_type.is_file() && has_packages(&path) {
+                ret.push(path);
+            }
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Pool entries of the Packages files in a generation.
+pub(crate) async fn snapshot_packages(
+    ctx: &MirrorCtx,
+    name: &str,
+) -> anyhow::Result<package_pair_list> {
+    let files = snapshot_packages_files(ctx, name).await?;
+    if files.is_empty() {
+        return Ok(package_pair_list::new());
+    }
+    let files: Vec<&str> = files.iter().map(|x| x.as_str()).collect();
+    read_packages_files(ctx, &files).await
+}
+
+/// Stanzas of the same index in older generations, the current one first
+/// and the rest newest first.
+pub(crate) struct IndexHistory {
+    current: Vec<PackageStanza>,
+    older: Vec<PackageStanza>,
+}
+
+impl IndexHistory {
+    /// Loads the index at `index` (relative to a generation, e.g.
+    /// `dists/bookworm/main/binary-amd64/Packages`) from every snapshot, and
+    /// the versions kept of it that may be in none of them any more.
+    pub(crate) async fn load(ctx: &MirrorCtx, index: &str) -> anyhow::Result<IndexHistory> {
+        let current_name = current_snapshot(ctx).await;
+        let mut ret = IndexHistory {
+            current: Vec::new(),
+            older: Vec::new(),
+        };
+        for name in list_snapshots(ctx).await?.iter().rev() {
+            let path = format!("{}/{}/{}", &ctx.paths().snapshots, name, index);
+            if !tokio::fs::try_exists(&path).await? {
+                continue;
+            }
+            let stanzas = read_stanzas(&path).await?;
+            if current_name.as_deref() == Some(name.as_str()) {
+                ret.current = stanzas;
+            } else {
+                ret.older.extend(stanzas);
+            }
+        }
+        ret.older.extend(load_retained(ctx, index).await?);
+        Ok(ret)
+    }
+
+    pub(crate) fn current_version(&self, package: &str) -> Option<&str> {
+        self.current
+            .iter()
+            .filter(|x| x.package() == package)
+            .map(|x| x.version())
+            .max_by(|a, b| compare_versions(a, b))
+    }
+
+    pub(crate) fn find(&self, package: &str, version: &str) -> Option<&PackageStanza> {
+        self.current
+            .iter()
+            .chain(self.older.iter())
+            .find(|x| x.package() == package && x.version() == version)
+    }
+
+    /// Package names served by the current generation.
+    pub(crate) fn packages(&self) -> impl Iterator<Item = &str> {
+        self.current.iter().map(|x| x.package())
+    }
+
+    /// Every stanza of the index in any generation, current first.
+    pub(crate) fn stanzas(&self) -> impl Iterator<Item = &PackageStanza> {
+        self.current.iter().chain(self.older.iter())
+    }
+}
+
+/// Points `current` at the given generation by renaming a fresh symlink over
+/// it, so clients see either the old or the new tree and never a mix. The
+/// link is relative to the directory holding it, wherever that is.
+pub(crate) async fn switch_current(ctx: &MirrorCtx, name: &str) -> anyhow::Result<()> {
+    let snapshot = format!("{}/{}", &ctx.paths().snapshots, name);
+    let target = relative_target(&ctx.paths().current, &snapshot)?;
+    let tmp = format!("{}.tmp", &ctx.paths().current);
+    let _ = tokio::fs::remove_file(&tmp).await;
+    tokio::fs::symlink(&target, &tmp)
+        .await
+        .with_context(|| format!("Failed creating symlink from {} to {}", tmp, target))?;
+    tokio::fs::rename(&tmp, &ctx.paths().current)
+        .await
+        .with_context(|| format!("Failed to move {} to {}", tmp, &ctx.paths().current))
+}
+
+/// Removes all but the newest `keep` generations, never the current one.
+pub(crate) async fn prune_snapshots(ctx: &MirrorCtx, keep: usize) -> anyhow::Result<()> {
+    let snapshots = list_snapshots(ctx).await?;
+    let current = current_snapshot(ctx).await;
+    if snapshots.len() <= keep {
+        return Ok(());
+    }
+    for name in snapshots[..snapshots.len() - keep].iter() {
+        if current.as_deref() == Some(name.as_str()) {
+            continue;
+        }
+        let path = format!("{}/{}", &ctx.paths().snapshots, name);
+        info!(snapshot = %path, "removing old snapshot");
+        tokio::fs::remove_dir_all(&path)
+            .await
+            .with_context(|| format!("failed to remove {}", path))?;
+    }
+    Ok(())
+}
+
+/// Strips a .gz or .xz extension, giving the uncompressed index a
+/// compressed variant is generated from.
+fn uncompressed_name(file: &str) -> &str {
+    file.strip_suffix(".gz")
+        .or_else(|| file.strip_suffix(".xz"))
+        .unwrap_or(file)
+}
+
+/// Builds a new generation in snapshots/<timestamp> from the downloaded dists
+/// tree and the store, switches `current` to it and drops all but the newest
+/// `keep` generations. Packages indices are regenerated when the retention
+/// policy keeps older versions or pins hold a package back, and the Release
+/// checksums are updated to match.
+pub async fn publish_snapshot(ctx: &MirrorCtx, options: &PublishOptions) -> anyhow::Result<()> {
+    if options.keep == 0 || options.retention.keep_versions == 0 {
+        return Err(anyhow::format_err!(
+            "--keep and --keep-versions must be at least 1"
+        ));
+    }
+    let _store = lock_store(false).await?;
+    let pins = read_pins(ctx).await?;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let name = timestamp_name(now);
+    let root = format!("{}/{}", &ctx.paths().snapshots, name);
+    let building = format!("{}/.{}", &ctx.paths().snapshots, name);
+    if tokio::fs::try_exists(&root).await? {
+        return Err(anyhow::format_err!("snapshot {} already exists", root));
+    }
+    // Leftovers of an interrupted publish are never served, start afresh.
+    let _ = tokio::fs::remove_dir_all(&building).await;
+    tokio::fs::create_dir_all(&building).await?;
+    let prefix = format!("{}/", building);
+
+    let list_dist_packages = read_list_dist_packages(ctx).await?;
+    let mut files: Vec<&str> = Vec::new();
+    for file in list_dist_packages.split('\n').filter(|x| !x.is_empty()) {
+        if tokio::fs::try_exists(ctx.path(file)).await? {
+            files.push(file);
+        }
+    }
+
+    let mirror = ctx.mirror().filter(|x| x.has_filters());
+    let rewrite = !pins.is_empty() || options.retention.is_active();
+    let mut first_seen = FirstSeen::load(ctx, now).await?;
+    let mut regenerated: HashMap<&str, Vec<u8>> = HashMap::new();
+    for file in files.iter().filter(|x| has_packages(x)) {
+        let mut stanzas = read_stanzas(&ctx.path(file)).await?;
+        let mut changed = false;
+        if let Some(mirror) = &mirror {
+            let before = stanzas.len();
+            stanzas.retain(|x| mirror.wants(x.package()));
+            changed = stanzas.len() != before;
+        }
+        if rewrite {
+            let upstream = stanzas.clone();
+            let history = IndexHistory::load(ctx, file).await?;
+            let (retained, c1) =
+                apply_retention(&options.retention, &history, &first_seen, stanzas).await?;
+            let (pinned, c2) = apply_pins(&pins, file, &history, retained).await?;
+            stanzas = pinned;
+            changed = changed || c1 || c2;
+            save_retained(ctx, file, &upstream, &stanzas).await?;
+        }
+        first_seen.record(&stanzas);
+        if changed {
+            let text: String = stanzas.iter().map(|x| x.to_text()).collect();
+            regenerated.insert(file, text.into_bytes());
+        }
+    }
+
+    let mut changed: HashMap<&str, Vec<u8>> = HashMap::new();
+    for file in files.iter() {
+        let dest = format!("{}{}", prefix, file);
+        if let Some(parent) = std::path::Path::new(&dest).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        match regenerated.get(uncompressed_name(file)) {
+            Some(data) => {
+                let data = encode_for(file, data)?;
+                tokio::fs::write(&dest, &data)
+                    .await
+                    .with_context(|| format!("Failed to write {}", dest))?;
+                changed.insert(file, data);
+            }
+            None => {
+                tokio::fs::copy(ctx.path(file), &dest)
+                    .await
+                    .with_context(|| format!("Failed to copy {} to {}", file, dest))?;
+            }
+        }
+        link_pool_in_dist_under(ctx, &prefix, file).await;
+    }
+
+    for file in files.iter().filter(|x| x.ends_with("/Release")) {
+        let dir = &file[..file.len() - "Release".len()];
+        let relative: HashMap<String, Vec<u8>> = changed
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix(dir).map(|x| (x.to_string(), v.clone())))
+            .collect();
+        if relative.is_empty() {
+            continue;
+        }
+        let text = tokio::fs::read_to_string(ctx.path(file)).await?;
+        let dest = format!("{}{}", prefix, file);
+        tokio::fs::write(&dest, rewrite_release(&text, &relative))
+            .await
+            .with_context(|| format!("Failed to write {}", dest))?;
+        // The upstream signatures no longer match the rewritten Release.
+        for signed in ["InRelease", "Release.gpg"] {
+            let _ = tokio::fs::remove_file(format!("{}{}{}", prefix, dir, signed)).await;
+        }
+    }
+
+    let indices: Vec<String> = files
+        .iter()
+        .filter(|x| has_packages(x))
+        .map(|x| format!("{}{}", prefix, x))
+        .collect();
+    let indices: Vec<&str> = indices.iter().map(|x| x.as_str()).collect();
+    let packages = read_packages_files(ctx, &indices).await?;
+
+    let mut num_missing: usize = 0;
+    for x in packages.iter() {
+        if !tokio::fs::try_exists(blob_path(&x.sha256)).await? {
+            num_missing += 1;
+        }
+    }
+    if num_missing > 0 {
+        let _ = tokio::fs::remove_dir_all(&building).await;
+        return Err(anyhow::format_err!(
+            "{} referenced blobs are missing from the store, run the pool download first",
+            num_missing
+        ));
+    }
+
+    // The same pool file is often listed by several architectures.
+    let mut seen = std::collections::HashSet::new();
+    let unique = packages.iter().filter(|x| seen.insert(x.filename.as_str()));
+
+    let previous = current_snapshot(ctx)
+        .await
+        .map(|x| format!("{}/{}/", &ctx.paths().snapshots, x));
+    const NUM_THREADS: usize = 16;
+    let results = futures::stream::iter(unique.map(|x| {
+        let loc = format!("{}{}", prefix, x.filename);
+        let previous = previous.as_ref().map(|p| format!("{}{}", p, x.filename));
+        async move {
+            match previous {
+                Some(o) => link_file_reusing(ctx, &x.sha256, &loc, &o).await,
+                None => link_file(ctx, &x.sha256, &loc).await,
+            }
+        }
+    }))
+    .buffer_unordered(NUM_THREADS)
+    .collect::<Vec<_>>()
+    .await;
+    for result in results {
+        result?;
+    }
+
+    // Links are relative, so they stay valid after the rename.
+    tokio::fs::rename(&building, &root)
+        .await
+        .with_context(|| format!("Failed to move {} to {}", building, root))?;
+    switch_current(ctx, &name).await?;
+    info!(snapshot = %root, "published snapshot");
+    first_seen.save().await?;
+
+    prune_snapshots(ctx, options.keep).await
+}
+
+/// Accepts either a bare generation name or a path such as
+/// `snapshots/<name>` and checks that the generation exists.
+async fn resolve_snapshot(ctx: &MirrorCtx, arg: &str) -> anyhow::Result<String> {
+    let name = std::path::Path::new(arg.trim_end_matches('/'))
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .ok_or_else(|| anyhow::format_err!("invalid snapshot {}", arg))?;
+    if !list_snapshots(ctx).await?.contains(&name) {
+        return Err(anyhow::format_err!("no snapshot named {}", name));
+    }
+    Ok(name)
+}
+
+/// Re-points `current` at an earlier generation after checking that every
+/// blob it references is still in the store.
+pub async fn rollback_snapshot(ctx: &MirrorCtx, arg: &str) -> anyhow::Result<()> {
+    let _store = lock_store(false).await?;
+    let name = resolve_snapshot(ctx, arg).await?;
+
+    let mut num_missing: usize = 0;
+    for x in snapshot_packages(ctx, &name).await? {
+        if !tokio::fs::try_exists(blob_path(&x.sha256)).await? {
+            num_missing += 1;
+        }
+    }
+    if num_missing > 0 {
+        return Err(anyhow::format_err!(
+            "{} blobs referenced by snapshot {} are missing from the store",
+            num_missing,
+            name
+        ));
+    }
+
+    switch_current(ctx, &name).await?;
+    info!(snapshot = %name, "current now points at snapshots/{}", name);
+    Ok(())
+}
+
+/// Versions of every package in a generation, keyed by the index directory
+/// relative to the generation (e.g. `dists/bookworm/main/binary-amd64`) and
+/// the package name.
+async fn snapshot_versions(
+    ctx: &MirrorCtx,
+    name: &str,
+) -> anyhow::Result<BTreeMap<(String, String), Vec<String>>> {
+    let prefix = format!("{}/{}/", &ctx.paths().snapshots, name);
+    let mut ret: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    for file in snapshot_packages_files(ctx, name).await? {
+        let index = file
+            .strip_prefix(&prefix)
+            .unwrap_or(&file)
+            .rsplit_once('/')
+            .map(|(dir, _)| dir.to_string())
+            .unwrap_or_default();
+        for stanza in read_stanzas(&file).await? {
+            ret.entry((index.clone(), stanza.package().to_string()))
+                .or_default()
+                .push(stanza.version().to_string());
+        }
+    }
+    for versions in ret.values_mut() {
+        versions.sort_by(|a, b| compare_versions(a, b));
+        versions.dedup();
+    }
+    Ok(ret)
+}
+
+/// Lists packages added (`+`), removed (`-`), upgraded (`^`) and downgraded
+/// (`v`) between two generations, comparing the newest version of each
+/// package per index.
+pub async fn diff_snapshots(ctx: &MirrorCtx, a: &str, b: &str) -> anyhow::Result<()> {
+    let old = snapshot_versions(ctx, &resolve_snapshot(ctx, a).await?).await?;
+    let new = snapshot_versions(ctx, &resolve_snapshot(ctx, b).await?).await?;
+
+    let (mut num_added, mut num_removed, mut num_upgraded, mut num_downgraded) = (0, 0, 0, 0);
+
+    let keys: BTreeSet<&(String, String)> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        let (index, package) = key;
+        match (old.get(key), new.get(key)) {
+            (None, Some(v)) => {
+                println!("+ {} {} {}", index, package, v.join(" "));
+                num_added += 1;
+            }
+            (Some(v), None) => {
+                println!("- {} {} {}", index, package, v.join(" "));
+                num_removed += 1;
+            }
+            (Some(x), Some(y)) => {
+                let (x, y) = (x.last().unwrap(), y.last().unwrap());
+                match compare_versions(x, y) {
+                    Ordering::Less => {
+                        println!("^ {} {} {} -> {}", index, package, x, y);
+                        num_upgraded += 1;
+                    }
+                    Ordering::Greater => {
+                        println!("v {} {} {} -> {}", index, package, x, y);
+                        num_downgraded += 1;
+                    }
+                    Ordering::Equal => {}
+                }
+            }
+            (None, None) => {}
+        }
+    }
+
+    println!(
+        "{} added, {} removed, {} upgraded, {} downgraded",
+        num_added, num_removed, num_upgraded, num_downgraded
+    );
+    Ok(())
+}
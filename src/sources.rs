@@ -0,0 +1,115 @@
+/// One line of a sources.list, e.g.
+/// `deb [arch=amd64] http://deb.debian.org/debian bookworm main contrib`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceEntry {
+    /// `deb-src` rather than `deb`.
+    pub source: bool,
+    /// The bracketed options, as `name=value` strings.
+    pub options: Vec<String>,
+    pub url: String,
+    pub suite: String,
+    pub components: Vec<String>,
+}
+
+impl SourceEntry {
+    /// Parses one line, returning `None` for comments, blank lines and
+    /// anything that is not a complete `deb` or `deb-src` entry.
+    pub fn parse(line: &str) -> Option<SourceEntry> {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let source = match words.next()? {
+            "deb" => false,
+            "deb-src" => true,
+            _ => return None,
+        };
+
+        let mut options = Vec::new();
+        let mut word = words.next()?;
+        if let Some(rest) = word.strip_prefix('[') {
+            let mut rest = rest.to_string();
+            loop {
+                let done = rest.ends_with(']');
+                let option = rest.trim_end_matches(']');
+                if !option.is_empty() {
+                    options.push(option.to_string());
+                }
+                if done {
+                    break;
+                }
+                rest = words.next()?.to_string();
+            }
+            word = words.next()?;
+        }
+
+        Some(SourceEntry {
+            source,
+            options,
+            url: word.to_string(),
+            suite: words.next()?.to_string(),
+            components: words.map(|x| x.to_string()).collect(),
+        })
+    }
+
+    /// The architectures named by an `arch=` option, empty without one.
+    pub fn architectures(&self) -> Vec<&str> {
+        self.options
+            .iter()
+            .filter_map(|x| x.strip_prefix("arch="))
+            .flat_map(|x| x.split(','))
+            .filter(|x| !x.is_empty())
+            .collect()
+    }
+
+    pub fn is_http(&self) -> bool {
+        self.url.starts_with("http://") || self.url.starts_with("https://")
+    }
+}
+
+/// Every entry of a sources.list, in order.
+pub fn parse_sources_list(text: &str) -> Vec<SourceEntry> {
+    text.lines().filter_map(SourceEntry::parse).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_entries() {
+        let x =
+            SourceEntry::parse("deb http://deb.debian.org/debian bookworm main contrib").unwrap();
+        assert!(!x.source);
+        assert!(x.options.is_empty());
+        assert_eq!(x.url, "http://deb.debian.org/debian");
+        assert_eq!(x.suite, "bookworm");
+        assert_eq!(x.components, ["main", "contrib"]);
+        assert!(x.is_http());
+    }
+
+    #[test]
+    fn parses_options_and_comments() {
+        let x = SourceEntry::parse("deb-src [arch=amd64 trusted=yes] file:/srv x main # local")
+            .unwrap();
+        assert!(x.source);
+        assert_eq!(x.options, ["arch=amd64", "trusted=yes"]);
+        assert_eq!(x.url, "file:/srv");
+        assert_eq!(x.components, ["main"]);
+        assert!(!x.is_http());
+
+        let x = SourceEntry::parse("deb [ arch=arm64 ] http://ports.ubuntu.com/ noble").unwrap();
+        assert_eq!(x.options, ["arch=arm64"]);
+        assert_eq!(x.suite, "noble");
+        assert!(x.components.is_empty());
+    }
+
+    #[test]
+    fn skips_what_is_not_an_entry() {
+        assert!(SourceEntry::parse("").is_none());
+        assert!(SourceEntry::parse("# deb http://example.org x main").is_none());
+        assert!(SourceEntry::parse("rpm http://example.org x main").is_none());
+        assert!(SourceEntry::parse("deb http://example.org").is_none());
+        assert!(SourceEntry::parse("deb [arch=amd64").is_none());
+        let list = "deb http://a x main\n\n# comment\ndeb-src http://b y main\n";
+        assert_eq!(parse_sources_list(list).len(), 2);
+    }
+}
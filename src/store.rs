@@ -0,0 +1,466 @@
+use anyhow::Context;
+use std::os::unix::io::AsRawFd;
+use std::sync::RwLock;
+
+use crate::download_dist::do_link_to;
+use crate::download_dist::sha256_digest;
+use crate::metrics;
+use crate::mirrors::all_trees;
+use crate::mirrors::MirrorCtx;
+use crate::paths::paths;
+use tracing::info;
+use tracing::warn;
+
+/// A content addressed store keeping every blob once, named by the hex
+/// SHA256 of its content, below a fan-out of two character directories.
+///
+/// ```no_run
+/// # async fn f() -> anyhow::Result<()> {
+/// let store = deb_mirror::BlobStore::open("/srv/mirror/SHA256")?;
+/// let sha256 = store.insert("/tmp/hello_1.0_amd64.deb").await?;
+/// assert!(store.contains(&sha256).await?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: String,
+    levels: usize,
+}
+
+impl BlobStore {
+    /// Opens the store at `root`, reading its fan-out from the layout file.
+    pub fn open(root: &str) -> anyhow::Result<BlobStore> {
+        Ok(BlobStore {
+            root: root.to_string(),
+            levels: read_levels(root)?,
+        })
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Number of two character directory levels above each blob.
+    pub fn levels(&self) -> usize {
+        self.levels
+    }
+
+    /// Path the blob named `sha256` is kept at.
+    pub fn path(&self, sha256: &str) -> String {
+        blob_path_with_levels(&self.root, sha256, self.levels)
+    }
+
+    pub async fn contains(&self, sha256: &str) -> anyhow::Result<bool> {
+        Ok(tokio::fs::try_exists(self.path(sha256)).await?)
+    }
+
+    /// Names and paths of every blob in the store.
+    pub async fn list(&self) -> anyhow::Result<Vec<(String, String)>> {
+        list_blobs(&self.root).await
+    }
+
+    /// Whether the content of the blob still hashes to its name.
+    pub async fn verify(&self, sha256: &str) -> anyhow::Result<bool> {
+        Ok(sha256_digest(&self.path(sha256)).await?.eq(sha256))
+    }
+
+    /// Moves `file` into the store under the SHA256 of its content and
+    /// returns that name. A blob already present is kept and `file` removed.
+    pub async fn insert(&self, file: &str) -> anyhow::Result<String> {
+        let sha256 = sha256_digest(file).await?;
+        let dest = self.path(&sha256);
+        if tokio::fs::try_exists(&dest).await? {
+            tokio::fs::remove_file(file)
+                .await
+                .with_context(|| format!("failed to remove {}", file))?;
+            return Ok(sha256);
+        }
+        if let Some(parent) = std::path::Path::new(&dest).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(file, &dest)
+            .await
+            .with_context(|| format!("failed to move {} to {}", file, dest))?;
+        Ok(sha256)
+    }
+}
+
+/// Records the fan-out of the store as the number of two character
+/// directory levels above each blob, e.g. `2` for `SHA256/ab/cd/<hex>`.
+/// A store without this file is flat.
+pub(crate) const LAYOUT_FILE: &str = ".layout";
+
+/// Lock file of the store, taken shared by whatever adds or links blobs
+/// and exclusively by gc, which deletes them, and migrate, which moves them.
+pub(crate) const LOCK_FILE: &str = ".lock";
+
+const MAX_LEVELS: usize = 4;
+
+static STORE: RwLock<Option<BlobStore>> = RwLock::new(None);
+
+fn read_levels(store: &str) -> anyhow::Result<usize> {
+    let path = format!("{}/{}", store, LAYOUT_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(o) => {
+            let levels: usize = o
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid store layout in {}", path))?;
+            if levels > MAX_LEVELS {
+                return Err(anyhow::format_err!(
+                    "store layout {} in {} is deeper than {}",
+                    levels,
+                    path,
+                    MAX_LEVELS
+                ));
+            }
+            Ok(levels)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path)),
+    }
+}
+
+/// The store of this process at the configured path, opened on first use
+/// and again whenever the store lock is taken.
+fn store() -> BlobStore {
+    if let Some(o) = STORE.read().unwrap().as_ref() {
+        return o.clone();
+    }
+    reopen_store()
+}
+
+/// Reads the layout of the store afresh, which a migration run by another
+/// process may have changed since this one opened it.
+fn reopen_store() -> BlobStore {
+    let ret = match BlobStore::open(&paths().store) {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("{:#}, assuming a flat store", e);
+            BlobStore {
+                root: paths().store.clone(),
+                levels: 0,
+            }
+        }
+    };
+    *STORE.write().unwrap() = Some(ret.clone());
+    ret
+}
+
+/// Number of fan-out levels of the store, as last read.
+pub(crate) fn store_levels() -> usize {
+    store().levels
+}
+
+fn blob_path_with_levels(root: &str, sha256: &str, levels: usize) -> String {
+    let mut ret = String::from(root);
+    ret.push('/');
+    for i in 0..levels {
+        if sha256.len() >= 2 * (i + 1) {
+            ret.push_str(&sha256[2 * i..2 * (i + 1)]);
+            ret.push('/');
+        }
+    }
+    ret.push_str(sha256);
+    ret
+}
+
+/// Path of a blob below `root` (the store, or a directory mirroring it such
+/// as WASTE) using the configured fan-out.
+pub(crate) fn blob_path_in(root: &str, sha256: &str) -> String {
+    blob_path_with_levels(root, sha256, store_levels())
+}
+
+/// Path of a blob in the store, e.g. `SHA256/ab/cd/abcd...`.
+pub(crate) fn blob_path(sha256: &str) -> String {
+    store().path(sha256)
+}
+
+/// Whether `name` is the hex SHA256 a blob is stored under.
+pub fn is_sha256_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|x| x.is_ascii_hexdigit())
+}
+
+/// Takes an flock on the lock file of the store, shared or `exclusive`,
+/// waiting for the holders of a conflicting one. Every mirror and every
+/// daemon task shares the store, so gc must not delete a blob that a sync
+/// has just found present and is about to link, and migrate must not move
+/// blobs under either. Once the lock is held the layout is read again, so a
+/// long running daemon or proxy follows a migration. The lock is released
+/// when the returned file is dropped.
+pub(crate) async fn lock_store(exclusive: bool) -> anyhow::Result<std::fs::File> {
+    let store = &paths().store;
+    tokio::fs::create_dir_all(store)
+        .await
+        .with_context(|| format!("failed to create {}", store))?;
+    let lock = format!("{}/{}", store, LOCK_FILE);
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock)
+        .with_context(|| format!("failed to open {}", lock))?;
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+        info!(lock = %lock, exclusive, "waiting for the store lock");
+        file = tokio::task::spawn_blocking(move || loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+                return Ok(file);
+            }
+            let e = std::io::Error::last_os_error();
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        })
+        .await?
+        .with_context(|| format!("failed to lock {}", lock))?;
+    }
+    reopen_store();
+    Ok(file)
+}
+
+/// Lists the names of all blobs below `root`, descending into the fan-out
+/// directories. Each entry is `(name, path)`.
+pub(crate) async fn list_blobs(root: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut ret = Vec::new();
+    let mut stack = vec![String::from(root)];
+    while let Some(dir) = stack.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("failed to list {}", dir))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = format!("{}/{}", dir, name);
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                stack.push(path);
+            } else if file_type.is_file() && !name.eq(LAYOUT_FILE) && !name.eq(LOCK_FILE) {
+                ret.push((name, path));
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Rewrites every symlink below `root` that points into the store so it
+/// follows the layout of `store`.
+async fn relink_tree(root: &str, store: &BlobStore) -> anyhow::Result<u64> {
+    let mut num_links: u64 = 0;
+    let mut stack = vec![String::from(root)];
+    while let Some(dir) = stack.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(o) => o,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to list {}", dir)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                stack.push(path);
+            } else if file_type.is_symlink() {
+                let target = tokio::fs::read_link(&path).await?;
+                let sha256 = match target.file_name() {
+                    Some(o) => o.to_string_lossy().to_string(),
+                    None => continue,
+                };
+                if is_sha256_name(&sha256) {
+                    tokio::fs::remove_file(&path)
+                        .await
+                        .with_context(|| format!("failed to remove {}", path))?;
+                    do_link_to(&store.path(&sha256), &path).await?;
+                    num_links += 1;
+                }
+            }
+        }
+    }
+    Ok(num_links)
+}
+
+async fn relink_here(ctx: &MirrorCtx, store: &BlobStore) -> anyhow::Result<u64> {
+    Ok(relink_tree(&ctx.path("pool"), store).await?
+        + relink_tree(&ctx.paths().snapshots, store).await?)
+}
+
+/// Records the number and total size of the blobs in the store.
+pub(crate) async fn record_store_usage() -> anyhow::Result<()> {
+    let blobs = list_blobs(&paths().store).await?;
+    let mut bytes: u64 = 0;
+    for (_, path) in blobs.iter() {
+        bytes += tokio::fs::metadata(path)
+            .await
+            .map(|x| x.len())
+            .unwrap_or(0);
+    }
+    metrics::set(&metrics::STORE_BLOBS, &[], blobs.len() as f64);
+    metrics::set(&metrics::STORE_BYTES, &[], bytes as f64);
+    Ok(())
+}
+
+/// Relinks the pool and snapshots of every mirror sharing the store, or of
+/// the mirror root without a mirrors file, to the layout of `store`.
+async fn relink_mirrors(store: &BlobStore) -> anyhow::Result<u64> {
+    let mut num_links: u64 = 0;
+    for ctx in all_trees()? {
+        num_links += relink_here(&ctx, store).await?;
+    }
+    Ok(num_links)
+}
+
+/// Moves every blob below `root` to where a fan-out of `levels` keeps it and
+/// returns how many moved.
+async fn move_blobs(root: &str, levels: usize) -> anyhow::Result<u64> {
+    let mut num_moved: u64 = 0;
+    for (name, path) in list_blobs(root).await?.iter() {
+        if !is_sha256_name(name) {
+            warn!(file = %path, "leaving unexpected file in place");
+            continue;
+        }
+        let dest = blob_path_with_levels(root, name, levels);
+        if dest.eq(path) {
+            continue;
+        }
+        if let Some(parent) = std::path::Path::new(&dest).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(path, &dest)
+            .await
+            .with_context(|| format!("Failed to move {} to {}", path, dest))?;
+        num_moved += 1;
+    }
+    Ok(num_moved)
+}
+
+/// Moves every blob of the store into the fan-out given by `levels`, records
+/// the new layout and repoints the pool symlinks at the new locations. The
+/// store lock is held exclusively throughout, so no sync, gc or proxy uses
+/// the store while blobs move.
+pub async fn migrate_store(levels: usize) -> anyhow::Result<()> {
+    if levels > MAX_LEVELS {
+        return Err(anyhow::format_err!(
+            "store layout can have at most {} levels",
+            MAX_LEVELS
+        ));
+    }
+    let _store = lock_store(true).await?;
+    let num_moved = move_blobs(&paths().store, levels).await?;
+
+    // Written only once every blob is in place, so an interrupted migration
+    // is finished by simply running it again.
+    let layout = format!("{}/{}", &paths().store, LAYOUT_FILE);
+    let tmp = format!("{}.tmp", layout);
+    tokio::fs::write(&tmp, format!("{}\n", levels)).await?;
+    tokio::fs::rename(&tmp, &layout)
+        .await
+        .with_context(|| format!("failed to write {}", layout))?;
+    let migrated = reopen_store();
+    let num_links = relink_mirrors(&migrated).await?;
+
+    info!(
+        moved = num_moved,
+        levels,
+        relinked = num_links,
+        "migrated the store layout"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "abcdef0000000000000000000000000000000000000000000000000000000000";
+
+    fn scratch(name: &str) -> String {
+        let ret =
+            std::env::temp_dir().join(format!("mysync-store-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&ret);
+        std::fs::create_dir_all(&ret).unwrap();
+        ret.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn blobs_fan_out_by_two_characters() {
+        assert_eq!(
+            blob_path_with_levels("SHA256", A, 0),
+            format!("SHA256/{}", A)
+        );
+        assert_eq!(
+            blob_path_with_levels("SHA256", A, 1),
+            format!("SHA256/ab/{}", A)
+        );
+        assert_eq!(blob_path_with_levels("/s", A, 2), format!("/s/ab/cd/{}", A));
+        // Names too short for the fan-out stop where they run out.
+        assert_eq!(blob_path_with_levels("s", "abc", 2), "s/ab/abc");
+    }
+
+    #[test]
+    fn layouts_are_read_from_the_layout_file() {
+        let root = scratch("layout");
+        assert_eq!(read_levels(&root).unwrap(), 0);
+        std::fs::write(format!("{}/{}", root, LAYOUT_FILE), "2\n").unwrap();
+        assert_eq!(read_levels(&root).unwrap(), 2);
+        std::fs::write(format!("{}/{}", root, LAYOUT_FILE), "5\n").unwrap();
+        assert!(read_levels(&root).is_err());
+        std::fs::write(format!("{}/{}", root, LAYOUT_FILE), "deep\n").unwrap();
+        assert!(read_levels(&root).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_keep_each_blob_once() {
+        let root = scratch("insert");
+        std::fs::write(format!("{}/{}", root, LAYOUT_FILE), "1\n").unwrap();
+        let store = BlobStore::open(&root).unwrap();
+        let data = b"Package: hello\n";
+        for _ in 0..2 {
+            let file = format!("{}/incoming", root);
+            std::fs::write(&file, data).unwrap();
+            let sha256 = store.insert(&file).await.unwrap();
+            assert!(!std::path::Path::new(&file).exists());
+            assert_eq!(
+                store.path(&sha256),
+                blob_path_with_levels(&root, &sha256, 1)
+            );
+            assert!(store.contains(&sha256).await.unwrap());
+            assert!(store.verify(&sha256).await.unwrap());
+        }
+        let blobs = store.list().await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migration_moves_blobs_and_leaves_the_rest() {
+        let root = scratch("migrate");
+        std::fs::write(format!("{}/{}", root, A), "a").unwrap();
+        std::fs::write(format!("{}/{}", root, LAYOUT_FILE), "0\n").unwrap();
+        std::fs::write(format!("{}/{}", root, LOCK_FILE), "").unwrap();
+        std::fs::write(format!("{}/stray.tmp", root), "x").unwrap();
+
+        assert_eq!(move_blobs(&root, 2).await.unwrap(), 1);
+        let moved = blob_path_with_levels(&root, A, 2);
+        assert_eq!(std::fs::read_to_string(&moved).unwrap(), "a");
+        assert!(std::path::Path::new(&format!("{}/stray.tmp", root)).exists());
+        // Running it again, as after an interruption, moves nothing more.
+        assert_eq!(move_blobs(&root, 2).await.unwrap(), 0);
+        let names: Vec<String> = list_blobs(&root)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(names.contains(&A.to_string()) && names.contains(&String::from("stray.tmp")));
+
+        assert_eq!(move_blobs(&root, 0).await.unwrap(), 1);
+        assert!(std::path::Path::new(&format!("{}/{}", root, A)).exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
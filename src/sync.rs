@@ -0,0 +1,373 @@
+use anyhow::Context;
+use std::os::unix::io::AsRawFd;
+
+use crate::download_dist::download_dist;
+use crate::download_dist::download_pool;
+use crate::download_dist::link_pool;
+use crate::download_dist::make_config;
+use crate::download_dist::read_list_dist_packages;
+use crate::gc::gc_sha;
+use crate::gc::now_seconds;
+use crate::gc::GcOptions;
+use crate::metrics;
+use crate::mirrors::MirrorCtx;
+use crate::snapshot::publish_snapshot;
+use crate::snapshot::PublishOptions;
+use crate::trace::fetch_upstream_trace;
+use crate::trace::upstream_unchanged;
+use crate::trace::write_traces;
+use tracing::debug;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Instrument;
+
+/// Exit status when another sync holds the lock.
+pub const EXIT_LOCKED: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Config,
+    Dist,
+    Pool,
+    Link,
+    Publish,
+    Gc,
+}
+
+const STAGES: [Stage; 6] = [
+    Stage::Config,
+    Stage::Dist,
+    Stage::Pool,
+    Stage::Link,
+    Stage::Publish,
+    Stage::Gc,
+];
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Config => "config",
+            Stage::Dist => "dist",
+            Stage::Pool => "pool",
+            Stage::Link => "link",
+            Stage::Publish => "publish",
+            Stage::Gc => "gc",
+        }
+    }
+
+    /// Exit status of the process when this stage fails, 10 for config up
+    /// to 15 for gc.
+    pub fn exit_code(&self) -> i32 {
+        10 + STAGES.iter().position(|x| x == self).unwrap_or(0) as i32
+    }
+}
+
+/// Error returned when a stage fails, carrying the stage so the caller can
+/// turn it into an exit status.
+#[derive(Debug)]
+pub struct StageFailed {
+    pub stage: Stage,
+}
+
+impl std::fmt::Display for StageFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sync stage {} failed", self.stage.name())
+    }
+}
+
+impl std::error::Error for StageFailed {}
+
+/// Error returned when another process holds the sync lock.
+#[derive(Debug)]
+pub struct Locked {
+    pub lock: String,
+}
+
+impl std::fmt::Display for Locked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "another sync is running, {} is locked", self.lock)
+    }
+}
+
+impl std::error::Error for Locked {}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct SyncOptions {
+    /// Start from the first stage even if an earlier sync was interrupted
+    #[arg(long)]
+    pub restart: bool,
+
+    /// Sync even if the upstream trace file shows no change since the last
+    /// sync
+    #[arg(long)]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub publish: PublishOptions,
+
+    #[command(flatten)]
+    pub gc: GcOptions,
+}
+
+/// Takes an exclusive, non-blocking flock on the lock file of the tree. The
+/// lock is released when the returned file is dropped or the process exits.
+pub(crate) fn lock_root(ctx: &MirrorCtx) -> anyhow::Result<std::fs::File> {
+    let lock = &ctx.paths().lock;
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock)
+        .with_context(|| format!("failed to open {}", lock))?;
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret != 0 {
+        return Err(anyhow::Error::new(Locked { lock: lock.clone() }));
+    }
+    Ok(file)
+}
+
+/// Exit status for an error returned by sync: the failed stage's code,
+/// EXIT_LOCKED when the lock was busy and 1 otherwise.
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    if let Some(x) = e.downcast_ref::<StageFailed>() {
+        x.stage.exit_code()
+    } else if e.downcast_ref::<Locked>().is_some() {
+        EXIT_LOCKED
+    } else {
+        1
+    }
+}
+
+/// What an interrupted sync leaves behind: the last stage it finished and
+/// the upstream master trace it is based on, which the resumed sync records
+/// when it finishes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SyncState {
+    done: Stage,
+    upstream: Option<String>,
+}
+
+impl SyncState {
+    /// Parses the stage name on the first line and the trace after it. A
+    /// state file without the trace is read as having none.
+    fn parse(text: &str) -> Option<SyncState> {
+        let (first, rest) = text.split_once('\n').unwrap_or((text, ""));
+        let done = STAGES.iter().copied().find(|x| x.name() == first.trim())?;
+        Some(SyncState {
+            done,
+            upstream: (!rest.is_empty()).then(|| rest.to_string()),
+        })
+    }
+
+    /// Index in STAGES of the stage a resumed sync starts with, past the
+    /// end when the last sync finished.
+    fn resume_at(&self) -> usize {
+        STAGES.iter().position(|x| *x == self.done).unwrap_or(0) + 1
+    }
+
+    fn to_text(&self) -> String {
+        format!(
+            "{}\n{}",
+            self.done.name(),
+            self.upstream.as_deref().unwrap_or("")
+        )
+    }
+}
+
+async fn read_state(ctx: &MirrorCtx) -> anyhow::Result<Option<SyncState>> {
+    let content = match tokio::fs::read_to_string(&ctx.paths().sync_state).await {
+        Ok(o) => o,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", &ctx.paths().sync_state))
+        }
+    };
+    Ok(SyncState::parse(&content))
+}
+
+async fn write_state(ctx: &MirrorCtx, state: &SyncState) -> anyhow::Result<()> {
+    let tmp = format!("{}.tmp", &ctx.paths().sync_state);
+    tokio::fs::write(&tmp, state.to_text()).await?;
+    tokio::fs::rename(&tmp, &ctx.paths().sync_state)
+        .await
+        .with_context(|| format!("failed to write {}", &ctx.paths().sync_state))
+}
+
+async fn run_stage(ctx: &MirrorCtx, stage: Stage, options: &SyncOptions) -> anyhow::Result<()> {
+    match stage {
+        Stage::Config => make_config(ctx).await,
+        Stage::Dist => download_dist(ctx).await,
+        Stage::Pool => download_pool(ctx).await,
+        Stage::Link => link_pool(ctx).await,
+        Stage::Publish => publish_snapshot(ctx, &options.publish).await,
+        Stage::Gc => gc_sha(&options.gc).await,
+    }
+}
+
+/// Marks every suite of the dists list as freshly synced.
+async fn record_success(ctx: &MirrorCtx) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let list = read_list_dist_packages(ctx).await?;
+    for line in list.lines() {
+        let suite = line
+            .strip_prefix("dists/")
+            .and_then(|x| x.strip_suffix("/Release"));
+        if let Some(suite) = suite {
+            let labels = [("mirror", ctx.label()), ("suite", suite)];
+            metrics::set(&metrics::LAST_SUCCESS, &labels, now as f64);
+        }
+    }
+    let labels = [("mirror", ctx.label()), ("result", "success")];
+    metrics::add(&metrics::SYNC_RUNS, &labels, 1.0);
+    Ok(())
+}
+
+/// Runs config, dist, pool, link, publish and gc in order under the mirror
+/// lock. Each finished stage is checkpointed, so after a crash the next
+/// sync resumes with the stage that did not finish.
+///
+/// Like ftpsync, a fresh sync stops after config when the upstream master
+/// trace is the one the last sync was based on, and a successful one ends
+/// by writing the trace file of this host. The dist stage only stages the
+/// new dists files and the pool stage fails if any blob is missing, so the
+/// link stage puts them in place once every pool file they refer to is in
+/// the store.
+pub async fn sync(ctx: &MirrorCtx, options: &SyncOptions) -> anyhow::Result<()> {
+    if ctx.mirror().is_some_and(|x| x.proxy) {
+        return Err(anyhow::format_err!(
+            "mirror {} is a caching proxy, run the proxy command instead",
+            ctx.label()
+        ));
+    }
+    let _lock = lock_root(ctx)?;
+    let started = now_seconds();
+    let mut upstream = None;
+
+    let mut first = 0;
+    if options.restart {
+        let _ = tokio::fs::remove_file(&ctx.paths().sync_state).await;
+    } else if let Some(state) = read_state(ctx).await? {
+        first = state.resume_at();
+        if first < STAGES.len() {
+            info!(after = state.done.name(), "resuming sync");
+        }
+        upstream = state.upstream;
+    }
+
+    for stage in STAGES[first.min(STAGES.len())..].iter() {
+        let started = std::time::Instant::now();
+        info!(stage = stage.name(), "running sync stage");
+        let result = run_stage(ctx, *stage, options)
+            .instrument(info_span!("stage", stage = stage.name()))
+            .await;
+        if result.is_err() {
+            let labels = [("mirror", ctx.label()), ("result", "failure")];
+            metrics::add(&metrics::SYNC_RUNS, &labels, 1.0);
+        }
+        result.with_context(|| StageFailed { stage: *stage })?;
+        let seconds = started.elapsed().as_secs_f64();
+        metrics::set(
+            &metrics::STAGE_DURATION,
+            &[("mirror", ctx.label()), ("stage", stage.name())],
+            seconds,
+        );
+        debug!(stage = stage.name(), seconds, "finished sync stage");
+
+        if *stage == Stage::Config {
+            upstream = match fetch_upstream_trace(ctx).await {
+                Ok(o) => o,
+                Err(e) => {
+                    warn!("{:#}", e);
+                    None
+                }
+            };
+        }
+        let state = SyncState {
+            done: *stage,
+            upstream: upstream.clone(),
+        };
+        write_state(ctx, &state).await?;
+
+        if *stage == Stage::Config
+            && !options.force
+            && upstream
+                .as_deref()
+                .is_some_and(|x| upstream_unchanged(ctx, x))
+        {
+            tokio::fs::remove_file(&ctx.paths().sync_state).await?;
+            info!("upstream trace unchanged, nothing to sync");
+            return Ok(());
+        }
+    }
+
+    tokio::fs::remove_file(&ctx.paths().sync_state)
+        .await
+        .with_context(|| format!("failed to remove {}", &ctx.paths().sync_state))?;
+    record_success(ctx).await?;
+    write_traces(ctx, upstream.as_deref(), started).await?;
+    info!("sync finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_carry_the_upstream_trace() {
+        let state = SyncState {
+            done: Stage::Pool,
+            upstream: Some(String::from(
+                "Sat Oct 17 10:00:00 UTC 2026\nArchive serial: 2026101701\n",
+            )),
+        };
+        assert_eq!(SyncState::parse(&state.to_text()), Some(state));
+        let state = SyncState {
+            done: Stage::Config,
+            upstream: None,
+        };
+        assert_eq!(state.to_text(), "config\n");
+        assert_eq!(SyncState::parse("config\n"), Some(state));
+    }
+
+    #[test]
+    fn states_of_older_versions_and_unknown_stages() {
+        assert_eq!(
+            SyncState::parse("link"),
+            Some(SyncState {
+                done: Stage::Link,
+                upstream: None,
+            })
+        );
+        assert_eq!(SyncState::parse("fetch\n"), None);
+        assert_eq!(SyncState::parse(""), None);
+    }
+
+    #[test]
+    fn syncs_resume_after_the_last_finished_stage() {
+        let state = |done| SyncState {
+            done,
+            upstream: None,
+        };
+        assert_eq!(STAGES[state(Stage::Config).resume_at()], Stage::Dist);
+        assert_eq!(STAGES[state(Stage::Link).resume_at()], Stage::Publish);
+        assert_eq!(state(Stage::Gc).resume_at(), STAGES.len());
+    }
+
+    #[test]
+    fn failed_stages_have_their_own_exit_status() {
+        assert_eq!(Stage::Config.exit_code(), 10);
+        assert_eq!(Stage::Gc.exit_code(), 15);
+        let failed = anyhow::Error::new(StageFailed { stage: Stage::Pool })
+            .context("failed to sync mirror debian");
+        assert_eq!(exit_code(&failed), 12);
+        let locked = anyhow::Error::new(Locked {
+            lock: String::from("sync.lock"),
+        });
+        assert_eq!(exit_code(&locked), EXIT_LOCKED);
+        assert_eq!(exit_code(&anyhow::format_err!("no mirrors")), 1);
+    }
+}
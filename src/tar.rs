@@ -0,0 +1,256 @@
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+const BLOCK: usize = 512;
+
+/// Largest file the octal size field of a ustar header can describe.
+const MAX_SIZE: u64 = 0o77777777777;
+
+/// Writes a ustar archive of regular files, the directories implied by
+/// their names.
+pub(crate) struct TarWriter {
+    file: tokio::io::BufWriter<tokio::fs::File>,
+    mtime: u64,
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let text = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..text.len()].copy_from_slice(text.as_bytes());
+}
+
+/// Splits `name` into the prefix and name fields of a ustar header.
+fn split_name(name: &str) -> anyhow::Result<(&str, &str)> {
+    if name.len() <= 100 {
+        return Ok(("", name));
+    }
+    for (i, _) in name.match_indices('/') {
+        if i <= 155 && name.len() - i - 1 <= 100 {
+            return Ok((&name[..i], &name[i + 1..]));
+        }
+    }
+    Err(anyhow::format_err!(
+        "{} is too long for a tar archive",
+        name
+    ))
+}
+
+fn header(name: &str, size: u64, mtime: u64) -> anyhow::Result<[u8; BLOCK]> {
+    if size > MAX_SIZE {
+        return Err(anyhow::format_err!(
+            "{} is too large for a tar archive",
+            name
+        ));
+    }
+    let (prefix, name) = split_name(name)?;
+    let mut ret = [0u8; BLOCK];
+    ret[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut ret[100..108], 0o644);
+    octal(&mut ret[108..116], 0);
+    octal(&mut ret[116..124], 0);
+    octal(&mut ret[124..136], size);
+    octal(&mut ret[136..148], mtime);
+    ret[156] = b'0';
+    ret[257..263].copy_from_slice(b"ustar\0");
+    ret[263..265].copy_from_slice(b"00");
+    ret[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // The checksum is taken with its own field filled with spaces.
+    ret[148..156].copy_from_slice(b"        ");
+    let sum: u64 = ret.iter().map(|x| *x as u64).sum();
+    octal(&mut ret[148..155], sum);
+    Ok(ret)
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+impl TarWriter {
+    pub(crate) async fn create(path: &Path, mtime: u64) -> anyhow::Result<TarWriter> {
+        let file = tokio::fs::File::create(path).await?;
+        Ok(TarWriter {
+            file: tokio::io::BufWriter::new(file),
+            mtime,
+        })
+    }
+
+    pub(crate) async fn add_bytes(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.file
+            .write_all(&header(name, data.len() as u64, self.mtime)?)
+            .await?;
+        self.file.write_all(data).await?;
+        self.file
+            .write_all(&[0u8; BLOCK][..padding(data.len() as u64)])
+            .await?;
+        Ok(())
+    }
+
+    pub(crate) async fn add_file(&mut self, name: &str, source: &Path) -> anyhow::Result<()> {
+        let mut input = tokio::fs::File::open(source).await?;
+        let size = input.metadata().await?.len();
+        self.file
+            .write_all(&header(name, size, self.mtime)?)
+            .await?;
+        let copied = tokio::io::copy(&mut (&mut input).take(size), &mut self.file).await?;
+        if copied != size {
+            return Err(anyhow::format_err!(
+                "{} shrank while archived",
+                source.display()
+            ));
+        }
+        self.file.write_all(&[0u8; BLOCK][..padding(size)]).await?;
+        Ok(())
+    }
+
+    /// Ends the archive with two zero blocks and flushes it to disk.
+    pub(crate) async fn finish(mut self) -> anyhow::Result<()> {
+        self.file.write_all(&[0u8; 2 * BLOCK]).await?;
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+        Ok(())
+    }
+}
+
+fn parse_octal(field: &[u8]) -> anyhow::Result<u64> {
+    let text = std::str::from_utf8(field)?;
+    let text = text.trim_matches(|x: char| x == '\0' || x == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    Ok(u64::from_str_radix(text, 8)?)
+}
+
+fn parse_text(field: &[u8]) -> anyhow::Result<&str> {
+    let end = field.iter().position(|x| *x == 0).unwrap_or(field.len());
+    Ok(std::str::from_utf8(&field[..end])?)
+}
+
+/// Unpacks the regular files of the tar archive at `path` below `dest`.
+/// Names leaving `dest` are refused, other entry types skipped.
+pub(crate) async fn extract(path: &Path, dest: &Path) -> anyhow::Result<()> {
+    let mut input = tokio::io::BufReader::new(tokio::fs::File::open(path).await?);
+    let mut block = [0u8; BLOCK];
+    loop {
+        input.read_exact(&mut block).await?;
+        if block.iter().all(|x| *x == 0) {
+            return Ok(());
+        }
+        let stored = parse_octal(&block[148..156])?;
+        let mut copy = block;
+        copy[148..156].copy_from_slice(b"        ");
+        if copy.iter().map(|x| *x as u64).sum::<u64>() != stored {
+            return Err(anyhow::format_err!(
+                "{} has a corrupt header",
+                path.display()
+            ));
+        }
+        let name = parse_text(&block[..100])?;
+        let prefix = parse_text(&block[345..500])?;
+        let name = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let size = parse_octal(&block[124..136])?;
+        let kind = block[156];
+        if name.split('/').any(|x| x == ".." || x.contains('\0')) || name.starts_with('/') {
+            return Err(anyhow::format_err!(
+                "{} holds the unsafe name {}",
+                path.display(),
+                name
+            ));
+        }
+
+        if kind == b'0' || kind == 0 {
+            let target = dest.join(&name);
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut output = tokio::fs::File::create(&target).await?;
+            let copied = tokio::io::copy(&mut (&mut input).take(size), &mut output).await?;
+            if copied != size {
+                return Err(anyhow::format_err!("{} is truncated", path.display()));
+            }
+        } else {
+            tokio::io::copy(&mut (&mut input).take(size), &mut tokio::io::sink()).await?;
+        }
+        let mut pad = [0u8; BLOCK];
+        input.read_exact(&mut pad[..padding(size)]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let ret = std::env::temp_dir().join(format!("mysync-tar-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&ret);
+        std::fs::create_dir_all(&ret).unwrap();
+        ret
+    }
+
+    #[tokio::test]
+    async fn archives_round_trip() {
+        let dir = scratch("round-trip");
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let source = dir.join("source");
+        std::fs::write(&source, vec![7u8; 1000]).unwrap();
+        let archive = dir.join("a.tar");
+        let mut writer = TarWriter::create(&archive, 1_792_231_200).await.unwrap();
+        writer.add_bytes("manifest", b"Format: 1\n").await.unwrap();
+        writer.add_bytes("empty", b"").await.unwrap();
+        writer.add_file("blobs/aa/aabb", &source).await.unwrap();
+        writer.add_bytes(&long, b"x").await.unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!(std::fs::metadata(&archive).unwrap().len() % BLOCK as u64, 0);
+
+        let out = dir.join("out");
+        extract(&archive, &out).await.unwrap();
+        assert_eq!(std::fs::read(out.join("manifest")).unwrap(), b"Format: 1\n");
+        assert_eq!(std::fs::read(out.join("empty")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read(out.join("blobs/aa/aabb")).unwrap(),
+            vec![7u8; 1000]
+        );
+        assert_eq!(std::fs::read(out.join(&long)).unwrap(), b"x");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn extract_refuses_names_leaving_the_target() {
+        let dir = scratch("unsafe");
+        for (i, name) in ["../evil", "a/../../evil", "/evil"].iter().enumerate() {
+            let archive = dir.join(format!("{}.tar", i));
+            let mut writer = TarWriter::create(&archive, 0).await.unwrap();
+            writer.add_bytes(name, b"x").await.unwrap();
+            writer.finish().await.unwrap();
+            let out = dir.join("out").join("inner");
+            assert!(extract(&archive, &out).await.is_err(), "{}", name);
+        }
+        assert!(!dir.join("out").join("evil").exists());
+        assert!(!dir.join("evil").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn extract_refuses_corrupt_headers() {
+        let dir = scratch("corrupt");
+        let archive = dir.join("a.tar");
+        let mut writer = TarWriter::create(&archive, 0).await.unwrap();
+        writer.add_bytes("file", b"x").await.unwrap();
+        writer.finish().await.unwrap();
+        let mut data = std::fs::read(&archive).unwrap();
+        data[0] = b'g';
+        std::fs::write(&archive, data).unwrap();
+        assert!(extract(&archive, &dir.join("out")).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_too_long_are_refused() {
+        assert!(header(&"x".repeat(101), 0, 0).is_err());
+        assert!(header(&format!("{}/x", "d".repeat(156)), 0, 0).is_err());
+        assert!(header("x", MAX_SIZE + 1, 0).is_err());
+    }
+}
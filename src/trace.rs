@@ -0,0 +1,225 @@
+use anyhow::Context;
+use std::collections::BTreeSet;
+
+use crate::download_dist::read_list_dist_packages;
+use crate::download_dist::Upstreams;
+use crate::gc::now_seconds;
+use crate::http::ctime_date;
+use crate::http::http_date;
+use crate::mirrors::MirrorCtx;
+use tracing::debug;
+
+/// Trace file the archive writes on every update, mirrors pass it on.
+const MASTER: &str = "master";
+
+/// Name of this host, which names the trace file a sync leaves behind.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let end = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+    match std::str::from_utf8(&buf[..end]) {
+        Ok(o) if ret == 0 && !o.is_empty() => o.to_string(),
+        _ => String::from("localhost"),
+    }
+}
+
+/// The upstream mirrors in list.url_mirrors.txt, those the last dist stage
+/// took its Release files from first, as they are what the mirror is based
+/// on rather than one found down or stale.
+async fn upstream_mirrors(ctx: &MirrorCtx) -> anyhow::Result<Vec<String>> {
+    let upstreams = Upstreams::read(ctx).await?;
+    let (mut ret, stale): (Vec<String>, Vec<String>) = upstreams
+        .default
+        .into_iter()
+        .partition(|x| upstreams.fresh.contains(x));
+    ret.extend(stale);
+    if ret.is_empty() {
+        return Err(anyhow::format_err!(
+            "no upstream mirror in {}",
+            ctx.paths().url_mirrors
+        ));
+    }
+    Ok(ret)
+}
+
+async fn fetch_trace(url: &str) -> anyhow::Result<Option<String>> {
+    let url = format!("{}/project/trace/{}", url, MASTER);
+    let response = reqwest::get(&url)
+        .await
+        .with_context(|| format!("failed to fetch {}", url))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        debug!(url = %url, "upstream has no trace file");
+        return Ok(None);
+    }
+    let response = response
+        .error_for_status()
+        .with_context(|| format!("failed to fetch {}", url))?;
+    Ok(Some(response.text().await?))
+}
+
+/// Fetches the master trace of the mirror dists are synced from, None when
+/// it has none. The next mirror is asked when one can not be reached.
+pub(crate) async fn fetch_upstream_trace(ctx: &MirrorCtx) -> anyhow::Result<Option<String>> {
+    let mut error = None;
+    for url in upstream_mirrors(ctx).await? {
+        match fetch_trace(&url).await {
+            Ok(o) => return Ok(o),
+            Err(e) => {
+                debug!(mirror = %url, error = %e, "no trace from this mirror");
+                error = Some(e);
+            }
+        }
+    }
+    Err(error.unwrap())
+}
+
+fn modified(path: &str) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Whether `upstream` is the master trace recorded by the last successful
+/// sync, and the mirror configuration has not changed since.
+pub(crate) fn upstream_unchanged(ctx: &MirrorCtx, upstream: &str) -> bool {
+    let path = format!("{}/{}", &ctx.paths().trace, MASTER);
+    trace_unchanged(&path, &ctx.paths().config, upstream)
+}
+
+/// Whether the trace kept at `path` is `upstream` and no older than the
+/// configuration at `config`, which need not exist.
+fn trace_unchanged(path: &str, config: &str, upstream: &str) -> bool {
+    match std::fs::read_to_string(path) {
+        Ok(o) if o == upstream => {}
+        _ => return false,
+    }
+    match (modified(path), modified(config)) {
+        (Some(trace), Some(config)) => config <= trace,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+fn trace_field<'a>(trace: &'a str, name: &str) -> Option<&'a str> {
+    trace.lines().find_map(|x| {
+        x.strip_prefix(name)
+            .and_then(|x| x.strip_prefix(':'))
+            .map(|x| x.trim())
+    })
+}
+
+async fn write_atomic(path: &str, content: &str) -> anyhow::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("failed to write {}", path))
+}
+
+/// Writes the trace file of this host after a successful sync, in the
+/// format ftpsync uses, and keeps the upstream master trace the sync was
+/// based on. `started` is the unix time the sync began.
+pub(crate) async fn write_traces(
+    ctx: &MirrorCtx,
+    upstream: Option<&str>,
+    started: u64,
+) -> anyhow::Result<()> {
+    let now = now_seconds();
+    let list = read_list_dist_packages(ctx).await?;
+    let mut suites = BTreeSet::new();
+    let mut architectures = BTreeSet::new();
+    for line in list.lines() {
+        let parts: Vec<&str> = line.split('/').collect();
+        if parts.len() < 3 || parts[0] != "dists" {
+            continue;
+        }
+        if parts[2] == "Release" {
+            suites.insert(parts[1]);
+        }
+        for x in parts[2..].iter() {
+            if let Some(arch) = x.strip_prefix("binary-") {
+                architectures.insert(arch);
+            } else if *x == "source" {
+                architectures.insert("source");
+            }
+        }
+    }
+
+    let mut trace = format!("{}\n", ctime_date(now));
+    trace.push_str(&format!("Date: {}\n", http_date(now)));
+    trace.push_str(&format!("Date-Started: {}\n", http_date(started)));
+    if let Some(serial) = upstream.and_then(|x| trace_field(x, "Archive serial")) {
+        trace.push_str(&format!("Archive serial: {}\n", serial));
+    }
+    trace.push_str(&format!(
+        "Creator: deb_mirror {}\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    trace.push_str(&format!("Running on host: {}\n", hostname()));
+    trace.push_str(&format!(
+        "Suites: {}\n",
+        suites.into_iter().collect::<Vec<_>>().join(" ")
+    ));
+    trace.push_str(&format!(
+        "Architectures: {}\n",
+        architectures.into_iter().collect::<Vec<_>>().join(" ")
+    ));
+    if let Some(o) = upstream_mirrors(ctx).await.unwrap_or_default().first() {
+        trace.push_str(&format!("Upstream-mirror: {}\n", o));
+    }
+    trace.push_str(&format!(
+        "Total time spent in sync: {}\n",
+        now.saturating_sub(started)
+    ));
+
+    tokio::fs::create_dir_all(&ctx.paths().trace)
+        .await
+        .with_context(|| format!("failed to create {}", &ctx.paths().trace))?;
+    if let Some(o) = upstream {
+        write_atomic(&format!("{}/{}", &ctx.paths().trace, MASTER), o).await?;
+    }
+    write_atomic(&format!("{}/{}", &ctx.paths().trace, hostname()), &trace).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    fn touch(path: &str, content: &str, time: SystemTime) {
+        std::fs::write(path, content).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn unchanged_traces_of_an_unchanged_configuration() {
+        let dir = std::env::temp_dir().join(format!("mysync-trace-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let trace = dir.join("master").to_string_lossy().to_string();
+        let config = dir.join("config.txt").to_string_lossy().to_string();
+        let upstream = "Sat Oct 17 10:00:00 UTC 2026\nArchive serial: 2026101701\n";
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_792_231_200);
+
+        assert!(!trace_unchanged(&trace, &config, upstream));
+        touch(&trace, upstream, then);
+        assert!(trace_unchanged(&trace, &config, upstream));
+        assert!(!trace_unchanged(
+            &trace,
+            &config,
+            "Archive serial: 2026101702\n"
+        ));
+        touch(&config, "", then - Duration::from_secs(60));
+        assert!(trace_unchanged(&trace, &config, upstream));
+        touch(&config, "", then + Duration::from_secs(60));
+        assert!(!trace_unchanged(&trace, &config, upstream));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trace_fields_by_name() {
+        let trace = "Sat Oct 17 10:00:00 UTC 2026\nDate: x\nArchive serial: 2026101701\n";
+        assert_eq!(trace_field(trace, "Archive serial"), Some("2026101701"));
+        assert_eq!(trace_field(trace, "Date"), Some("x"));
+        assert_eq!(trace_field(trace, "Creator"), None);
+    }
+}